@@ -1,30 +1,151 @@
-use std::env;
+use std::{env, path::PathBuf, str::FromStr};
+
+use crate::{memory::Layout, vm::{Config, Register}};
 
 #[derive(Debug)]
 pub enum ArgError {
     IncorrectSize,
-    InvalidExtension
+    InvalidExtension(&'static str),
+    UnknownCommand(String),
+    UnknownOption(String),
+    InvalidValue(String),
+}
+
+#[derive(Debug)]
+pub enum Command {
+    /// Runs the program.
+    Run,
+    /// Prints the control-flow graph of the program in Graphviz DOT.
+    Cfg,
+    /// Checks the possible stack depths of the program without running it.
+    Stack,
+    /// Runs the program, merging the instructions and branches it executed into
+    /// the coverage data of previous runs.
+    Coverage,
+    /// Prints the program translated to standalone C source.
+    C,
+    /// Runs the program several times and reports the speed of the interpreter.
+    Bench,
+    /// Compiles the program to native code and runs it.
+    Jit,
+    /// Runs the program both interpreted and compiled, and compares the results.
+    JitDiff,
+    /// Writes the program as bytecode to a `.invmb` file.
+    Assemble,
+    /// Prints a `.invmb` file back as source.
+    Disasm,
+}
+
+#[derive(Debug)]
+pub struct Args {
+    pub command: Command,
+    pub filepath: String,
+    pub seed: Option<u64>,
+    pub runs: usize,
+    pub memory: Layout,
+    pub call_limit: usize,
+    pub registers: usize,
+    pub heap_debug: bool,
+    /// Directory granted to `OPEN`.
+    pub files: Option<PathBuf>,
+    /// Summarises the prices seen by every `WAIT` on stderr.
+    pub trace: bool,
+    /// Leaves the debug line table out of assembled bytecode.
+    pub strip: bool,
+    /// Optimises the program before using it.
+    pub optimize: bool,
+    /// Keeps the number of instructions every run executes when optimising.
+    pub keep_ticks: bool,
 }
 
 /// Checks the passed arguments.
-/// Expected: cargo run -- "{query}"
-/// If the argument number is different than 2, results in an error.
-pub fn parse_args() -> Result<String, ArgError> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        return Err(ArgError::IncorrectSize);
+/// Expected: cargo run -- [command] "{query}" [options]
+/// The file is source (`.invm`) or bytecode (`.invmb`).
+/// If no command is given, the program is run.
+pub fn parse_args() -> Result<Args, ArgError> {
+    let mut positional = vec![];
+    let mut seed = None;
+    let mut runs = 10;
+    let mut memory = Layout::default();
+    let mut call_limit = Config::default().call_limit;
+    let mut registers = Config::default().registers;
+    let mut heap_debug = false;
+    let mut files = None;
+    let mut trace = false;
+    let mut strip = false;
+    let mut optimize = false;
+    let mut keep_ticks = false;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seed" => seed = Some(expect_value(&arg, args.next())?),
+            "--runs" => runs = expect_value(&arg, args.next())?,
+            "--memory" => memory.size = expect_value(&arg, args.next())?,
+            "--data" => memory.data = expect_value(&arg, args.next())?,
+            "--call-limit" => call_limit = expect_value(&arg, args.next())?,
+            "--registers" => registers = expect_value(&arg, args.next())?,
+            "--heap-debug" => heap_debug = true,
+            "--files" => files = Some(expect_value(&arg, args.next())?),
+            "--trace" => trace = true,
+            "--strip" => strip = true,
+            "-O" => optimize = true,
+            "--keep-ticks" => keep_ticks = true,
+            _ if arg.starts_with("--") => return Err(ArgError::UnknownOption(arg)),
+            _ => positional.push(arg),
+        }
+    }
+
+    if runs == 0 {
+        return Err(ArgError::InvalidValue("--runs".to_string()));
+    }
+    // Every address must fit in a word, which may have 32 bits.
+    if memory.size > i32::MAX as usize {
+        return Err(ArgError::InvalidValue("--memory".to_string()));
+    }
+    if memory.data > memory.size {
+        return Err(ArgError::InvalidValue("--data".to_string()));
     }
-    let filename = match args.get(1) {
-        Some(arg) => arg.to_string(),
-        None => return Err(ArgError::IncorrectSize),
+    if registers > Register::MAX_GENERAL {
+        return Err(ArgError::InvalidValue("--registers".to_string()));
+    }
+
+    let (command, filename) = match positional.as_slice() {
+        [filename] => (Command::Run, filename.to_string()),
+        [command, filename] => (parse_command(command)?, filename.to_string()),
+        _ => return Err(ArgError::IncorrectSize),
     };
-    let last = {
-        let dot = filename.len() - 5;
-        &filename[dot..]
+    // Source is needed to assemble and to annotate coverage, bytecode to disassemble.
+    let expected = match command {
+        Command::Assemble | Command::Coverage => ".invm",
+        Command::Disasm => ".invmb",
+        _ if filename.ends_with(".invmb") => ".invmb",
+        _ => ".invm",
     };
-    if last != ".invm" {
-        return Err(ArgError::InvalidExtension);
+    if !filename.ends_with(expected) {
+        return Err(ArgError::InvalidExtension(expected));
     }
-    Ok(filename)
+    Ok(Args { command, filepath: filename, seed, runs, memory, call_limit, registers, heap_debug, files, trace, strip, optimize, keep_ticks })
+}
+
+fn parse_command(command: &str) -> Result<Command, ArgError> {
+    match command {
+        "run" => Ok(Command::Run),
+        "cfg" => Ok(Command::Cfg),
+        "stack" => Ok(Command::Stack),
+        "coverage" => Ok(Command::Coverage),
+        "c" => Ok(Command::C),
+        "bench" => Ok(Command::Bench),
+        "jit" => Ok(Command::Jit),
+        "jit-diff" => Ok(Command::JitDiff),
+        "assemble" => Ok(Command::Assemble),
+        "disasm" => Ok(Command::Disasm),
+        _ => Err(ArgError::UnknownCommand(command.to_string())),
+    }
+}
 
+fn expect_value<T: FromStr>(option: &str, value: Option<String>) -> Result<T, ArgError> {
+    value
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| ArgError::InvalidValue(option.to_string()))
 }
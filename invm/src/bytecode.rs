@@ -0,0 +1,687 @@
+//! Binary form of a program, stored in `.invmb` files.
+//!
+//! Layout, all integers little-endian:
+//!
+//! ```text
+//! header   "INVB" u16 version  u16 flags
+//! symbols  u32 count, then per label: u16 length, UTF-8 name, u32 index of
+//!          its declaration (u32::MAX if it is only used by jumps)
+//! data     u32 count, then per directive: u16 length, UTF-8 name, u8 kind and
+//!          u32 length, followed by UTF-8 text for .string or words for .word
+//! code     u32 count, then per instruction: u8 opcode and its operands
+//! lines    u32 source line per instruction, only if FLAG_LINES is set
+//! ```
+//!
+//! Labels in the code are indexes into the symbol table. A reference is a tag
+//! byte followed by a register or sensor index or by a word. The high bits
+//! of the tag give the addressing mode: `*R+n` is followed by the word
+//! offset, and `*(R+R)` and `*R[i]` by the tag and value of the second base.
+//! Print instructions to stderr set `STREAM_ERR` in their type or stream byte.
+//! Words are i32, or i64 if FLAG_WIDTH_64 is set, and the overflow mode of
+//! `.arith` is kept in FLAG_SATURATING or FLAG_TRAPPING.
+
+use std::{collections::HashMap, fmt::{self, Display, Formatter}, fmt::Write};
+
+use crate::{lexer::Lexer, memory::Layout, parser::{Data, DataKind, Program}, vm::{self, arith::{Arith, Overflow, Width}, Condition, FileMode, GeneralRegister, Instruction, Reference, Register, Sensor, Stream, Type, Word}};
+
+const MAGIC: &[u8; 4] = b"INVB";
+pub const VERSION: u16 = 4;
+
+/// The file has a debug line table after the code.
+const FLAG_LINES: u16 = 1;
+/// Words are 64 bits wide.
+const FLAG_WIDTH_64: u16 = 2;
+const FLAG_SATURATING: u16 = 4;
+const FLAG_TRAPPING: u16 = 8;
+
+const UNDECLARED: u32 = u32::MAX;
+
+const DATA_STRING: u8 = 0;
+const DATA_WORD: u8 = 1;
+const DATA_ZERO: u8 = 2;
+
+const OP_SET: u8 = 0x01;
+const OP_ADD: u8 = 0x02;
+const OP_SUB: u8 = 0x03;
+const OP_MULT: u8 = 0x04;
+const OP_DIV: u8 = 0x05;
+const OP_GOTO: u8 = 0x06;
+const OP_GOIF: u8 = 0x07;
+const OP_PRINT: u8 = 0x08;
+const OP_PUSH: u8 = 0x09;
+const OP_POP: u8 = 0x0a;
+const OP_CRASH: u8 = 0x0b;
+const OP_BUY: u8 = 0x0c;
+const OP_SELL: u8 = 0x0d;
+const OP_LABEL: u8 = 0x0e;
+const OP_READ: u8 = 0x0f;
+const OP_NOP: u8 = 0x10;
+const OP_CALL: u8 = 0x11;
+const OP_RET: u8 = 0x12;
+const OP_MOD: u8 = 0x13;
+const OP_AND: u8 = 0x14;
+const OP_OR: u8 = 0x15;
+const OP_XOR: u8 = 0x16;
+const OP_NOT: u8 = 0x17;
+const OP_SHL: u8 = 0x18;
+const OP_SHR: u8 = 0x19;
+const OP_NEG: u8 = 0x1a;
+const OP_ABS: u8 = 0x1b;
+const OP_MIN: u8 = 0x1c;
+const OP_MAX: u8 = 0x1d;
+/// `GOIF` with a second operand, used when it is not the constant 0.
+const OP_GOIF_CMP: u8 = 0x1e;
+const OP_ALLOC: u8 = 0x1f;
+const OP_FREE: u8 = 0x20;
+const OP_ENTER: u8 = 0x21;
+const OP_LEAVE: u8 = 0x22;
+const OP_PUT: u8 = 0x23;
+const OP_PRINTF: u8 = 0x24;
+const OP_OPEN: u8 = 0x25;
+const OP_READLINE: u8 = 0x26;
+const OP_WRITE: u8 = 0x27;
+const OP_CLOSE: u8 = 0x28;
+const OP_MMULT: u8 = 0x29;
+const OP_MDIV: u8 = 0x2a;
+const OP_WAIT: u8 = 0x2b;
+
+const REF_REGISTER: u8 = 0x00;
+const REF_SENSOR: u8 = 0x01;
+const REF_VALUE: u8 = 0x02;
+/// Added to the tag of the base of an `Address`.
+const REF_ADDRESS: u8 = 0x10;
+const REF_OFFSET: u8 = 0x20;
+const REF_SUM: u8 = 0x30;
+const REF_INDEX: u8 = 0x40;
+/// The bits of a tag that give the addressing mode.
+const REF_MODE: u8 = 0xf0;
+
+/// Set in the type byte of `PRINT` and `PUT`, and in the byte after the
+/// format of `PRINTF`, when they write to stderr.
+const STREAM_ERR: u8 = 0x80;
+
+const TYPES: [Type; 5] = [Type::Int, Type::Bool, Type::Char, Type::Str, Type::Money];
+
+const MODES: [FileMode; 3] = [FileMode::Read, FileMode::Write, FileMode::Append];
+
+const CONDITIONS: [Condition; 6] = [
+    Condition::Equals,
+    Condition::Different,
+    Condition::Greater,
+    Condition::Lesser,
+    Condition::GreaterOrEqual,
+    Condition::LesserOrEqual,
+];
+
+#[derive(Debug)]
+pub enum BytecodeError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    InvalidFlags(u16),
+    Truncated,
+    InvalidOpcode(u8, usize),
+    InvalidOperand(u8, usize),
+    InvalidSymbol(u32, usize),
+    InvalidName,
+    InvalidData(u8, usize),
+    /// A data name that is not an identifier or is used twice.
+    InvalidDataName(usize),
+    /// Data that does not fit in the words of static data.
+    DataTooLarge(usize, usize),
+    TrailingBytes(usize),
+}
+
+impl Display for BytecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            BytecodeError::BadMagic => write!(f, "Not an invm bytecode file."),
+            BytecodeError::UnsupportedVersion(v) => write!(f, "Unsupported bytecode version {v}, expected {VERSION}."),
+            BytecodeError::InvalidFlags(flags) => write!(f, "Invalid flags {flags:#06x}."),
+            BytecodeError::Truncated => write!(f, "Unexpected end of file."),
+            BytecodeError::InvalidOpcode(op, at) => write!(f, "Invalid opcode {op:#04x} at byte {at}."),
+            BytecodeError::InvalidOperand(tag, at) => write!(f, "Invalid operand {tag:#04x} at byte {at}."),
+            BytecodeError::InvalidSymbol(sym, at) => write!(f, "Invalid symbol {sym} at byte {at}."),
+            BytecodeError::InvalidName => write!(f, "Name or string is not valid UTF-8."),
+            BytecodeError::InvalidData(kind, at) => write!(f, "Invalid data kind {kind:#04x} at byte {at}."),
+            BytecodeError::InvalidDataName(at) => write!(f, "Invalid or duplicate data name at byte {at}."),
+            BytecodeError::DataTooLarge(at, words) => write!(f, "Data at byte {at} does not fit in the {words} words of static data."),
+            BytecodeError::TrailingBytes(at) => write!(f, "Unexpected data after the program at byte {at}."),
+        }
+    }
+}
+
+/// Encodes a program. The line table is left out when `lines` is false.
+pub fn assemble(program: &Program, lines: bool) -> Vec<u8> {
+    let labels = vm::resolve_labels(&program.instructions);
+
+    let mut symbols: Vec<&str> = vec![];
+    let mut ids: HashMap<&str, u32> = HashMap::new();
+    for inst in &program.instructions {
+        if let Instruction::Goto(label) | Instruction::GoIf(_, _, _, label) | Instruction::Call(label) | Instruction::DeclareLabel(label) = inst
+            && !ids.contains_key(label.as_str())
+        {
+            ids.insert(label, symbols.len() as u32);
+            symbols.push(label);
+        }
+    }
+
+    let mut out = vec![];
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    let width = program.arith.width;
+    let mut flags = if lines { FLAG_LINES } else { 0 };
+    if width == Width::W64 {
+        flags |= FLAG_WIDTH_64;
+    }
+    flags |= match program.arith.overflow {
+        Overflow::Wrapping => 0,
+        Overflow::Saturating => FLAG_SATURATING,
+        Overflow::Trapping => FLAG_TRAPPING,
+    };
+    out.extend_from_slice(&flags.to_le_bytes());
+
+    out.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    for name in &symbols {
+        let index = labels.get(*name).map_or(UNDECLARED, |i| *i as u32);
+        out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(&index.to_le_bytes());
+    }
+
+    out.extend_from_slice(&(program.data.len() as u32).to_le_bytes());
+    for data in &program.data {
+        out.extend_from_slice(&(data.name.len() as u16).to_le_bytes());
+        out.extend_from_slice(data.name.as_bytes());
+        match &data.kind {
+            DataKind::String(s) => {
+                out.push(DATA_STRING);
+                out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+                out.extend_from_slice(s.as_bytes());
+            },
+            DataKind::Word(words) => {
+                out.push(DATA_WORD);
+                out.extend_from_slice(&(words.len() as u32).to_le_bytes());
+                for w in words {
+                    encode_word(&mut out, width, *w);
+                }
+            },
+            DataKind::Zero(n) => {
+                out.push(DATA_ZERO);
+                out.extend_from_slice(&(*n as u32).to_le_bytes());
+            },
+        }
+    }
+
+    out.extend_from_slice(&(program.instructions.len() as u32).to_le_bytes());
+    for inst in &program.instructions {
+        let symbol = |label: &String| ids[label.as_str()].to_le_bytes();
+        match inst {
+            Instruction::Set(r, v) => encode_pair(&mut out, width, OP_SET, r, v),
+            Instruction::Add(v, r) => encode_pair(&mut out, width, OP_ADD, v, r),
+            Instruction::Sub(v, r) => encode_pair(&mut out, width, OP_SUB, v, r),
+            Instruction::Mult(v, r) => encode_pair(&mut out, width, OP_MULT, v, r),
+            Instruction::Div(v, r) => encode_pair(&mut out, width, OP_DIV, v, r),
+            Instruction::Mod(v, r) => encode_pair(&mut out, width, OP_MOD, v, r),
+            Instruction::And(v, r) => encode_pair(&mut out, width, OP_AND, v, r),
+            Instruction::Or(v, r) => encode_pair(&mut out, width, OP_OR, v, r),
+            Instruction::Xor(v, r) => encode_pair(&mut out, width, OP_XOR, v, r),
+            Instruction::Not(v, r) => encode_pair(&mut out, width, OP_NOT, v, r),
+            Instruction::Shl(v, r) => encode_pair(&mut out, width, OP_SHL, v, r),
+            Instruction::Shr(v, r) => encode_pair(&mut out, width, OP_SHR, v, r),
+            Instruction::Neg(v, r) => encode_pair(&mut out, width, OP_NEG, v, r),
+            Instruction::Abs(v, r) => encode_pair(&mut out, width, OP_ABS, v, r),
+            Instruction::Min(v, r) => encode_pair(&mut out, width, OP_MIN, v, r),
+            Instruction::Max(v, r) => encode_pair(&mut out, width, OP_MAX, v, r),
+            Instruction::MMult(v, r) => encode_pair(&mut out, width, OP_MMULT, v, r),
+            Instruction::MDiv(v, r) => encode_pair(&mut out, width, OP_MDIV, v, r),
+            Instruction::Goto(label) => {
+                out.push(OP_GOTO);
+                out.extend_from_slice(&symbol(label));
+            },
+            Instruction::GoIf(cond, v, Reference::Value(0), label) => {
+                out.push(OP_GOIF);
+                out.push(*cond as u8);
+                encode_reference(&mut out, width, v);
+                out.extend_from_slice(&symbol(label));
+            },
+            Instruction::GoIf(cond, a, b, label) => {
+                out.push(OP_GOIF_CMP);
+                out.push(*cond as u8);
+                encode_reference(&mut out, width, a);
+                encode_reference(&mut out, width, b);
+                out.extend_from_slice(&symbol(label));
+            },
+            Instruction::Print(v, t, stream) | Instruction::Put(v, t, stream) => {
+                out.push(if matches!(inst, Instruction::Print(..)) { OP_PRINT } else { OP_PUT });
+                encode_reference(&mut out, width, v);
+                out.push(*t as u8 | stream_flag(*stream));
+            },
+            Instruction::Printf(v, stream) => {
+                out.push(OP_PRINTF);
+                encode_reference(&mut out, width, v);
+                out.push(stream_flag(*stream));
+            },
+            Instruction::Push(v) => {
+                out.push(OP_PUSH);
+                encode_reference(&mut out, width, v);
+            },
+            Instruction::Pop(r) => {
+                out.push(OP_POP);
+                encode_reference(&mut out, width, r);
+            },
+            Instruction::Crash => out.push(OP_CRASH),
+            Instruction::Nop => out.push(OP_NOP),
+            Instruction::Call(label) => {
+                out.push(OP_CALL);
+                out.extend_from_slice(&symbol(label));
+            },
+            Instruction::Ret => out.push(OP_RET),
+            Instruction::Alloc(n, r) => encode_pair(&mut out, width, OP_ALLOC, n, r),
+            Instruction::Free(v) => {
+                out.push(OP_FREE);
+                encode_reference(&mut out, width, v);
+            },
+            Instruction::Enter(words) => {
+                out.push(OP_ENTER);
+                out.extend_from_slice(&(*words as u32).to_le_bytes());
+            },
+            Instruction::Leave => out.push(OP_LEAVE),
+            Instruction::Open(name, mode, r) => {
+                out.push(OP_OPEN);
+                encode_reference(&mut out, width, name);
+                out.push(*mode as u8);
+                encode_reference(&mut out, width, r);
+            },
+            Instruction::ReadLine(h, r) => encode_pair(&mut out, width, OP_READLINE, h, r),
+            Instruction::Write(h, v) => encode_pair(&mut out, width, OP_WRITE, h, v),
+            Instruction::Close(h) => {
+                out.push(OP_CLOSE);
+                encode_reference(&mut out, width, h);
+            },
+            Instruction::Wait(n) => {
+                out.push(OP_WAIT);
+                encode_reference(&mut out, width, n);
+            },
+            Instruction::Buy(n) => {
+                out.push(OP_BUY);
+                encode_reference(&mut out, width, n);
+            },
+            Instruction::Sell(n) => {
+                out.push(OP_SELL);
+                encode_reference(&mut out, width, n);
+            },
+            Instruction::DeclareLabel(label) => {
+                out.push(OP_LABEL);
+                out.extend_from_slice(&symbol(label));
+            },
+            Instruction::Read(r, t) => {
+                out.push(OP_READ);
+                encode_reference(&mut out, width, r);
+                out.push(*t as u8);
+            },
+        }
+    }
+
+    if lines {
+        for line in &program.lines {
+            out.extend_from_slice(&(*line as u32).to_le_bytes());
+        }
+    }
+    out
+}
+
+fn stream_flag(stream: Stream) -> u8 {
+    match stream {
+        Stream::Out => 0,
+        Stream::Err => STREAM_ERR,
+    }
+}
+
+fn encode_pair(out: &mut Vec<u8>, width: Width, opcode: u8, a: &Reference, b: &Reference) {
+    out.push(opcode);
+    encode_reference(out, width, a);
+    encode_reference(out, width, b);
+}
+
+fn encode_word(out: &mut Vec<u8>, width: Width, n: Word) {
+    match width {
+        Width::W32 => out.extend_from_slice(&(n as i32).to_le_bytes()),
+        Width::W64 => out.extend_from_slice(&n.to_le_bytes()),
+    }
+}
+
+fn encode_reference(out: &mut Vec<u8>, width: Width, r: &Reference) {
+    let (mode, g) = match r {
+        Reference::Register(r) => (0, &GeneralRegister::Register(*r)),
+        Reference::Sensor(s) => (0, &GeneralRegister::Sensor(*s)),
+        Reference::Value(n) => (0, &GeneralRegister::Value(*n)),
+        Reference::Address(g) => (REF_ADDRESS, g),
+        Reference::Offset(g, _) => (REF_OFFSET, g),
+        Reference::Sum(g, _) => (REF_SUM, g),
+        Reference::Index(g, _) => (REF_INDEX, g),
+    };
+    out.push(mode + base_tag(g));
+    encode_base(out, width, g);
+    match r {
+        Reference::Offset(_, n) => encode_word(out, width, *n),
+        Reference::Sum(_, b) | Reference::Index(_, b) => {
+            out.push(base_tag(b));
+            encode_base(out, width, b);
+        },
+        _ => (),
+    }
+}
+
+fn encode_base(out: &mut Vec<u8>, width: Width, g: &GeneralRegister) {
+    match g {
+        GeneralRegister::Register(r) => out.push(r.index() as u8),
+        GeneralRegister::Sensor(s) => out.push(s.index() as u8),
+        GeneralRegister::Value(n) => encode_word(out, width, *n),
+    }
+}
+
+fn base_tag(g: &GeneralRegister) -> u8 {
+    match g {
+        GeneralRegister::Register(_) => REF_REGISTER,
+        GeneralRegister::Sensor(_) => REF_SENSOR,
+        GeneralRegister::Value(_) => REF_VALUE,
+    }
+}
+
+/// Decodes a program whose data must fit in the static data of `layout`.
+/// Without a line table, the line of every instruction is its position in
+/// the program, starting at 1.
+pub fn load(bytes: &[u8], layout: &Layout) -> Result<Program, BytecodeError> {
+    let mut r = Reader { bytes, pos: 0, width: Width::W32 };
+
+    if r.take(4)? != MAGIC {
+        return Err(BytecodeError::BadMagic);
+    }
+    let version = r.u16()?;
+    if version != VERSION {
+        return Err(BytecodeError::UnsupportedVersion(version));
+    }
+    let flags = r.u16()?;
+    let known = FLAG_LINES | FLAG_WIDTH_64 | FLAG_SATURATING | FLAG_TRAPPING;
+    let overflow = match flags & (FLAG_SATURATING | FLAG_TRAPPING) {
+        _ if flags & !known != 0 => return Err(BytecodeError::InvalidFlags(flags)),
+        0 => Overflow::Wrapping,
+        FLAG_SATURATING => Overflow::Saturating,
+        FLAG_TRAPPING => Overflow::Trapping,
+        _ => return Err(BytecodeError::InvalidFlags(flags)),
+    };
+    let width = if flags & FLAG_WIDTH_64 != 0 { Width::W64 } else { Width::W32 };
+    r.width = width;
+
+    // Names must read back from disassembled source, and be valid in the C
+    // translation.
+    let mut symbols: Vec<(String, u32, usize)> = vec![];
+    for sym in 0..r.u32()? {
+        let len = r.u16()? as usize;
+        let name = r.string(len)?;
+        let at = r.pos;
+        let index = r.u32()?;
+        if !Lexer::is_label(&name) || symbols.iter().any(|(other, ..)| *other == name) {
+            return Err(BytecodeError::InvalidSymbol(sym, at));
+        }
+        symbols.push((name, index, at));
+    }
+
+    let mut data: Vec<Data> = vec![];
+    let mut words = 0usize;
+    for _ in 0..r.u32()? {
+        let name_at = r.pos;
+        let len = r.u16()? as usize;
+        let name = r.string(len)?;
+        if !Lexer::is_name(&name) || data.iter().any(|d| d.name == name) {
+            return Err(BytecodeError::InvalidDataName(name_at));
+        }
+        let at = r.pos;
+        let kind = r.u8()?;
+        let len = r.u32()? as usize;
+        let kind = match kind {
+            DATA_STRING => DataKind::String(r.string(len)?),
+            // Checked before reading, so that a corrupt length is not allocated.
+            DATA_WORD | DATA_ZERO if words.saturating_add(len) > layout.data => {
+                return Err(BytecodeError::DataTooLarge(at, layout.data));
+            },
+            DATA_WORD => DataKind::Word((0..len).map(|_| r.word()).collect::<Result<_, _>>()?),
+            DATA_ZERO => DataKind::Zero(len),
+            _ => return Err(BytecodeError::InvalidData(kind, at)),
+        };
+        let d = Data { name, kind };
+        words += d.size();
+        if words > layout.data {
+            return Err(BytecodeError::DataTooLarge(at, layout.data));
+        }
+        data.push(d);
+    }
+
+    let count = r.u32()? as usize;
+    let mut instructions = Vec::with_capacity(count.min(bytes.len()));
+    for _ in 0..count {
+        instructions.push(r.instruction(&symbols)?);
+    }
+
+    // A label must be declared where the symbol table says, or the jumps of
+    // the loaded program would go elsewhere than the assembled ones.
+    for (sym, (name, index, at)) in symbols.iter().enumerate() {
+        let declared = instructions.iter().position(|inst| matches!(inst, Instruction::DeclareLabel(l) if l == name));
+        let expected = (*index != UNDECLARED).then_some(*index as usize);
+        if declared != expected {
+            return Err(BytecodeError::InvalidSymbol(sym as u32, *at));
+        }
+    }
+
+    let lines = if flags & FLAG_LINES != 0 {
+        (0..count).map(|_| r.u32().map(|l| l as usize)).collect::<Result<_, _>>()?
+    } else {
+        (1..=count).collect()
+    };
+
+    if r.pos != bytes.len() {
+        return Err(BytecodeError::TrailingBytes(r.pos));
+    }
+    Ok(Program { instructions, lines, data, arith: Arith { overflow, width } })
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    /// Size of the words, known once the header is read.
+    width: Width,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], BytecodeError> {
+        let slice = self.bytes.get(self.pos..self.pos + n).ok_or(BytecodeError::Truncated)?;
+        self.pos += n;
+        Ok(slice)
+    }
+
+    fn string(&mut self, len: usize) -> Result<String, BytecodeError> {
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| BytecodeError::InvalidName)
+    }
+
+    fn u8(&mut self) -> Result<u8, BytecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, BytecodeError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, BytecodeError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn word(&mut self) -> Result<Word, BytecodeError> {
+        Ok(match self.width {
+            Width::W32 => i32::from_le_bytes(self.take(4)?.try_into().unwrap()).into(),
+            Width::W64 => i64::from_le_bytes(self.take(8)?.try_into().unwrap()),
+        })
+    }
+
+    fn instruction(&mut self, symbols: &[(String, u32, usize)]) -> Result<Instruction, BytecodeError> {
+        let at = self.pos;
+        let inst = match self.u8()? {
+            OP_SET => Instruction::Set(self.reference()?, self.reference()?),
+            OP_ADD => Instruction::Add(self.reference()?, self.reference()?),
+            OP_SUB => Instruction::Sub(self.reference()?, self.reference()?),
+            OP_MULT => Instruction::Mult(self.reference()?, self.reference()?),
+            OP_DIV => Instruction::Div(self.reference()?, self.reference()?),
+            OP_MOD => Instruction::Mod(self.reference()?, self.reference()?),
+            OP_AND => Instruction::And(self.reference()?, self.reference()?),
+            OP_OR => Instruction::Or(self.reference()?, self.reference()?),
+            OP_XOR => Instruction::Xor(self.reference()?, self.reference()?),
+            OP_NOT => Instruction::Not(self.reference()?, self.reference()?),
+            OP_SHL => Instruction::Shl(self.reference()?, self.reference()?),
+            OP_SHR => Instruction::Shr(self.reference()?, self.reference()?),
+            OP_NEG => Instruction::Neg(self.reference()?, self.reference()?),
+            OP_ABS => Instruction::Abs(self.reference()?, self.reference()?),
+            OP_MIN => Instruction::Min(self.reference()?, self.reference()?),
+            OP_MAX => Instruction::Max(self.reference()?, self.reference()?),
+            OP_MMULT => Instruction::MMult(self.reference()?, self.reference()?),
+            OP_MDIV => Instruction::MDiv(self.reference()?, self.reference()?),
+            OP_GOTO => Instruction::Goto(self.symbol(symbols)?),
+            OP_GOIF => Instruction::GoIf(self.condition()?, self.reference()?, Reference::Value(0), self.symbol(symbols)?),
+            OP_GOIF_CMP => Instruction::GoIf(self.condition()?, self.reference()?, self.reference()?, self.symbol(symbols)?),
+            OP_PRINT => {
+                let v = self.reference()?;
+                let (t, stream) = self.output()?;
+                Instruction::Print(v, t, stream)
+            },
+            OP_PUT => {
+                let v = self.reference()?;
+                let (t, stream) = self.output()?;
+                Instruction::Put(v, t, stream)
+            },
+            OP_PRINTF => Instruction::Printf(self.reference()?, self.stream()?),
+            OP_PUSH => Instruction::Push(self.reference()?),
+            OP_POP => Instruction::Pop(self.reference()?),
+            OP_CRASH => Instruction::Crash,
+            OP_NOP => Instruction::Nop,
+            OP_CALL => Instruction::Call(self.symbol(symbols)?),
+            OP_RET => Instruction::Ret,
+            OP_ALLOC => Instruction::Alloc(self.reference()?, self.reference()?),
+            OP_FREE => Instruction::Free(self.reference()?),
+            OP_ENTER => Instruction::Enter(self.u32()? as usize),
+            OP_LEAVE => Instruction::Leave,
+            OP_OPEN => Instruction::Open(self.reference()?, self.mode()?, self.reference()?),
+            OP_READLINE => Instruction::ReadLine(self.reference()?, self.reference()?),
+            OP_WRITE => Instruction::Write(self.reference()?, self.reference()?),
+            OP_CLOSE => Instruction::Close(self.reference()?),
+            OP_WAIT => Instruction::Wait(self.reference()?),
+            OP_BUY => Instruction::Buy(self.reference()?),
+            OP_SELL => Instruction::Sell(self.reference()?),
+            OP_LABEL => Instruction::DeclareLabel(self.symbol(symbols)?),
+            OP_READ => Instruction::Read(self.reference()?, self.ty()?),
+            op => return Err(BytecodeError::InvalidOpcode(op, at)),
+        };
+        Ok(inst)
+    }
+
+    fn reference(&mut self) -> Result<Reference, BytecodeError> {
+        let at = self.pos;
+        let tag = self.u8()?;
+        let base = self.base(tag & !REF_MODE, tag, at)?;
+        let reference = match (tag & REF_MODE, base) {
+            (0, GeneralRegister::Register(r)) => Reference::Register(r),
+            (0, GeneralRegister::Sensor(s)) => Reference::Sensor(s),
+            (0, GeneralRegister::Value(n)) => Reference::Value(n),
+            (REF_ADDRESS, g) => Reference::Address(g),
+            (REF_OFFSET, g) => Reference::Offset(g, self.word()?),
+            (REF_SUM | REF_INDEX, g) => {
+                let at = self.pos;
+                let second = self.u8()?;
+                let b = self.base(second, second, at)?;
+                match tag & REF_MODE {
+                    REF_SUM => Reference::Sum(g, b),
+                    _ => Reference::Index(g, b),
+                }
+            },
+            _ => return Err(BytecodeError::InvalidOperand(tag, at)),
+        };
+        Ok(reference)
+    }
+
+    /// Reads the value of a base with the tag `kind`. `tag` and `at` locate
+    /// the error if the tag or the index is invalid.
+    fn base(&mut self, kind: u8, tag: u8, at: usize) -> Result<GeneralRegister, BytecodeError> {
+        let base = match kind {
+            REF_REGISTER => GeneralRegister::Register(Register::from_index(self.u8()? as usize)
+                .ok_or(BytecodeError::InvalidOperand(tag, at))?),
+            REF_SENSOR => GeneralRegister::Sensor(*Sensor::ALL.get(self.u8()? as usize)
+                .ok_or(BytecodeError::InvalidOperand(tag, at))?),
+            REF_VALUE => GeneralRegister::Value(self.word()?),
+            _ => return Err(BytecodeError::InvalidOperand(tag, at)),
+        };
+        Ok(base)
+    }
+
+    fn symbol(&mut self, symbols: &[(String, u32, usize)]) -> Result<String, BytecodeError> {
+        let at = self.pos;
+        let sym = self.u32()?;
+        symbols.get(sym as usize).map(|(name, _, _)| name.clone()).ok_or(BytecodeError::InvalidSymbol(sym, at))
+    }
+
+    fn condition(&mut self) -> Result<Condition, BytecodeError> {
+        let at = self.pos;
+        let tag = self.u8()?;
+        CONDITIONS.get(tag as usize).copied().ok_or(BytecodeError::InvalidOperand(tag, at))
+    }
+
+    fn ty(&mut self) -> Result<Type, BytecodeError> {
+        let at = self.pos;
+        let tag = self.u8()?;
+        TYPES.get(tag as usize).copied().ok_or(BytecodeError::InvalidOperand(tag, at))
+    }
+
+    fn mode(&mut self) -> Result<FileMode, BytecodeError> {
+        let at = self.pos;
+        let tag = self.u8()?;
+        MODES.get(tag as usize).copied().ok_or(BytecodeError::InvalidOperand(tag, at))
+    }
+
+    /// The type byte of `PRINT` and `PUT`, with the stream in its high bit.
+    fn output(&mut self) -> Result<(Type, Stream), BytecodeError> {
+        let at = self.pos;
+        let tag = self.u8()?;
+        let t = TYPES.get((tag & !STREAM_ERR) as usize).copied().ok_or(BytecodeError::InvalidOperand(tag, at))?;
+        Ok((t, if tag & STREAM_ERR != 0 { Stream::Err } else { Stream::Out }))
+    }
+
+    fn stream(&mut self) -> Result<Stream, BytecodeError> {
+        let at = self.pos;
+        match self.u8()? {
+            0 => Ok(Stream::Out),
+            STREAM_ERR => Ok(Stream::Err),
+            tag => Err(BytecodeError::InvalidOperand(tag, at)),
+        }
+    }
+}
+
+/// Prints a loaded program as source the parser accepts, with the original
+/// source line of every instruction as a comment. `.arith` and `.width` are
+/// printed only when they differ from the defaults.
+pub fn disassemble(program: &Program) -> String {
+    let mut out = String::new();
+    writeln!(out, "# invm bytecode version {VERSION}, {} instructions", program.instructions.len()).unwrap();
+    if program.arith.overflow != Overflow::Wrapping {
+        writeln!(out, ".arith {}", program.arith.overflow.name()).unwrap();
+    }
+    if program.arith.width != Width::W32 {
+        writeln!(out, ".width {}", program.arith.width.bits()).unwrap();
+    }
+    for data in &program.data {
+        writeln!(out, "{data}").unwrap();
+    }
+    for (inst, line) in program.instructions.iter().zip(&program.lines) {
+        let text = match inst {
+            Instruction::DeclareLabel(_) => format!("{inst}"),
+            _ => format!("  {inst}"),
+        };
+        writeln!(out, "{text:<32} # line {line}").unwrap();
+    }
+    out
+}
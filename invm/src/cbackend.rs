@@ -0,0 +1,294 @@
+use std::fmt::Write;
+
+use crate::{parser::Program, vm::{self, arith::Overflow, Condition, Config, FileMode, GeneralRegister, Instruction, Reference, Register, Sensor, Stream, Type, Word}};
+
+/// Helpers shared by every translated program: registers, memory, I/O and the market simulation.
+const RUNTIME: &str = include_str!("cbackend/runtime.c");
+
+/// Translates a program into a standalone C file. Every instruction but `WAIT`,
+/// which runs its own ticks, is followed by a market tick, like `VM::step`, and
+/// labels become C labels placed after their own tick, since jumping to a label
+/// skips its declaration.
+/// `RET` jumps back through a `switch` over the index of every `CALL`.
+pub fn translate(program: &Program, source: &str, config: &Config) -> String {
+    vm::check_registers(&program.instructions, config.registers);
+    let labels = vm::resolve_labels(&program.instructions);
+    let targets: Vec<&String> = program.instructions.iter().filter_map(|inst| match inst {
+        Instruction::Goto(label) | Instruction::GoIf(_, _, _, label) | Instruction::Call(label) => Some(label),
+        _ => None,
+    }).collect();
+    let call_sites: Vec<usize> = program.instructions.iter().enumerate()
+        .filter(|(_, inst)| matches!(inst, Instruction::Call(_)))
+        .map(|(pc, _)| pc)
+        .collect();
+    let returns = program.instructions.iter().any(|inst| matches!(inst, Instruction::Ret));
+    let two_bases = program.instructions.iter()
+        .flat_map(Instruction::references)
+        .any(|r| matches!(r, Reference::Sum(..) | Reference::Index(..)));
+
+    let mut c = String::new();
+    writeln!(c, "/* Translated from {source} by invm. */").unwrap();
+    writeln!(c, "#define MEM_SIZE {}", config.memory.size).unwrap();
+    writeln!(c, "#define DATA_SIZE {}", config.memory.data).unwrap();
+    writeln!(c, "#define CALL_LIMIT {}", config.call_limit).unwrap();
+    writeln!(c, "#define GENERAL_REGISTERS {}", config.registers).unwrap();
+    writeln!(c, "#define HEAP_DEBUG {}", config.heap_debug as i32).unwrap();
+    writeln!(c, "#define TRACE {}", config.trace as i32).unwrap();
+    writeln!(c, "#define WORD_BITS {}", program.arith.width.bits()).unwrap();
+    let overflow = match program.arith.overflow {
+        Overflow::Wrapping => "WRAPPING",
+        Overflow::Saturating => "SATURATING",
+        Overflow::Trapping => "TRAPPING",
+    };
+    writeln!(c, "#define OVERFLOW {overflow}").unwrap();
+    if let Some(dir) = &config.files {
+        writeln!(c, "#define FILES_DIR {}", c_string(&dir.to_string_lossy())).unwrap();
+    }
+    c.push_str(RUNTIME);
+    writeln!(c).unwrap();
+    if two_bases {
+        writeln!(c, "/* The two values an address is computed from in *(R+R) and *R[i]. */").unwrap();
+        writeln!(c, "static word base_a, base_b;").unwrap();
+        writeln!(c).unwrap();
+    }
+    writeln!(c, "int main(void) {{").unwrap();
+    writeln!(c, "    srand((unsigned)time(NULL));").unwrap();
+    writeln!(c, "    runtime_init();").unwrap();
+    config.memory.check_data(program.data_size());
+    let data = program.data_words();
+    if !data.is_empty() {
+        let words: Vec<String> = data.iter().map(|w| literal(*w)).collect();
+        writeln!(c, "    static const word data[] = {{ {} }};", words.join(", ")).unwrap();
+        writeln!(c, "    memcpy(mem, data, sizeof data);").unwrap();
+    }
+    writeln!(c).unwrap();
+
+    for (pc, inst) in program.instructions.iter().enumerate() {
+        writeln!(c, "    /* {}: {} */", program.lines[pc], inst.to_string().replace("*/", "* /")).unwrap();
+        let jump = |label: &str| match labels.get(label) {
+            Some(_) => format!("goto {};", label_name(label)),
+            None => format!("fault(\"[INVM] Use of unknown label {label}.\");"),
+        };
+
+        let code = match inst {
+            Instruction::Set(r, v) => format!("{{ word v = {}; {} }}", read_ref(v), write_ref(r, "v")),
+            Instruction::Add(v, r) => binary("w_add(\"ADD\", a, b)", v, r),
+            Instruction::Sub(v, r) => binary("w_sub(\"SUB\", b, a)", v, r),
+            Instruction::Mult(v, r) => binary("w_mul(\"MULT\", a, b)", v, r),
+            Instruction::Div(v, r) => binary("w_div(\"DIV\", b, a)", v, r),
+            Instruction::Mod(v, r) => binary("w_mod(\"MOD\", b, a)", v, r),
+            Instruction::And(v, r) => binary("b & a", v, r),
+            Instruction::Or(v, r) => binary("b | a", v, r),
+            Instruction::Xor(v, r) => binary("b ^ a", v, r),
+            Instruction::Shl(v, r) => binary("w_shl(\"SHL\", b, a)", v, r),
+            Instruction::Shr(v, r) => binary("w_shr(b, a)", v, r),
+            Instruction::Min(v, r) => binary("b < a ? b : a", v, r),
+            Instruction::Max(v, r) => binary("b > a ? b : a", v, r),
+            Instruction::MMult(v, r) => binary("w_mmul(\"MMULT\", b, a)", v, r),
+            Instruction::MDiv(v, r) => binary("w_mdiv(\"MDIV\", b, a)", v, r),
+            Instruction::Not(v, r) => unary("~a", v, r),
+            Instruction::Neg(v, r) => unary("w_sub(\"NEG\", 0, a)", v, r),
+            Instruction::Abs(v, r) => unary("a < 0 ? w_sub(\"ABS\", 0, a) : a", v, r),
+            Instruction::Goto(label) => format!("simulate(); {}", jump(label)),
+            Instruction::GoIf(cond, a, b, label) => format!(
+                "{{ word a = {}; word b = {}; if (a {} b) {{ simulate(); {} }} }}",
+                read_ref(a), read_ref(b), condition(cond), jump(label)
+            ),
+            Instruction::Print(v, t, stream) => format!("print({}, {}, {}, 1);", file(stream), c_type(t), read_ref(v)),
+            Instruction::Put(v, t, stream) => format!("print({}, {}, {}, 0);", file(stream), c_type(t), read_ref(v)),
+            Instruction::Printf(v, stream) => format!("print_format({}, {});", file(stream), read_ref(v)),
+            Instruction::Push(v) => format!("push({});", read_ref(v)),
+            Instruction::Pop(r) => format!("{{ word v = pop(); {} }}", write_ref(r, "v")),
+            Instruction::Crash => "simulate(); return finish();".to_string(),
+            Instruction::Buy(n) => format!("buy({});", read_ref(n)),
+            Instruction::Sell(n) => format!("sell({});", read_ref(n)),
+            Instruction::DeclareLabel(_) | Instruction::Nop => String::new(),
+            Instruction::Call(label) => match labels.get(label) {
+                Some(_) if returns => format!("call_push({pc}, \"{label}\"); simulate(); {}\nret_{pc}:;", jump(label)),
+                Some(_) => format!("call_push({pc}, \"{label}\"); simulate(); {}", jump(label)),
+                None => format!("call_push({pc}, \"{label}\"); {}", jump(label)),
+            },
+            Instruction::Ret => {
+                let cases: String = call_sites.iter().map(|site| format!(" case {site}: goto ret_{site};")).collect();
+                format!("{{ size_t site = call_pop(); simulate(); switch (site) {{{cases} }} }}")
+            },
+            Instruction::Read(r, t) => {
+                let call = match t {
+                    Type::Int | Type::Bool => "read_int()".to_string(),
+                    Type::Char => "read_char()".to_string(),
+                    Type::Money => "read_money()".to_string(),
+                    Type::Str => format!("read_str({pc}, {})", c_string(&inst.to_string())),
+                };
+                format!("{{ word v = {call}; {} }}", write_ref(r, "v"))
+            },
+            Instruction::Alloc(n, r) => format!(
+                "{{ word v = heap_alloc({}, {pc}, {}); {} }}",
+                read_ref(n), c_string(&inst.to_string()), write_ref(r, "v")
+            ),
+            Instruction::Free(v) => format!("heap_free({});", read_ref(v)),
+            Instruction::Enter(words) => format!("enter_frame({words});"),
+            Instruction::Leave => "leave_frame();".to_string(),
+            Instruction::Open(name, mode, r) => {
+                let mode = match mode {
+                    FileMode::Read => "MODE_READ",
+                    FileMode::Write => "MODE_WRITE",
+                    FileMode::Append => "MODE_APPEND",
+                };
+                format!("{{ word v = file_open({}, {mode}); {} }}", read_ref(name), write_ref(r, "v"))
+            },
+            Instruction::ReadLine(h, r) => format!(
+                "{{ word v = file_read_line({}, {pc}, {}); {} }}",
+                read_ref(h), c_string(&inst.to_string()), write_ref(r, "v")
+            ),
+            Instruction::Write(h, v) => format!("file_write({}, {});", read_ref(h), read_ref(v)),
+            Instruction::Close(h) => format!("file_close({});", read_ref(h)),
+            Instruction::Wait(n) => format!("wait({}, {pc}, {});", read_ref(n), c_string(&inst.to_string())),
+        };
+        if !code.is_empty() {
+            writeln!(c, "    {code}").unwrap();
+        }
+
+        match inst {
+            Instruction::Goto(_) | Instruction::Crash | Instruction::Call(_) | Instruction::Ret | Instruction::Wait(_) => (),
+            Instruction::DeclareLabel(label) => {
+                writeln!(c, "    simulate();").unwrap();
+                if targets.contains(&label) {
+                    writeln!(c, "{}:;", label_name(label)).unwrap();
+                }
+            },
+            _ => writeln!(c, "    simulate();").unwrap(),
+        }
+    }
+
+    writeln!(c, "    return finish();").unwrap();
+    writeln!(c, "}}").unwrap();
+    c
+}
+
+/// Reads the operand `a` and then the destination `b`, in the same order as
+/// the interpreter, and stores `op` back into the destination.
+fn binary(op: &str, v: &Reference, r: &Reference) -> String {
+    format!("{{ word a = {}; word b = {}; {} }}", read_ref(v), read_ref(r), write_ref(r, op))
+}
+
+/// Reads the operand `a` and stores `op` in the destination without reading it.
+fn unary(op: &str, v: &Reference, r: &Reference) -> String {
+    format!("{{ word a = {}; {} }}", read_ref(v), write_ref(r, op))
+}
+
+/// A C string literal with the text of an instruction, for the leak report.
+fn c_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn label_name(label: &str) -> String {
+    format!("label_{label}")
+}
+
+fn register(r: &Register) -> String {
+    match r {
+        Register::Fund1 => "FUND1".to_string(),
+        Register::Fund2 => "FUND2".to_string(),
+        Register::General(n) => format!("R0 + {n}"),
+        Register::Fp => "FP".to_string(),
+    }
+}
+
+fn sensor(s: &Sensor) -> &'static str {
+    match s {
+        Sensor::Shares => "SHARES",
+        Sensor::Stockprice => "STOCKPRICE",
+        Sensor::Reputation => "REPUTATION",
+        Sensor::MarketValue => "MARKETVAL",
+        Sensor::Equity => "EQUITY",
+        Sensor::Owned => "OWNED",
+        Sensor::Balance => "BALANCE",
+    }
+}
+
+fn condition(cond: &Condition) -> &'static str {
+    match cond {
+        Condition::Equals => "==",
+        Condition::Different => "!=",
+        Condition::Greater => ">",
+        Condition::Lesser => "<",
+        Condition::GreaterOrEqual => ">=",
+        Condition::LesserOrEqual => "<=",
+    }
+}
+
+fn file(stream: &Stream) -> &'static str {
+    match stream {
+        Stream::Out => "stdout",
+        Stream::Err => "stderr",
+    }
+}
+
+fn c_type(t: &Type) -> &'static str {
+    match t {
+        Type::Int => "T_INT",
+        Type::Bool => "T_BOOL",
+        Type::Char => "T_CHAR",
+        Type::Str => "T_STR",
+        Type::Money => "T_MONEY",
+    }
+}
+
+fn general_reg(g: &GeneralRegister) -> String {
+    match g {
+        GeneralRegister::Register(r) => format!("reg_get({})", register(r)),
+        GeneralRegister::Sensor(s) => format!("sensors[{}]", sensor(s)),
+        GeneralRegister::Value(n) => literal(*n),
+    }
+}
+
+/// A C literal for `n`. The smallest words have no literal of their own,
+/// since their magnitude does not fit in the type of a positive literal.
+fn literal(n: Word) -> String {
+    if n == i64::MIN {
+        "INT64_MIN".to_string()
+    } else if n == i32::MIN.into() {
+        "INT32_MIN".to_string()
+    } else if i32::try_from(n).is_err() {
+        format!("INT64_C({n})")
+    } else {
+        n.to_string()
+    }
+}
+
+/// C expression reading the value of a reference.
+fn read_ref(r: &Reference) -> String {
+    match r {
+        Reference::Register(r) => format!("reg_get({})", register(r)),
+        Reference::Sensor(s) => format!("sensors[{}]", sensor(s)),
+        Reference::Value(n) => literal(*n),
+        _ => format!("mem_get({})", address(r)),
+    }
+}
+
+/// C statement storing `value` at a writable reference.
+fn write_ref(r: &Reference, value: &str) -> String {
+    match r {
+        Reference::Register(r) => format!("reg_set({}, {value});", register(r)),
+        Reference::Sensor(_) | Reference::Value(_) => panic!("[C] Unable to modify readonly value {r}."),
+        // The value is computed first, as the interpreter does before finding the address.
+        _ => format!("{{ word w = {value}; mem_set({}, w); }}", address(r)),
+    }
+}
+
+/// C expression with the address of a memory operand, as a wide so that
+/// offsets and sums do not overflow. The bases of `*(R+R)` and `*R[i]` are read
+/// in order through `base_a` and `base_b`.
+fn address(r: &Reference) -> String {
+    match r {
+        Reference::Address(g) => general_reg(g),
+        Reference::Offset(g, n) => format!("(wide){} + {}", general_reg(g), literal(*n)),
+        Reference::Sum(a, b) => {
+            format!("(base_a = {}, base_b = {}, (wide)base_a + base_b)", general_reg(a), general_reg(b))
+        },
+        Reference::Index(g, i) => {
+            format!("(base_a = {}, base_b = {}, element(base_a, base_b))", general_reg(g), general_reg(i))
+        },
+        _ => unreachable!("[C] {r} is not a memory operand."),
+    }
+}
@@ -0,0 +1,949 @@
+/* Runtime support for .invm programs translated to C by `invm c`.
+ * Mirrors the interpreter in vm.rs, vm/arith.rs, memory.rs and vm/simulation.rs.
+ * MEM_SIZE, DATA_SIZE, CALL_LIMIT, GENERAL_REGISTERS, HEAP_DEBUG, TRACE,
+ * WORD_BITS and OVERFLOW are defined by the translator, and FILES_DIR if a directory
+ * was granted to OPEN. */
+#define _XOPEN_SOURCE 700
+#include <errno.h>
+#include <fcntl.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <time.h>
+#include <unistd.h>
+#include <sys/stat.h>
+
+/* A register, sensor or memory word, and a type that holds any result of
+ * arithmetic on two of them exactly. */
+#if WORD_BITS == 64
+typedef int64_t word;
+#define WORD_MIN INT64_MIN
+#define WORD_MAX INT64_MAX
+#else
+typedef int32_t word;
+#define WORD_MIN INT32_MIN
+#define WORD_MAX INT32_MAX
+#endif
+typedef __int128 wide;
+
+/* The modes of .arith, in the order of Overflow. */
+enum { WRAPPING, SATURATING, TRAPPING };
+
+/* General-purpose register Rn is R0 + n. */
+enum { FUND1, FUND2, R0, FP = R0 + GENERAL_REGISTERS, REGISTERS };
+enum { SHARES, STOCKPRICE, REPUTATION, MARKETVAL, EQUITY, OWNED, BALANCE, SENSORS };
+
+static word regs[REGISTERS];
+static int reg_init[REGISTERS];
+/* Cents in a real, as in money::SCALE. STOCKPRICE, MARKETVAL, EQUITY and
+ * BALANCE hold cents. */
+#define SCALE 100
+static word sensors[SENSORS] = { 0, 200 * SCALE, 50, 200 * SCALE, 0, 0, 10000 * SCALE };
+/* Static data, then the heap growing up to heap_top, then the stack growing
+ * down from the end of memory to sp. */
+static word *mem;
+static size_t heap_top;
+static size_t sp;
+
+/* Heap blocks, as in Memory: the allocated blocks, the free ranges below the
+ * heap top sorted by address, and with HEAP_DEBUG the freed blocks. */
+typedef struct { size_t addr, words, site; const char *inst; } block_t;
+static block_t *blocks, *free_ranges, *freed;
+static size_t nblocks, nfree, nfreed;
+
+/* The index of every CALL waiting for its RET and the label it called. */
+static size_t *call_sites;
+static const char **call_labels;
+static size_t call_depth;
+
+static inline void fault(const char *msg) {
+    fflush(stdout);
+    fprintf(stderr, "%s\n", msg);
+    if (call_depth > 0) {
+        /* Outermost first, with repeated recursive calls folded, as in VM::report_calls. */
+        fprintf(stderr, "[INVM] Call chain: ");
+        for (size_t i = 0; i < call_depth;) {
+            size_t n = 1;
+            while (i + n < call_depth && strcmp(call_labels[i + n], call_labels[i]) == 0) n++;
+            fprintf(stderr, "%s$%s", i > 0 ? " -> " : "", call_labels[i]);
+            if (n > 1) fprintf(stderr, " (x%zu)", n);
+            i += n;
+        }
+        fprintf(stderr, "\n");
+    }
+    /* The frames of ENTER from FP outwards, as in VM::report_frames. */
+    if (mem != NULL && regs[FP] >= (int64_t)sp && regs[FP] < MEM_SIZE) {
+        size_t fp = (size_t)regs[FP], bottom = sp;
+        fprintf(stderr, "[INVM] Frames, innermost first:\n");
+        for (int shown = 0;; shown++) {
+            if (shown == 16) {
+                fprintf(stderr, "[INVM]   ...\n");
+                break;
+            }
+            fprintf(stderr, "[INVM]   FP %zu: ", fp);
+            if (fp == bottom) fprintf(stderr, "no locals");
+            for (size_t addr = fp - 1; addr + 1 > bottom && fp - addr <= 8; addr--) {
+                fprintf(stderr, "%sFP-%zu = %lld", addr + 1 == fp ? "" : ", ", fp - addr, (long long)mem[addr]);
+            }
+            if (fp - bottom > 8) fprintf(stderr, ", ...");
+            fprintf(stderr, "\n");
+            word saved = mem[fp];
+            if ((int64_t)saved <= (int64_t)fp || saved >= MEM_SIZE) break;
+            bottom = fp + 1;
+            fp = (size_t)saved;
+        }
+    }
+    exit(101);
+}
+
+/* Writes the decimal digits of n to buf, which holds at least 41 bytes. */
+static inline const char *format_wide(wide n, char *buf) {
+    char *c = buf + 40;
+    unsigned __int128 u = n < 0 ? -(unsigned __int128)n : (unsigned __int128)n;
+    *c = '\0';
+    do *--c = (char)('0' + (int)(u % 10)); while ((u /= 10) != 0);
+    if (n < 0) *--c = '-';
+    return c;
+}
+
+/* The word holding the exact result of what, following the overflow mode of
+ * .arith when it does not fit, as in Arith::fit. */
+static inline word fit(const char *what, wide exact) {
+    if (exact >= WORD_MIN && exact <= WORD_MAX) return (word)exact;
+    if (OVERFLOW == SATURATING) return exact < 0 ? WORD_MIN : WORD_MAX;
+    if (OVERFLOW == TRAPPING) {
+        char msg[128], digits[41];
+        snprintf(msg, sizeof msg, "[INVM] Overflow in %s: %s does not fit in %d bits.", what, format_wide(exact, digits), WORD_BITS);
+        fault(msg);
+    }
+    return (word)exact;
+}
+
+static inline word w_add(const char *what, word a, word b) { return fit(what, (wide)a + b); }
+static inline word w_sub(const char *what, word a, word b) { return fit(what, (wide)a - b); }
+static inline word w_mul(const char *what, word a, word b) { return fit(what, (wide)a * b); }
+
+/* Faults for DIV, MOD and MDIV by 0, as in Fault::DivideByZero. */
+static inline void check_divisor(const char *what, word b) {
+    if (b == 0) {
+        char msg[64];
+        snprintf(msg, sizeof msg, "[INVM] Division by zero in %s.", what);
+        fault(msg);
+    }
+}
+
+static inline word w_div(const char *what, word a, word b) {
+    check_divisor(what, b);
+    return fit(what, (wide)a / b);
+}
+
+static inline word w_mod(const char *what, word a, word b) {
+    check_divisor(what, b);
+    return fit(what, (wide)a % b);
+}
+
+static inline unsigned shift_amount(word b) {
+    if (b < 0 || b >= WORD_BITS) {
+        char msg[64];
+        snprintf(msg, sizeof msg, "[INVM] Shift by %lld bits, expected 0 to %d.", (long long)b, WORD_BITS - 1);
+        fault(msg);
+    }
+    return (unsigned)b;
+}
+
+/* A left shift overflows like a multiplication by a power of two. */
+static inline word w_shl(const char *what, word a, word b) {
+    return fit(what, (wide)a * ((wide)1 << shift_amount(b)));
+}
+
+static inline word w_shr(word a, word b) { return a >> shift_amount(b); }
+
+/* MMULT and MDIV, truncating to whole cents. */
+static inline word w_mmul(const char *what, word a, word b) { return fit(what, (wide)a * b / SCALE); }
+
+static inline word w_mdiv(const char *what, word a, word b) {
+    check_divisor(what, b);
+    return fit(what, (wide)a * SCALE / b);
+}
+
+/* Writes cents as in money::format, to buf, which holds at least 64 bytes. */
+static inline const char *format_money(wide cents, char *buf) {
+    char digits[41], *c = buf;
+    unsigned __int128 magnitude = cents < 0 ? -(unsigned __int128)cents : (unsigned __int128)cents;
+    const char *whole = format_wide((wide)(magnitude / SCALE), digits);
+    size_t len = strlen(whole);
+    if (cents < 0) *c++ = '-';
+    c += sprintf(c, "R$ ");
+    for (size_t i = 0; i < len; i++) {
+        if (i > 0 && (len - i) % 3 == 0) *c++ = '.';
+        *c++ = whole[i];
+    }
+    sprintf(c, ",%02u", (unsigned)(magnitude % SCALE));
+    return buf;
+}
+
+static inline word reg_get(int r) {
+    if (!reg_init[r]) {
+        char msg[64];
+        if (r < R0) snprintf(msg, sizeof msg, "[INVM] Use of uninitialized register FUND%d.", r + 1);
+        else snprintf(msg, sizeof msg, "[INVM] Use of uninitialized register R%d.", r - R0);
+        fault(msg);
+    }
+    return regs[r];
+}
+
+static inline void reg_set(int r, word val) {
+    regs[r] = val;
+    reg_init[r] = 1;
+}
+
+/* Files of OPEN, READLINE, WRITE and CLOSE, as in vm/files.rs. Handle h is
+ * files[h - 1]. */
+enum { FILE_NOT_FOUND = -1, FILE_DENIED = -2, FILE_ERROR = -3, FILE_END = -4 };
+enum { MODE_READ, MODE_WRITE, MODE_APPEND };
+#define MAX_OPEN 64
+static FILE *files[MAX_OPEN];
+static int file_modes[MAX_OPEN];
+/* The granted directory, canonical, or NULL if no file may be opened. */
+static char *files_dir;
+
+static inline void files_init(void) {
+#ifdef FILES_DIR
+    char msg[4200];
+    files_dir = realpath(FILES_DIR, NULL);
+    if (files_dir == NULL) {
+        snprintf(msg, sizeof msg, "[INVM] Failed to use %s for files: %s (os error %d).", FILES_DIR, strerror(errno), errno);
+        fault(msg);
+    }
+    struct stat st;
+    if (stat(files_dir, &st) != 0 || !S_ISDIR(st.st_mode)) {
+        snprintf(msg, sizeof msg, "[INVM] %s is not a directory.", FILES_DIR);
+        fault(msg);
+    }
+#endif
+}
+
+static inline void runtime_init(void) {
+    mem = calloc(MEM_SIZE, sizeof *mem);
+    call_sites = calloc(CALL_LIMIT + 1, sizeof *call_sites);
+    call_labels = calloc(CALL_LIMIT + 1, sizeof *call_labels);
+    if (mem == NULL || call_sites == NULL || call_labels == NULL) fault("[INVM] Failed to allocate memory.");
+    heap_top = DATA_SIZE;
+    sp = MEM_SIZE;
+    reg_set(FP, MEM_SIZE);
+    files_init();
+}
+
+static inline size_t to_address(wide v) {
+    if (v < 0) {
+        char msg[96], digits[41];
+        snprintf(msg, sizeof msg, "[INVM] Segmentation fault at address %s.", format_wide(v, digits));
+        fault(msg);
+    }
+    return (size_t)v;
+}
+
+/* Only the data, the allocated heap and the live stack are mapped. */
+static inline size_t check(size_t addr) {
+    if (addr >= heap_top && (addr < sp || addr >= MEM_SIZE)) {
+        char msg[64];
+        snprintf(msg, sizeof msg, "[INVM] Segmentation fault at address %zu.", addr);
+        fault(msg);
+    }
+    for (size_t i = 0; HEAP_DEBUG && i < nfreed; i++) {
+        if (addr >= freed[i].addr && addr < freed[i].addr + freed[i].words) {
+            char msg[128];
+            snprintf(msg, sizeof msg, "[INVM] Use after free at address %zu, in the block of %zu words at %zu.",
+                addr, freed[i].words, freed[i].addr);
+            fault(msg);
+        }
+    }
+    return addr;
+}
+
+static inline word mem_get(wide v) {
+    return mem[check(to_address(v))];
+}
+
+static inline void mem_set(wide v, word val) {
+    mem[check(to_address(v))] = val;
+}
+
+/* The address of element i of the array at base, whose first word is its length. */
+static inline wide element(word base, word i) {
+    size_t addr = to_address(base);
+    word len = mem_get(base);
+    if (i < 0 || i >= len) {
+        char msg[160];
+        snprintf(msg, sizeof msg, "[INVM] Index %lld out of bounds for the array of length %lld at address %zu.",
+            (long long)i, (long long)len, addr);
+        fault(msg);
+    }
+    return (wide)(addr + 1 + (size_t)i);
+}
+
+static inline void push(word val) {
+    if (sp == heap_top) fault("[INVM] Stack overflow.");
+    mem[--sp] = val;
+}
+
+static inline word pop(void) {
+    if (sp == MEM_SIZE) fault("[INVM] Pop used on an empty stack.");
+    return mem[sp++];
+}
+
+static inline void append(block_t **list, size_t *len, block_t block) {
+    block_t *grown = realloc(*list, (*len + 1) * sizeof **list);
+    if (grown == NULL) fault("[INVM] Failed to allocate memory.");
+    *list = grown;
+    (*list)[(*len)++] = block;
+}
+
+static inline void remove_at(block_t *list, size_t *len, size_t i) {
+    memmove(&list[i], &list[i + 1], (*len - i - 1) * sizeof *list);
+    (*len)--;
+}
+
+/* Allocates a zeroed block in the lowest free range that fits, or else grows the heap. */
+static inline size_t alloc(size_t words, size_t site, const char *inst) {
+    size_t addr, i = 0;
+    while (i < nfree && free_ranges[i].words < words) i++;
+    if (i < nfree) {
+        addr = free_ranges[i].addr;
+        free_ranges[i].addr += words;
+        free_ranges[i].words -= words;
+        if (free_ranges[i].words == 0) remove_at(free_ranges, &nfree, i);
+    } else {
+        if (words > sp - heap_top) {
+            char msg[128];
+            snprintf(msg, sizeof msg, "[INVM] Heap overflow: %zu words do not fit between the heap and the stack.", words);
+            fault(msg);
+        }
+        addr = heap_top;
+        heap_top += words;
+    }
+    memset(&mem[addr], 0, words * sizeof *mem);
+    append(&blocks, &nblocks, (block_t){ addr, words, site, inst });
+    return addr;
+}
+
+static inline word heap_alloc(word words, size_t site, const char *inst) {
+    if (words < 1) {
+        char msg[64];
+        snprintf(msg, sizeof msg, "[INVM] ALLOC of %lld words, expected at least 1.", (long long)words);
+        fault(msg);
+    }
+    return (word)alloc((size_t)words, site, inst);
+}
+
+/* Frees a block, merging it with the free ranges around it. */
+static inline void heap_free(word v) {
+    size_t addr = to_address(v), i = 0;
+    while (i < nblocks && blocks[i].addr != addr) i++;
+    if (i == nblocks) {
+        char msg[128];
+        for (size_t j = 0; HEAP_DEBUG && j < nfreed; j++) {
+            if (freed[j].addr == addr) {
+                snprintf(msg, sizeof msg, "[INVM] Double free of address %zu.", addr);
+                fault(msg);
+            }
+        }
+        snprintf(msg, sizeof msg, "[INVM] FREE of address %zu, which is not the start of an allocated block.", addr);
+        fault(msg);
+    }
+    block_t block = blocks[i];
+    remove_at(blocks, &nblocks, i);
+    if (HEAP_DEBUG) {
+        append(&freed, &nfreed, block);
+        return;
+    }
+
+    size_t start = addr, words = block.words, at = 0;
+    while (at < nfree && free_ranges[at].addr < addr) at++;
+    if (at < nfree && free_ranges[at].addr == addr + block.words) {
+        words += free_ranges[at].words;
+        remove_at(free_ranges, &nfree, at);
+    }
+    if (at > 0 && free_ranges[at - 1].addr + free_ranges[at - 1].words == addr) {
+        at--;
+        start = free_ranges[at].addr;
+        words += free_ranges[at].words;
+        remove_at(free_ranges, &nfree, at);
+    }
+    if (start + words == heap_top) {
+        heap_top = start;
+    } else {
+        append(&free_ranges, &nfree, (block_t){ 0 });
+        memmove(&free_ranges[at + 1], &free_ranges[at], (nfree - at - 1) * sizeof *free_ranges);
+        free_ranges[at] = (block_t){ start, words, 0, NULL };
+    }
+}
+
+static int by_address(const void *a, const void *b) {
+    size_t x = ((const block_t *)a)->addr, y = ((const block_t *)b)->addr;
+    return (x > y) - (x < y);
+}
+
+/* Reports the blocks never freed with HEAP_DEBUG, as in VM::report_leaks, and returns the exit status. */
+static inline int finish(void) {
+    if (HEAP_DEBUG && nblocks > 0) {
+        fflush(stdout);
+        size_t words = 0;
+        for (size_t i = 0; i < nblocks; i++) words += blocks[i].words;
+        qsort(blocks, nblocks, sizeof *blocks, by_address);
+        fprintf(stderr, "[INVM] Leak: %zu blocks, %zu words still allocated.\n", nblocks, words);
+        for (size_t i = 0; i < nblocks; i++) {
+            fprintf(stderr, "[INVM]   %zu words at address %zu, allocated by instruction %zu: %s\n",
+                blocks[i].words, blocks[i].addr, blocks[i].site, blocks[i].inst);
+        }
+    }
+    return 0;
+}
+
+/* ENTER and LEAVE, as in VM::enter_frame and VM::leave_frame. */
+static inline void enter_frame(size_t words) {
+    push(regs[FP]);
+    regs[FP] = (word)sp;
+    for (size_t i = 0; i < words; i++) push(0);
+}
+
+static inline void leave_frame(void) {
+    if (regs[FP] == MEM_SIZE) fault("[INVM] LEAVE without a frame.");
+    if (regs[FP] < (int64_t)sp || regs[FP] > MEM_SIZE) {
+        char msg[96];
+        snprintf(msg, sizeof msg, "[INVM] LEAVE with FP at %lld, outside of the stack.", (long long)regs[FP]);
+        fault(msg);
+    }
+    sp = (size_t)regs[FP];
+    regs[FP] = pop();
+}
+
+static inline void call_push(size_t site, const char *label) {
+    if (call_depth == CALL_LIMIT) {
+        char msg[96];
+        snprintf(msg, sizeof msg, "[INVM] Call stack overflow: more than %d nested calls.", CALL_LIMIT);
+        fault(msg);
+    }
+    call_sites[call_depth] = site;
+    call_labels[call_depth] = label;
+    call_depth++;
+}
+
+static inline size_t call_pop(void) {
+    if (call_depth == 0) fault("[INVM] RET without a caller.");
+    return call_sites[--call_depth];
+}
+
+/* Writes the UTF-8 encoding of c to buf, returning its length. */
+static inline size_t encode_utf8(word c, char *buf) {
+    if (c < 0 || (c >= 0xD800 && c <= 0xDFFF) || c > 0x10FFFF) fault("[INVM] Invalid conversion of character.");
+    if (c < 0x80) {
+        buf[0] = (char)c;
+        return 1;
+    } else if (c < 0x800) {
+        buf[0] = (char)(0xC0 | (c >> 6));
+        buf[1] = (char)(0x80 | (c & 0x3F));
+        return 2;
+    } else if (c < 0x10000) {
+        buf[0] = (char)(0xE0 | (c >> 12));
+        buf[1] = (char)(0x80 | ((c >> 6) & 0x3F));
+        buf[2] = (char)(0x80 | (c & 0x3F));
+        return 3;
+    }
+    buf[0] = (char)(0xF0 | (c >> 18));
+    buf[1] = (char)(0x80 | ((c >> 12) & 0x3F));
+    buf[2] = (char)(0x80 | ((c >> 6) & 0x3F));
+    buf[3] = (char)(0x80 | (c & 0x3F));
+    return 4;
+}
+
+static inline void put_utf8(FILE *out, word c) {
+    char buf[4];
+    fwrite(buf, 1, encode_utf8(c, buf), out);
+}
+
+/* The types of PRINT, in the order of Type. */
+enum { T_INT, T_BOOL, T_CHAR, T_STR, T_MONEY };
+
+/* Writes a value as in VM::show. */
+static inline void show(FILE *out, int type, word val) {
+    switch (type) {
+    case T_INT: fprintf(out, "%lld", (long long)val); break;
+    case T_BOOL: fputs(val == 0 ? "bizarro" : "certeza", out); break;
+    case T_CHAR: put_utf8(out, (uint8_t)(val % 256)); break;
+    case T_MONEY: {
+        char buf[64];
+        fputs(format_money(val, buf), out);
+        break;
+    }
+    default: {
+        size_t addr = to_address(val);
+        word size = mem[check(addr)];
+        for (word i = 0; i < size; i++) {
+            put_utf8(out, mem[check(addr + 1 + (size_t)i)]);
+        }
+    }
+    }
+}
+
+/* PRINT with a newline and PUT without one. Output that does not end a line
+ * is flushed, so that a prompt shows before READ, and stdout is flushed before
+ * writing to stderr, so that both keep the order of the interpreter. */
+static inline void print(FILE *out, int type, word val, int newline) {
+    if (out == stderr) fflush(stdout);
+    show(out, type, val);
+    if (newline) fputc('\n', out);
+    else if (out == stdout) fflush(stdout);
+}
+
+/* PRINTF, as in VM::printf: checks the format and that the stack has a value
+ * for every placeholder, then prints them from the deepest one and pops them. */
+static inline void print_format(FILE *out, word format) {
+    size_t addr = to_address(format);
+    word size = mem[check(addr)];
+    size_t count = 0;
+    char buf[4];
+    for (word i = 0; i < size; i++) {
+        encode_utf8(mem[check(addr + 1 + (size_t)i)], buf);
+    }
+    for (word i = 0; i < size; i++) {
+        word c = mem[addr + 1 + (size_t)i];
+        if (c != '%') continue;
+        if (++i == size) {
+            char msg[128];
+            snprintf(msg, sizeof msg, "[INVM] Invalid placeholder %% in the PRINTF format at address %zu.", addr);
+            fault(msg);
+        }
+        c = mem[addr + 1 + (size_t)i];
+        if (c == 'd' || c == 'b' || c == 'c' || c == 's' || c == 'm') {
+            count++;
+        } else if (c != '%') {
+            char msg[128];
+            size_t len = encode_utf8(c, buf);
+            snprintf(msg, sizeof msg, "[INVM] Invalid placeholder %%%.*s in the PRINTF format at address %zu.", (int)len, buf, addr);
+            fault(msg);
+        }
+    }
+    if (MEM_SIZE - sp < count) {
+        char msg[96];
+        snprintf(msg, sizeof msg, "[INVM] PRINTF needs %zu values, but the stack has %zu.", count, (size_t)(MEM_SIZE - sp));
+        fault(msg);
+    }
+
+    if (out == stderr) fflush(stdout);
+    size_t arg = sp + count;
+    word last = 0;
+    for (word i = 0; i < size; i++) {
+        last = mem[addr + 1 + (size_t)i];
+        if (last != '%') {
+            put_utf8(out, last);
+            continue;
+        }
+        switch (mem[addr + 1 + (size_t)++i]) {
+        case 'd': show(out, T_INT, mem[--arg]); break;
+        case 'b': show(out, T_BOOL, mem[--arg]); break;
+        case 'c': show(out, T_CHAR, mem[--arg]); break;
+        case 's': show(out, T_STR, mem[--arg]); break;
+        case 'm': show(out, T_MONEY, mem[--arg]); break;
+        default: fputc('%', out);
+        }
+    }
+    sp += count;
+    if (out == stdout && last != '\n') fflush(stdout);
+}
+
+/* Reads a line without the trailing whitespace, like read_line + trim_end. */
+static inline void read_line(char *buf, size_t size) {
+    fflush(stdout);
+    if (fgets(buf, (int)size, stdin) == NULL) buf[0] = '\0';
+    size_t len = strlen(buf);
+    while (len > 0 && (buf[len - 1] == '\n' || buf[len - 1] == '\r' || buf[len - 1] == ' ' || buf[len - 1] == '\t')) {
+        buf[--len] = '\0';
+    }
+}
+
+static inline word read_int(void) {
+    char buf[4096];
+    read_line(buf, sizeof buf);
+    const char *digits = buf;
+    if (*digits == '+' || *digits == '-') digits++;
+    if (*digits == '\0') fault("[INVM] Failed to convert READ value to int.");
+    for (const char *c = digits; *c; c++) {
+        if (*c < '0' || *c > '9') fault("[INVM] Failed to convert READ value to int.");
+    }
+    errno = 0;
+    long long n = strtoll(buf, NULL, 10);
+    if (errno == ERANGE || n < WORD_MIN || n > WORD_MAX) fault("[INVM] Failed to convert READ value to int.");
+    return (word)n;
+}
+
+/* The cents of a non-empty run of n decimal digits, or -1 if it is not one. */
+static inline wide money_digits(const char *s, size_t n) {
+    wide value = 0;
+    if (n == 0) return -1;
+    for (size_t i = 0; i < n; i++) {
+        if (s[i] < '0' || s[i] > '9') return -1;
+        if (value > WORD_MAX) return -1;
+        value = value * 10 + (s[i] - '0');
+    }
+    return value;
+}
+
+/* Reads an amount as in money::parse: an optional sign and R$, the reais
+ * with or without . between groups of three digits, and up to two decimal
+ * places after a ,. */
+static inline word read_money(void) {
+    char buf[4096], whole[4096];
+    read_line(buf, sizeof buf);
+    const char *c = buf;
+    int negative = *c == '-';
+    if (negative) c++;
+    if (strncmp(c, "R$", 2) == 0) {
+        c += 2;
+        while (*c == ' ' || *c == '\t' || *c == '\n' || *c == '\r') c++;
+    }
+    const char *comma = strchr(c, ',');
+    size_t whole_len = comma != NULL ? (size_t)(comma - c) : strlen(c);
+
+    /* The reais without the dots, which must separate groups of three digits. */
+    size_t len = 0, group = 0, groups = 0, first = 0;
+    int valid = 1;
+    for (size_t i = 0; i < whole_len; i++) {
+        if (c[i] == '.') {
+            if (groups == 0) first = group;
+            else if (group != 3) valid = 0;
+            groups++;
+            group = 0;
+        } else {
+            whole[len++] = c[i];
+            group++;
+        }
+    }
+    if (groups > 0 && (first < 1 || first > 3 || group != 3)) valid = 0;
+    wide reais = money_digits(whole, len), cents = 0;
+    if (comma != NULL) {
+        size_t n = strlen(comma + 1);
+        cents = n >= 1 && n <= 2 ? money_digits(comma + 1, n) * (n == 1 ? 10 : 1) : -1;
+    }
+    if (!valid || reais < 0 || cents < 0) fault("[INVM] Failed to convert READ value to money.");
+    wide exact = reais * SCALE + cents;
+    if (negative) exact = -exact;
+    if (exact < WORD_MIN || exact > WORD_MAX) fault("[INVM] Failed to convert READ value to money.");
+    return (word)exact;
+}
+
+static inline word read_char(void) {
+    char buf[4096];
+    read_line(buf, sizeof buf);
+    if (strlen(buf) != 1) {
+        fault("[INVM] Failed to convert READ value to char. Expected a single character, not multiple.");
+    }
+    return (word)(unsigned char)buf[0];
+}
+
+/* Stores the number of characters followed by every character decoded from
+ * the n bytes of buf on the heap, as in Memory::alloc_str. */
+static inline word store_str(const char *buf, size_t n, size_t site, const char *inst) {
+    word *chars = malloc((n + 1) * sizeof *chars);
+    if (chars == NULL) fault("[INVM] Failed to allocate memory.");
+    size_t len = 0;
+    const unsigned char *c = (const unsigned char *)buf, *end = c + n;
+    while (c < end) {
+        uint32_t cp;
+        int extra;
+        if (*c < 0x80) { cp = *c; extra = 0; }
+        else if (*c < 0xE0) { cp = *c & 0x1F; extra = 1; }
+        else if (*c < 0xF0) { cp = *c & 0x0F; extra = 2; }
+        else { cp = *c & 0x07; extra = 3; }
+        c++;
+        for (; extra > 0 && c < end; extra--, c++) cp = (cp << 6) | (*c & 0x3F);
+        chars[len++] = (word)cp;
+    }
+    size_t addr = alloc(len + 1, site, inst);
+    mem[addr] = (word)len;
+    memcpy(&mem[addr + 1], chars, len * sizeof *chars);
+    free(chars);
+    return (word)addr;
+}
+
+static inline word read_str(size_t site, const char *inst) {
+    char buf[4096];
+    read_line(buf, sizeof buf);
+    return store_str(buf, strlen(buf), site, inst);
+}
+
+static inline word file_status(int error) {
+    if (error == ENOENT) return FILE_NOT_FOUND;
+    /* ELOOP is opening a symbolic link without following it. */
+    if (error == EACCES || error == EPERM || error == ELOOP) return FILE_DENIED;
+    return FILE_ERROR;
+}
+
+/* The string at the address as UTF-8, to be freed by the caller, with its
+ * length in bytes in *n. */
+static inline char *mem_string(word v, size_t *n) {
+    size_t addr = to_address(v);
+    word size = mem[check(addr)];
+    size_t len = size > 0 ? (size_t)size : 0;
+    char *s = malloc(len * 4 + 1), *end = s;
+    if (s == NULL) fault("[INVM] Failed to allocate memory.");
+    for (size_t i = 0; i < len; i++) end += encode_utf8(mem[check(addr + 1 + i)], end);
+    *end = '\0';
+    *n = (size_t)(end - s);
+    return s;
+}
+
+/* Whether path is dir or inside of it. */
+static inline int inside(const char *path, const char *dir) {
+    size_t len = strlen(dir);
+    if (strncmp(path, dir, len) != 0) return 0;
+    return path[len] == '\0' || path[len] == '/' || dir[len - 1] == '/';
+}
+
+/* The real path of name inside of the granted directory, to be freed by the
+ * caller, or NULL with the status in *status. name has len bytes. Names are made only of normal
+ * components, and a file that does not exist yet is created in the real
+ * directory of its parent, with *exists set to 0. As in Files::resolve, files
+ * are never written through a symbolic link. */
+static inline char *resolve(const char *name, size_t len, int mode, word *status, int *exists) {
+    *status = FILE_DENIED;
+    if (files_dir == NULL || name[0] == '\0' || name[0] == '/') return NULL;
+    for (const char *c = name; *c;) {
+        size_t n = strcspn(c, "/");
+        if ((n == 1 && c[0] == '.' && c == name) || (n == 2 && c[0] == '.' && c[1] == '.')) return NULL;
+        c += n;
+        while (*c == '/') c++;
+    }
+    /* A path cannot contain NUL. */
+    if (strlen(name) != len) {
+        *status = FILE_ERROR;
+        return NULL;
+    }
+    size_t dir_len = strlen(files_dir);
+    char *path = malloc(dir_len + strlen(name) + 2);
+    if (path == NULL) fault("[INVM] Failed to allocate memory.");
+    sprintf(path, "%s/%s", files_dir, name);
+    struct stat link;
+    if (mode != MODE_READ && lstat(path, &link) == 0 && S_ISLNK(link.st_mode)) {
+        free(path);
+        return NULL;
+    }
+
+    char *real = realpath(path, NULL);
+    *exists = real != NULL;
+    if (real == NULL && errno == ENOENT && mode != MODE_READ) {
+        size_t len = strlen(path);
+        while (path[len - 1] == '/') path[--len] = '\0';
+        char *slash = strrchr(path, '/');
+        *slash = '\0';
+        char *parent = realpath(path, NULL);
+        if (parent != NULL) {
+            real = malloc(strlen(parent) + strlen(slash + 1) + 2);
+            if (real == NULL) fault("[INVM] Failed to allocate memory.");
+            sprintf(real, "%s/%s", parent, slash + 1);
+            free(parent);
+        }
+    }
+    if (real == NULL) *status = file_status(errno);
+    free(path);
+    if (real != NULL && !inside(real, files_dir)) {
+        free(real);
+        real = NULL;
+    }
+    return real;
+}
+
+static inline word file_open(word name_addr, int mode) {
+    size_t len;
+    char *name = mem_string(name_addr, &len);
+    word status;
+    int exists;
+    char *path = resolve(name, len, mode, &status, &exists);
+    free(name);
+    if (path == NULL) return status;
+    /* As in Files::open, a new file is created only if it is still missing,
+     * and a link put in place of the checked path is not followed. */
+    int flags = O_NOFOLLOW | (mode == MODE_READ ? O_RDONLY : mode == MODE_WRITE ? O_WRONLY | O_TRUNC : O_WRONLY | O_APPEND);
+    if (!exists) flags |= O_CREAT | O_EXCL;
+    int fd = open(path, flags, 0666);
+    FILE *file = fd < 0 ? NULL : fdopen(fd, mode == MODE_READ ? "r" : mode == MODE_WRITE ? "w" : "a");
+    int error = errno;
+    if (fd >= 0 && file == NULL) close(fd);
+    free(path);
+    if (file == NULL) return file_status(error);
+    /* Only writing fails on a directory. */
+    struct stat st;
+    if (fstat(fileno(file), &st) == 0 && S_ISDIR(st.st_mode)) {
+        fclose(file);
+        return FILE_ERROR;
+    }
+    for (int i = 0; i < MAX_OPEN; i++) {
+        if (files[i] == NULL) {
+            files[i] = file;
+            file_modes[i] = mode;
+            return i + 1;
+        }
+    }
+    fclose(file);
+    return FILE_ERROR;
+}
+
+static inline int file_slot(word handle) {
+    if (handle < 1 || handle > MAX_OPEN || files[handle - 1] == NULL) {
+        char msg[64];
+        snprintf(msg, sizeof msg, "[INVM] File handle %lld is not open.", (long long)handle);
+        fault(msg);
+    }
+    return (int)handle - 1;
+}
+
+static inline word file_read_line(word handle, size_t site, const char *inst) {
+    int slot = file_slot(handle);
+    if (file_modes[slot] != MODE_READ) {
+        char msg[96];
+        snprintf(msg, sizeof msg, "[INVM] READLINE on file handle %lld, which was opened for writing.", (long long)handle);
+        fault(msg);
+    }
+    char *line = NULL;
+    size_t cap = 0;
+    ssize_t n = getline(&line, &cap, files[slot]);
+    if (n < 0) {
+        int error = ferror(files[slot]);
+        free(line);
+        return error ? FILE_ERROR : FILE_END;
+    }
+    if (n > 0 && line[n - 1] == '\n') n--;
+    if (n > 0 && line[n - 1] == '\r') n--;
+    word addr = store_str(line, (size_t)n, site, inst);
+    free(line);
+    return addr;
+}
+
+static inline void file_write(word handle, word text) {
+    int slot = file_slot(handle);
+    if (file_modes[slot] == MODE_READ) {
+        char msg[96];
+        snprintf(msg, sizeof msg, "[INVM] WRITE on file handle %lld, which was opened for reading.", (long long)handle);
+        fault(msg);
+    }
+    size_t len;
+    char *s = mem_string(text, &len);
+    if (fwrite(s, 1, len, files[slot]) != len || fflush(files[slot]) != 0) {
+        char msg[160];
+        snprintf(msg, sizeof msg, "[INVM] Failed to WRITE to file handle %lld: %s (os error %d).", (long long)handle, strerror(errno), errno);
+        fault(msg);
+    }
+    free(s);
+}
+
+static inline void file_close(word handle) {
+    int slot = file_slot(handle);
+    fclose(files[slot]);
+    files[slot] = NULL;
+}
+
+/* The number of stocks what trades, as in VM::stocks. */
+static inline word stocks(const char *what, word amount) {
+    if (amount < 0) {
+        char msg[96];
+        snprintf(msg, sizeof msg, "[INVM] %s of %lld stocks, expected at least 0.", what, (long long)amount);
+        fault(msg);
+    }
+    return amount;
+}
+
+/* As in VM::buy, with the exact total price and the sensors written last. */
+static inline void buy(word amount) {
+    stocks("BUY", amount);
+    word balance = sensors[BALANCE];
+    word stockprice = sensors[STOCKPRICE];
+    wide total_price = (wide)amount * stockprice;
+    if (balance < total_price) {
+        char msg[320], b[64], p[64], t[64];
+        snprintf(msg, sizeof msg,
+            "[INVM] Insufficient balance (%s) to buy %lld stocks at price %s (total price: %s).",
+            format_money(balance, b), (long long)amount, format_money(stockprice, p), format_money(total_price, t));
+        fault(msg);
+    }
+    word owned = w_add("BUY", sensors[OWNED], amount);
+    sensors[BALANCE] = (word)(balance - total_price);
+    sensors[OWNED] = owned;
+}
+
+static inline void sell(word amount) {
+    stocks("SELL", amount);
+    word owned = sensors[OWNED];
+    if (amount > owned) {
+        char msg[128];
+        snprintf(msg, sizeof msg, "[INVM] Insufficient stocks to sell (owned: %lld, sell: %lld).",
+            (long long)owned, (long long)amount);
+        fault(msg);
+    }
+    wide total_price = (wide)amount * sensors[STOCKPRICE];
+    sensors[BALANCE] = fit("SELL", sensors[BALANCE] + total_price);
+    sensors[OWNED] = owned - amount;
+}
+
+/* A random shift from -max to max. */
+static inline word random_shift(word max) {
+    return (word)(rand() % (2 * max + 1)) - max;
+}
+
+/* The name of the market simulation in overflow faults. */
+#define MARKET "the market simulation"
+
+/* One tick of the market, run after every instruction, computing in the same
+ * order as VM::simulate so that a trapping program stops at the same step. */
+static inline void simulate(void) {
+    word balance = sensors[BALANCE];
+    word stockprice = sensors[STOCKPRICE];
+    word reputation = sensors[REPUTATION];
+    word shares = sensors[SHARES];
+    word owned = sensors[OWNED];
+
+    reputation = w_add(MARKET, reputation, random_shift(5));
+    stockprice = w_add(MARKET, stockprice, random_shift(5 * SCALE));
+    if (stockprice < 0) stockprice = 0;
+
+    word bias = w_mul(MARKET, w_sub(MARKET, reputation, 50), stockprice) / (10 * SCALE);
+    word square = w_mul(MARKET, bias, bias);
+    word factor = bias < 0 ? w_sub(MARKET, 0, square) : square;
+
+    word amount = w_mul(MARKET, factor, w_sub(MARKET, shares, owned));
+    word total = w_add(MARKET, amount, shares);
+    word new_shares = total < 0 ? owned : total;
+
+    word market_value = w_mul(MARKET, shares, stockprice);
+    word equity = w_mul(MARKET, owned, stockprice);
+    balance = w_add(MARKET, balance, 100 * SCALE);
+    sensors[SHARES] = new_shares;
+    sensors[STOCKPRICE] = stockprice;
+    sensors[REPUTATION] = reputation;
+    sensors[MARKETVAL] = market_value;
+    sensors[EQUITY] = equity;
+    sensors[BALANCE] = balance;
+}
+
+/* WAIT at pc, as in VM::wait_ticks: ticks market ticks instead of the one
+ * after the instruction, traced with TRACE. */
+static inline void wait(word ticks, size_t pc, const char *inst) {
+    if (ticks < 0) {
+        char msg[96];
+        snprintf(msg, sizeof msg, "[INVM] WAIT of %lld ticks, expected at least 0.", (long long)ticks);
+        fault(msg);
+    }
+    word first = sensors[STOCKPRICE], low = first, high = first;
+    for (word i = 0; i < ticks; i++) {
+        simulate();
+        if (sensors[STOCKPRICE] < low) low = sensors[STOCKPRICE];
+        if (sensors[STOCKPRICE] > high) high = sensors[STOCKPRICE];
+    }
+    if (TRACE) {
+        char f[64], l[64], lo[64], hi[64];
+        fflush(stdout);
+        fprintf(stderr, "[Trace] %s at instruction %zu: %lld ticks, STOCKPRICE %s -> %s (low %s, high %s).\n",
+            inst, pc, (long long)ticks, format_money(first, f), format_money(sensors[STOCKPRICE], l),
+            format_money(low, lo), format_money(high, hi));
+    }
+}
@@ -0,0 +1,185 @@
+use std::fmt::Write;
+
+use crate::{parser::Program, vm::{self, Condition, Instruction, Reference}};
+
+/// A maximal run of instructions that is only entered at its first instruction
+/// and only left after its last one.
+pub struct BasicBlock {
+    /// Index of the first instruction of the block.
+    pub start: usize,
+    /// Index one past the last instruction of the block.
+    pub end: usize,
+    pub successors: Vec<Edge>,
+}
+
+pub struct Edge {
+    pub target: Target,
+    pub kind: EdgeKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Block(usize),
+    Exit,
+}
+
+pub enum EdgeKind {
+    Fallthrough,
+    Jump,
+    Halt,
+    Call,
+    /// From a `RET` back to the instruction after a `CALL`.
+    Return,
+    Taken(Condition, Reference, Reference),
+    NotTaken(Condition, Reference, Reference),
+}
+
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+}
+
+impl Cfg {
+    /// Splits the program into basic blocks. A new block starts at every label
+    /// declaration and after every `GOTO`, `GOIF`, `CRASH`, `CALL` and `RET`.
+    /// Every `RET` may return after any `CALL`.
+    pub fn build(program: &[Instruction]) -> Self {
+        let labels = vm::resolve_labels(program);
+
+        let mut leaders = vec![false; program.len()];
+        for (i, inst) in program.iter().enumerate() {
+            if i == 0 || matches!(inst, Instruction::DeclareLabel(_)) {
+                leaders[i] = true;
+            }
+            if Self::ends_block(inst) && i + 1 < program.len() {
+                leaders[i + 1] = true;
+            }
+        }
+
+        let mut block_of = Vec::with_capacity(program.len());
+        let mut blocks: Vec<BasicBlock> = vec![];
+        for (i, leader) in leaders.iter().enumerate() {
+            if *leader {
+                blocks.push(BasicBlock { start: i, end: i, successors: vec![] });
+            }
+            blocks.last_mut().unwrap().end = i + 1;
+            block_of.push(blocks.len() - 1);
+        }
+
+        let label_target = |label: &str| match labels.get(label) {
+            Some(i) => Target::Block(block_of[*i]),
+            None => panic!("[CFG] Use of unknown label {label}."),
+        };
+        let next_target = |end: usize| {
+            if end < program.len() {
+                Target::Block(block_of[end])
+            } else {
+                Target::Exit
+            }
+        };
+
+        let mut returns: Vec<Target> = vec![];
+        for (pc, inst) in program.iter().enumerate() {
+            if matches!(inst, Instruction::Call(_)) && !returns.contains(&next_target(pc + 1)) {
+                returns.push(next_target(pc + 1));
+            }
+        }
+        if returns.is_empty() {
+            // RET without a caller faults.
+            returns.push(Target::Exit);
+        }
+
+        for block in blocks.iter_mut() {
+            let last = &program[block.end - 1];
+            block.successors = match last {
+                Instruction::Goto(label) => vec![
+                    Edge { target: label_target(label), kind: EdgeKind::Jump },
+                ],
+                Instruction::GoIf(cond, a, b, label) => vec![
+                    Edge { target: label_target(label), kind: EdgeKind::Taken(*cond, a.clone(), b.clone()) },
+                    Edge { target: next_target(block.end), kind: EdgeKind::NotTaken(*cond, a.clone(), b.clone()) },
+                ],
+                Instruction::Crash => vec![
+                    Edge { target: Target::Exit, kind: EdgeKind::Halt },
+                ],
+                Instruction::Call(label) => vec![
+                    Edge { target: label_target(label), kind: EdgeKind::Call },
+                ],
+                Instruction::Ret => returns.iter().map(|target| Edge { target: *target, kind: EdgeKind::Return }).collect(),
+                _ => vec![
+                    Edge { target: next_target(block.end), kind: EdgeKind::Fallthrough },
+                ],
+            };
+        }
+
+        Cfg { blocks }
+    }
+
+    fn ends_block(inst: &Instruction) -> bool {
+        matches!(inst, Instruction::Goto(_) | Instruction::GoIf(..) | Instruction::Crash | Instruction::Call(_) | Instruction::Ret)
+    }
+
+    /// Renders the graph in Graphviz DOT, with every block listing its source lines.
+    pub fn to_dot(&self, program: &Program) -> String {
+        let mut dot = String::new();
+        writeln!(dot, "digraph invm {{").unwrap();
+        writeln!(dot, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+        writeln!(dot, "    entry [shape=oval];").unwrap();
+        writeln!(dot, "    exit [shape=oval];").unwrap();
+
+        if self.blocks.is_empty() {
+            writeln!(dot, "    entry -> exit;").unwrap();
+        } else {
+            writeln!(dot, "    entry -> b0;").unwrap();
+        }
+
+        for (i, block) in self.blocks.iter().enumerate() {
+            let mut label = String::new();
+            for pc in block.start..block.end {
+                let inst = &program.instructions[pc];
+                let indent = if matches!(inst, Instruction::DeclareLabel(_)) { "" } else { "  " };
+                write!(label, "{}: {indent}{}\\l", program.lines[pc], escape(&inst.to_string())).unwrap();
+            }
+            writeln!(dot, "    b{i} [label=\"{label}\"];").unwrap();
+
+            for edge in block.successors.iter() {
+                let target = match edge.target {
+                    Target::Block(b) => format!("b{b}"),
+                    Target::Exit => "exit".to_string(),
+                };
+                let attrs = match &edge.kind {
+                    EdgeKind::Fallthrough => String::new(),
+                    EdgeKind::Jump => " [label=\"GOTO\"]".to_string(),
+                    EdgeKind::Halt => " [label=\"CRASH\"]".to_string(),
+                    EdgeKind::Call => " [label=\"CALL\"]".to_string(),
+                    EdgeKind::Return => " [label=\"RET\", style=dashed]".to_string(),
+                    EdgeKind::Taken(cond, a, b) => {
+                        format!(" [label=\"{}\", color=darkgreen]", escape(&format!("{a} {cond} {b}")))
+                    },
+                    EdgeKind::NotTaken(cond, a, b) => {
+                        format!(" [label=\"{}\", color=red]", escape(&format!("{a} {} {b}", negate(cond))))
+                    },
+                };
+                writeln!(dot, "    b{i} -> {target}{attrs};").unwrap();
+            }
+        }
+
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+}
+
+fn negate(cond: &Condition) -> Condition {
+    match cond {
+        Condition::Equals => Condition::Different,
+        Condition::Different => Condition::Equals,
+        Condition::Greater => Condition::LesserOrEqual,
+        Condition::Lesser => Condition::GreaterOrEqual,
+        Condition::GreaterOrEqual => Condition::Lesser,
+        Condition::LesserOrEqual => Condition::Greater,
+    }
+}
+
+/// Escapes a string for use inside a quoted DOT attribute.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
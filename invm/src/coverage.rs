@@ -0,0 +1,170 @@
+use std::{fmt::Write, fs, path::Path};
+
+use crate::{bytecode, parser::Program, vm::Instruction};
+
+/// Execution counts of every instruction and of both directions of every `GOIF`.
+pub struct Coverage {
+    /// Hash of the bytecode of the program, so that counts of another program
+    /// are never added.
+    pub program: u64,
+    pub hits: Vec<u64>,
+    pub taken: Vec<u64>,
+    pub not_taken: Vec<u64>,
+}
+
+impl Coverage {
+    pub fn new(program: &Program) -> Self {
+        let size = program.instructions.len();
+        Coverage {
+            program: hash(&bytecode::assemble(program, true)),
+            hits: vec![0; size],
+            taken: vec![0; size],
+            not_taken: vec![0; size],
+        }
+    }
+
+    pub fn hit(&mut self, pc: usize) {
+        self.hits[pc] += 1;
+    }
+
+    pub fn branch(&mut self, pc: usize, taken: bool) {
+        if taken {
+            self.taken[pc] += 1;
+        } else {
+            self.not_taken[pc] += 1;
+        }
+    }
+
+    /// Adds the counts of another run of the same program.
+    pub fn merge(&mut self, other: &Coverage) {
+        if self.program != other.program || self.hits.len() != other.hits.len() {
+            panic!("[Coverage] Coverage data was recorded for a different program.");
+        }
+        for pc in 0..self.hits.len() {
+            self.hits[pc] += other.hits[pc];
+            self.taken[pc] += other.taken[pc];
+            self.not_taken[pc] += other.not_taken[pc];
+        }
+    }
+
+    /// Reads coverage data saved by `save`: the hash of the program, then one
+    /// `pc hits taken not_taken` line per instruction.
+    pub fn load(path: &Path) -> Self {
+        let data = fs::read_to_string(path).expect("[Coverage] Failed to read coverage data.");
+        let mut lines = data.lines();
+        let program = lines.next()
+            .and_then(|header| header.strip_prefix("invm-coverage 2 "))
+            .and_then(|hash| u64::from_str_radix(hash, 16).ok())
+            .unwrap_or_else(|| panic!("[Coverage] {} is not a coverage data file.", path.display()));
+
+        let mut coverage = Coverage { program, hits: vec![], taken: vec![], not_taken: vec![] };
+        for line in lines {
+            let fields: Vec<u64> = line
+                .split_whitespace()
+                .map(|f| f.parse().expect("[Coverage] Malformed coverage data."))
+                .collect();
+            let [_, hits, taken, not_taken] = fields[..] else {
+                panic!("[Coverage] Malformed coverage data.")
+            };
+            coverage.hits.push(hits);
+            coverage.taken.push(taken);
+            coverage.not_taken.push(not_taken);
+        }
+        coverage
+    }
+
+    pub fn save(&self, path: &Path) {
+        let mut data = format!("invm-coverage 2 {:016x}\n", self.program);
+        for pc in 0..self.hits.len() {
+            writeln!(data, "{pc} {} {} {}", self.hits[pc], self.taken[pc], self.not_taken[pc]).unwrap();
+        }
+        fs::write(path, data).expect("[Coverage] Failed to write coverage data.");
+    }
+
+    /// Renders the coverage in the lcov tracefile format. Label declarations are
+    /// not counted as lines, since jumps skip over them.
+    pub fn to_lcov(&self, program: &Program, source: &str) -> String {
+        let mut lcov = String::new();
+        writeln!(lcov, "TN:").unwrap();
+        writeln!(lcov, "SF:{source}").unwrap();
+
+        let (mut branches, mut branches_hit) = (0, 0);
+        for (pc, inst) in program.instructions.iter().enumerate() {
+            if !matches!(inst, Instruction::GoIf(..)) {
+                continue;
+            }
+            let line = program.lines[pc];
+            for (i, count) in [self.taken[pc], self.not_taken[pc]].iter().enumerate() {
+                let count = if self.hits[pc] == 0 { "-".to_string() } else { count.to_string() };
+                writeln!(lcov, "BRDA:{line},{pc},{i},{count}").unwrap();
+            }
+            branches += 2;
+            branches_hit += (self.taken[pc] > 0) as usize + (self.not_taken[pc] > 0) as usize;
+        }
+        writeln!(lcov, "BRF:{branches}").unwrap();
+        writeln!(lcov, "BRH:{branches_hit}").unwrap();
+
+        let (mut lines, mut lines_hit) = (0, 0);
+        for (pc, inst) in program.instructions.iter().enumerate() {
+            if matches!(inst, Instruction::DeclareLabel(_)) {
+                continue;
+            }
+            writeln!(lcov, "DA:{},{}", program.lines[pc], self.hits[pc]).unwrap();
+            lines += 1;
+            lines_hit += (self.hits[pc] > 0) as usize;
+        }
+        writeln!(lcov, "LF:{lines}").unwrap();
+        writeln!(lcov, "LH:{lines_hit}").unwrap();
+        writeln!(lcov, "end_of_record").unwrap();
+        lcov
+    }
+
+    /// Renders the source with the execution count of every instruction in the
+    /// margin. Instructions that never ran are marked with `#####`.
+    pub fn annotate(&self, program: &Program, source: &str) -> String {
+        let mut listing = String::new();
+        let mut pc = 0;
+        for (i, text) in source.lines().enumerate() {
+            let line = i + 1;
+            let mut count = "-".to_string();
+            let mut branch = String::new();
+            while pc < program.lines.len() && program.lines[pc] == line {
+                match &program.instructions[pc] {
+                    Instruction::DeclareLabel(_) => (),
+                    inst => {
+                        count = match self.hits[pc] {
+                            0 => "#####".to_string(),
+                            n => n.to_string(),
+                        };
+                        if matches!(inst, Instruction::GoIf(..)) {
+                            branch = format!("  [taken {}, not taken {}]", self.taken[pc], self.not_taken[pc]);
+                        }
+                    }
+                }
+                pc += 1;
+            }
+            writeln!(listing, "{count:>9} | {line:>5} | {text}{branch}").unwrap();
+        }
+
+        let executable: Vec<usize> = (0..program.instructions.len())
+            .filter(|pc| !matches!(program.instructions[*pc], Instruction::DeclareLabel(_)))
+            .collect();
+        let covered = executable.iter().filter(|pc| self.hits[**pc] > 0).count();
+        let branches: Vec<usize> = (0..program.instructions.len())
+            .filter(|pc| matches!(program.instructions[*pc], Instruction::GoIf(..)))
+            .collect();
+        let branches_covered: usize = branches
+            .iter()
+            .map(|pc| (self.taken[*pc] > 0) as usize + (self.not_taken[*pc] > 0) as usize)
+            .sum();
+        writeln!(listing, "[Coverage] Instructions: {covered}/{}.", executable.len()).unwrap();
+        writeln!(listing, "[Coverage] Branches: {branches_covered}/{}.", branches.len() * 2).unwrap();
+        listing
+    }
+}
+
+/// 64-bit FNV-1a, which unlike the hasher of the standard library stays the
+/// same across builds.
+fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3))
+}
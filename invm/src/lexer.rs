@@ -1,45 +1,160 @@
-use std::{iter::Peekable, panic, str::Chars};
+use std::{iter::Peekable, num::IntErrorKind, panic, str::Chars};
 
-use crate::vm::{Register, Sensor, Type};
+use crate::vm::{arith::Width, money, FileMode, Register, Sensor, Stream, Type, Word};
 
 #[derive(Debug)]
 pub enum Token {
-    Value(i32),
+    Value(Word),
     Reg(Register),
     Sens(Sensor),
-    Set, Add, Sub, Goto, GoIf, Print, Push, Pop, Buy, Sell, Crash, Mult, Div, Read,
+    Set, Add, Sub, Goto, GoIf, Print, Push, Pop, Buy, Sell, Crash, Mult, Div, Read, Nop, Call, Ret,
+    Mod, And, Or, Xor, Not, Shl, Shr, Neg, Abs, Min, Max, Alloc, Free, Enter, Leave, Put, Printf,
+    Open, ReadLine, Write, Close, MMult, MDiv, Wait,
     LabelDeclare(String),
     Label(String),
+    /// A name that is not a keyword, such as the name of data.
+    Name(String),
+    /// A string literal, without its quotes and with escapes replaced.
+    Str(String),
+    DataString, DataWord, DataZero,
+    /// `.arith` and `.width`, which set how the program does arithmetic.
+    Arith, Width,
     Reference,
+    /// Signs, parentheses and brackets of `*R+n`, `*R-n`, `*(R+R)` and `*R[i]`.
+    Plus, Minus, LParen, RParen, LBracket, RBracket,
     Endline,
     Type(Type),
+    /// `err` after a print instruction.
+    Stream(Stream),
+    /// `read`, `write` or `append` in `OPEN`.
+    Mode(FileMode),
     Equals, Different, Greater, Lesser, GreaterOrEqual, LesserOrEqual
 }
 
 pub struct Lexer<'a> {
     source: Peekable<Chars<'a>>,
+    /// Current line in the source, starting at 1.
+    pub line: usize,
+    /// Whether the last token was a register, number or name, or closed an
+    /// address. A `-` right after one is a sign, as in `*FUND1-4`, instead of
+    /// the start of a negative number.
+    operand_end: bool,
+    /// Size of the words numbers are read into.
+    width: Width,
 }
 
 impl<'a> Lexer<'a> {
-    pub fn new(query: &'a str) -> Self {
-        Lexer { source: query.chars().peekable() } 
+    pub fn new(query: &'a str, width: Width) -> Self {
+        Lexer { source: query.chars().peekable(), line: 1, operand_end: false, width }
     }
 
     /// Groups the numbers in a query together and returns the Token::Value containing that value.
+    /// Numbers may be negative, start with `0x` or `0b`, and use `_` between digits.
+    /// Hexadecimal and binary numbers as wide as a word are read as its bits.
+    /// Amounts of money like `12,34` are read as cents.
     fn parse_number(&mut self, c: char) -> Token {
         let mut number = String::from(c);
+        // Peeks so that the character after the number is not consumed.
+        while let Some(next) = self.source.peek() && (next.is_ascii_alphanumeric() || matches!(next, '_' | ',')) {
+            number.push(*next);
+            self.source.next();
+        }
+        if number.contains(',') {
+            return match money::parse(&number) {
+                Some(n) if self.width.contains(n) => Token::Value(n as Word),
+                Some(_) => self.overflow(&number),
+                None => panic!("[Lexer] Invalid amount {number} on line {}.", self.line),
+            };
+        }
+
+        let (negative, unsigned) = match number.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, number.as_str()),
+        };
+        let (radix, digits) = match unsigned.get(..2) {
+            Some("0x" | "0X") => (16, &unsigned[2..]),
+            Some("0b" | "0B") => (2, &unsigned[2..]),
+            _ => (10, unsigned),
+        };
+        if digits.is_empty() || digits.starts_with('_') || digits.ends_with('_') || digits.contains("__") {
+            panic!("[Lexer] Invalid number {number} on line {}.", self.line);
+        }
+        let magnitude = match u64::from_str_radix(&digits.replace('_', ""), radix) {
+            Ok(n) => n,
+            Err(e) if matches!(e.kind(), IntErrorKind::PosOverflow) => self.overflow(&number),
+            Err(_) => panic!("[Lexer] Invalid number {number} on line {}.", self.line),
+        };
+
+        let value = match (negative, radix, self.width) {
+            (true, ..) => Some(-(magnitude as i128)).filter(|n| self.width.contains(*n)).map(|n| n as Word),
+            (false, 10, _) => Some(magnitude as i128).filter(|n| self.width.contains(*n)).map(|n| n as Word),
+            (false, _, Width::W32) => u32::try_from(magnitude).ok().map(|n| (n as i32).into()),
+            (false, _, Width::W64) => Some(magnitude as i64),
+        };
+        match value {
+            Some(n) => Token::Value(n),
+            None => self.overflow(&number),
+        }
+    }
+
+    fn overflow(&self, number: &str) -> ! {
+        panic!("[Lexer] Number {number} on line {} does not fit in {} bits.", self.line, self.width.bits())
+    }
+
+    /// Reads the character after a `\` in a character or string literal.
+    fn parse_escape(&mut self, literal: &str) -> char {
+        match self.source.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some(c @ ('\\' | '\'' | '"')) => c,
+            Some(c) => panic!("[Lexer] Unknown escape \\{c} in {literal} literal on line {}.", self.line),
+            None => panic!("[Lexer] Unterminated {literal} literal on line {}.", self.line),
+        }
+    }
+
+    /// Reads a character literal after its opening quote, returning the code of the character.
+    fn parse_char(&mut self) -> Token {
+        let c = match self.source.next() {
+            Some('\\') => self.parse_escape("character"),
+            Some('\'' | '\n') | None => panic!("[Lexer] Empty character literal on line {}.", self.line),
+            Some(c) => c,
+        };
+        if self.source.next() != Some('\'') {
+            panic!("[Lexer] Expected ' to close the character literal on line {}.", self.line);
+        }
+        Token::Value(c as Word)
+    }
 
+    /// Reads a string literal after its opening quote.
+    fn parse_string(&mut self) -> Token {
+        let mut s = String::new();
         loop {
-            // Peeks here so that the character is not consumed.
-            let next = self.source.peek();
-            if next.is_none() || !next.unwrap().is_numeric() {
-                let n = number.parse::<i32>().unwrap();
-                return Token::Value(n);
-            } 
-            // Consumes here, in case it is a number.
-            number.push(self.source.next().unwrap());
+            match self.source.next() {
+                Some('"') => return Token::Str(s),
+                Some('\\') => s.push(self.parse_escape("string")),
+                Some('\n') | None => panic!("[Lexer] Unterminated string literal on line {}.", self.line),
+                Some(c) => s.push(c),
+            }
         }
+    }
 
+    /// Reads a directive after its dot.
+    fn parse_directive(&mut self) -> Token {
+        let mut iden = String::new();
+        while let Some(c) = self.source.peek() && c.is_ascii_alphabetic() {
+            iden.push(*c);
+            self.source.next();
+        }
+        match iden.as_str() {
+            "string" => Token::DataString,
+            "word" => Token::DataWord,
+            "zero" => Token::DataZero,
+            "arith" => Token::Arith,
+            "width" => Token::Width,
+            _ => panic!("[Lexer] Unknown directive .{iden} on line {}.", self.line),
+        }
     }
 
     fn get_keyword(s: String) -> Token {
@@ -49,17 +164,45 @@ impl<'a> Lexer<'a> {
             "SUB" => Token::Sub,
             "MULT" => Token::Mult,
             "DIV" => Token::Div,
+            "MOD" => Token::Mod,
+            "AND" => Token::And,
+            "OR" => Token::Or,
+            "XOR" => Token::Xor,
+            "NOT" => Token::Not,
+            "SHL" => Token::Shl,
+            "SHR" => Token::Shr,
+            "NEG" => Token::Neg,
+            "ABS" => Token::Abs,
+            "MIN" => Token::Min,
+            "MAX" => Token::Max,
+            "MMULT" => Token::MMult,
+            "MDIV" => Token::MDiv,
             "GOTO" => Token::Goto,
             "GOIF" => Token::GoIf,
             "PRINT" => Token::Print,
+            "PUT" => Token::Put,
+            "PRINTF" => Token::Printf,
             "PUSH" => Token::Push,
             "POP" => Token::Pop,
             "READ" => Token::Read,
             "CRASH" => Token::Crash,
+            "NOP" => Token::Nop,
+            "WAIT" => Token::Wait,
+            "CALL" => Token::Call,
+            "RET" => Token::Ret,
+            "ALLOC" => Token::Alloc,
+            "FREE" => Token::Free,
+            "ENTER" => Token::Enter,
+            "LEAVE" => Token::Leave,
+            "OPEN" => Token::Open,
+            "READLINE" => Token::ReadLine,
+            "WRITE" => Token::Write,
+            "CLOSE" => Token::Close,
             "BUY" => Token::Buy,
             "SELL" => Token::Sell,
             "FUND1" => Token::Reg(Register::Fund1),
             "FUND2" => Token::Reg(Register::Fund2),
+            "FP" => Token::Reg(Register::Fp),
             "SHARES" => Token::Sens(Sensor::Shares),
             "STOCKPRICE" => Token::Sens(Sensor::Stockprice),
             "REPUTATION" => Token::Sens(Sensor::Reputation),
@@ -71,10 +214,40 @@ impl<'a> Lexer<'a> {
             "int" => Token::Type(Type::Int),
             "bool" => Token::Type(Type::Bool),
             "str" => Token::Type(Type::Str),
-            _ => panic!("[Lexer] Unknown instruction, type or register: {s}.")
+            "money" => Token::Type(Type::Money),
+            "err" => Token::Stream(Stream::Err),
+            "read" => Token::Mode(FileMode::Read),
+            "write" => Token::Mode(FileMode::Write),
+            "append" => Token::Mode(FileMode::Append),
+            _ => match Lexer::general_register(&s) {
+                Some(r) => Token::Reg(r),
+                None => Token::Name(s),
+            }
         }
     }
 
+    /// Whether `s` is read back as a label: a letter followed by letters, digits and `_`.
+    pub fn is_label(s: &str) -> bool {
+        let mut chars = s.chars();
+        chars.next().is_some_and(|c| c.is_ascii_alphabetic()) && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    }
+
+    /// Whether `s` is read back as the name of data: a label that is not a
+    /// keyword, register or sensor.
+    pub fn is_name(s: &str) -> bool {
+        Lexer::is_label(s) && matches!(Lexer::get_keyword(s.to_string()), Token::Name(_))
+    }
+
+    /// Parses a general-purpose register name, `R0` up to `R29`, without leading zeros.
+    fn general_register(s: &str) -> Option<Register> {
+        let digits = s.strip_prefix('R')?;
+        let n: u8 = digits.parse().ok()?;
+        if n.to_string() != digits || n as usize >= Register::MAX_GENERAL {
+            return None;
+        }
+        Some(Register::General(n))
+    }
+
     fn parse_keyword(&mut self, c: char) -> Token {
         let mut iden = String::from(c);
         loop {
@@ -111,20 +284,28 @@ impl<'a> Lexer<'a> {
         }
     }
 
-}
-
-impl<'a> Iterator for Lexer<'a> {
-    type Item = Token;
-
-    fn next(&mut self) -> Option<Self::Item> {
+    /// Reads the next token, skipping spaces.
+    fn token(&mut self) -> Option<Token> {
         let mut char = self.source.next()?;
 
         loop {
             match char {
-                ' ' | '\t' => char = self.source.next()?,
-                '\n' => return Some(Token::Endline),
+                ' ' | '\t' => {
+                    self.operand_end = false;
+                    char = self.source.next()?
+                },
+                '\n' => {
+                    self.line += 1;
+                    return Some(Token::Endline);
+                },
                 '$' => return Some(self.parse_label()),
                 '*' => return Some(Token::Reference),
+                '+' => return Some(Token::Plus),
+                '-' if self.operand_end => return Some(Token::Minus),
+                '(' => return Some(Token::LParen),
+                ')' => return Some(Token::RParen),
+                '[' => return Some(Token::LBracket),
+                ']' => return Some(Token::RBracket),
                 ':' => panic!("[Lexer] Empty label identifier."),
                 '<' => match self.source.next() {
                     Some('=') => return Some(Token::LesserOrEqual),
@@ -147,9 +328,27 @@ impl<'a> Iterator for Lexer<'a> {
                     None => panic!("[Lexer] Unknown condition !. Maybe you meant !=?")
                 },
                 '0'..='9' => return Some(self.parse_number(char)),
+                '-' if self.source.peek().is_some_and(char::is_ascii_digit) => return Some(self.parse_number(char)),
+                '\'' => return Some(self.parse_char()),
+                '"' => return Some(self.parse_string()),
+                '.' => return Some(self.parse_directive()),
                 'a'..='z' | 'A'..='Z' => return Some(self.parse_keyword(char)),
                 _ => panic!("[Lexer] Invalid symbol: {char}.")
             }
         }
     }
+
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let token = self.token();
+        self.operand_end = matches!(
+            token,
+            Some(Token::Reg(_) | Token::Sens(_) | Token::Value(_) | Token::Name(_) | Token::RParen | Token::RBracket)
+        );
+        token
+    }
 }
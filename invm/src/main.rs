@@ -1,27 +1,117 @@
-use std::fs;
+use std::{fs, panic, path::PathBuf, process};
 
-use crate::{args::ArgError};
+use crate::{args::{ArgError, Command}, cfg::Cfg, coverage::Coverage, memory::Layout, parser::Program, stackdepth::Analysis, vm::Config};
 
-mod vm; 
-mod stack;
+mod vm;
+mod memory;
 mod lexer;
 mod prepro;
 mod args;
 mod parser;
+mod cfg;
+mod stackdepth;
+mod coverage;
+mod cbackend;
+mod bytecode;
+mod optimize;
 
 fn main() {
-    let filepath = match args::parse_args() {
+    let args = match args::parse_args() {
         Err(err) => match err {
             ArgError::IncorrectSize => panic!("[Run] Incorrect number of arguments."),
-            ArgError::InvalidExtension => panic!("[Run] Expected a .invm file."),
+            ArgError::InvalidExtension(e) => panic!("[Run] Expected a {e} file."),
+            ArgError::UnknownCommand(c) => panic!("[Run] Unknown command {c}. Expected run, cfg, stack, coverage, c, bench, jit, jit-diff, assemble or disasm."),
+            ArgError::UnknownOption(o) => panic!("[Run] Unknown option {o}."),
+            ArgError::InvalidValue(o) => panic!("[Run] Missing or invalid value for {o}."),
         },
-        Ok(f) => f
+        Ok(a) => a
     };
 
-    let query = fs::read_to_string(filepath)
-        .expect("[Run] File not found");
+    let config = Config {
+        seed: args.seed,
+        memory: args.memory,
+        call_limit: args.call_limit,
+        registers: args.registers,
+        heap_debug: args.heap_debug,
+        files: args.files,
+        trace: args.trace,
+    };
+
+    let mut program = load(&args.filepath, &config.memory);
+    if args.optimize {
+        program = optimize::optimize(&program, args.keep_ticks);
+    }
+
+    match args.command {
+        Command::Run => vm::run(&program, &config),
+        Command::Cfg => {
+            let cfg = Cfg::build(&program.instructions);
+            print!("{}", cfg.to_dot(&program));
+        }
+        Command::Stack => {
+            let analysis = Analysis::run(&program, config.memory.stack_limit());
+            analysis.report(&program);
+            if analysis.has_errors() {
+                process::exit(1);
+            }
+        }
+        Command::Coverage => {
+            let (mut coverage, fault) = vm::run_with_coverage(&program, &config);
 
-    let filtered = prepro::filter(query);
+            let data = PathBuf::from(format!("{}.cov", args.filepath));
+            if data.exists() {
+                coverage.merge(&Coverage::load(&data));
+            }
+            coverage.save(&data);
+
+            let lcov = PathBuf::from(format!("{}.lcov", args.filepath));
+            fs::write(&lcov, coverage.to_lcov(&program, &args.filepath))
+                .expect("[Coverage] Failed to write lcov file.");
+
+            let query = fs::read_to_string(&args.filepath).expect("[Run] File not found");
+            eprint!("{}", coverage.annotate(&program, &query));
+            if let Some(fault) = fault {
+                panic::resume_unwind(fault);
+            }
+        }
+        Command::C => {
+            print!("{}", cbackend::translate(&program, &args.filepath, &config));
+        }
+        Command::Bench => {
+            let samples = vm::bench(&program, &config, args.runs);
+            let total: f64 = samples.iter().map(|(time, _)| time.as_secs_f64()).sum();
+            let steps: u64 = samples.iter().map(|(_, steps)| steps).sum();
+            let best = samples.iter().map(|(time, _)| time.as_secs_f64()).fold(f64::INFINITY, f64::min);
+            eprintln!("[Bench] {} runs, {} instructions per run.", samples.len(), steps / samples.len() as u64);
+            eprintln!("[Bench] Mean {:.4} s, best {best:.4} s per run.", total / samples.len() as f64);
+            eprintln!("[Bench] {:.2} M instructions/s.", steps as f64 / total / 1e6);
+        }
+        #[cfg(feature = "jit")]
+        Command::Jit => vm::jit::run(&program, &config),
+        #[cfg(feature = "jit")]
+        Command::JitDiff => vm::jit::diff(&args.filepath, &config, args.optimize, args.keep_ticks),
+        #[cfg(not(feature = "jit"))]
+        Command::Jit | Command::JitDiff => panic!("[Run] invm was built without the jit feature."),
+        Command::Assemble => {
+            let path = format!("{}b", args.filepath);
+            let bytes = bytecode::assemble(&program, !args.strip);
+            fs::write(&path, &bytes).expect("[Assemble] Failed to write bytecode file.");
+            eprintln!("[Assemble] Wrote {path}: {} instructions, {} bytes.", program.instructions.len(), bytes.len());
+        }
+        Command::Disasm => print!("{}", bytecode::disassemble(&program)),
+    }
+}
 
-    vm::run(&filtered);
+/// Reads the program from source or, for `.invmb` files, from bytecode.
+fn load(filepath: &str, layout: &Layout) -> Program {
+    if filepath.ends_with(".invmb") {
+        let bytes = fs::read(filepath).expect("[Run] File not found");
+        match bytecode::load(&bytes, layout) {
+            Ok(program) => program,
+            Err(err) => panic!("[Bytecode] {err}"),
+        }
+    } else {
+        let query = fs::read_to_string(filepath).expect("[Run] File not found");
+        parser::read_program(&prepro::filter(query))
+    }
 }
@@ -0,0 +1,219 @@
+//! The memory of the VM: one array of words split in three regions.
+//!
+//! ```text
+//! 0            data          heap top             sp            size
+//! | static data | heap, grows -> |      unmapped      | <- stack |
+//! ```
+//!
+//! `*R` operands use absolute addresses over the whole array. Only the data,
+//! the heap up to its top and the live part of the stack are mapped; any other
+//! address faults, and the heap and the stack fault when they would grow into
+//! each other.
+//!
+//! The heap is split in blocks by `alloc`. Freed blocks are reused first fit,
+//! and the heap top comes back down when the last block is freed. In debug
+//! mode freed blocks are never reused, so that using or freeing them again
+//! faults instead.
+
+use std::collections::BTreeMap;
+
+use crate::vm::Word;
+
+/// Sizes of the memory regions, in words.
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    /// Total words of memory.
+    pub size: usize,
+    /// Words of static data at the start of memory.
+    pub data: usize,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout { size: 65536, data: 1024 }
+    }
+}
+
+impl Layout {
+    /// The most values the stack can hold, with an empty heap.
+    pub fn stack_limit(&self) -> usize {
+        self.size - self.data
+    }
+
+    /// Faults if `words` of initialised data do not fit in the static data.
+    pub fn check_data(&self, words: usize) {
+        if words > self.data {
+            panic!("[INVM] The data section needs {words} words, but there are only {} words of static data.", self.data);
+        }
+    }
+}
+
+pub struct Memory {
+    mem: Vec<Word>,
+    /// First free word after the heap.
+    heap_top: usize,
+    /// Top of the stack. The stack is empty when it is the size of the memory.
+    pub sp: usize,
+    /// Every allocated block by address.
+    blocks: BTreeMap<usize, Block>,
+    /// Size of every free range below the heap top by address. Free ranges are
+    /// never next to each other.
+    free: BTreeMap<usize, usize>,
+    /// Size of every freed block by address, kept in debug mode only.
+    freed: Option<BTreeMap<usize, usize>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Block {
+    pub words: usize,
+    /// Index of the instruction that allocated the block.
+    pub site: usize,
+}
+
+impl Memory {
+    /// Creates the memory with `data` at the start of the static data.
+    pub fn new(layout: &Layout, data: &[Word], debug: bool) -> Self {
+        layout.check_data(data.len());
+        let mut mem = vec![0; layout.size];
+        mem[..data.len()].copy_from_slice(data);
+        Memory {
+            mem,
+            heap_top: layout.data,
+            sp: layout.size,
+            blocks: BTreeMap::new(),
+            free: BTreeMap::new(),
+            freed: debug.then(BTreeMap::new),
+        }
+    }
+
+    /// Number of words in the memory. The stack is empty when `sp` is this.
+    pub fn size(&self) -> usize {
+        self.mem.len()
+    }
+
+    pub fn push(&mut self, val: Word) {
+        if self.sp == self.heap_top {
+            panic!("[INVM] Stack overflow.")
+        }
+        self.sp -= 1;
+        self.mem[self.sp] = val;
+    }
+
+    pub fn pop(&mut self) -> Word {
+        if self.sp == self.mem.len() {
+            panic!("[INVM] Pop used on an empty stack.")
+        }
+        self.sp += 1;
+        self.mem[self.sp - 1]
+    }
+
+    fn check(&self, addr: usize) {
+        if addr >= self.heap_top && !(self.sp..self.mem.len()).contains(&addr) {
+            panic!("[INVM] Segmentation fault at address {addr}.")
+        }
+        if let Some(freed) = &self.freed
+            && let Some((start, words)) = freed.range(..=addr).next_back()
+            && addr < start + words
+        {
+            panic!("[INVM] Use after free at address {addr}, in the block of {words} words at {start}.")
+        }
+    }
+
+    pub fn get(&self, addr: usize) -> Word {
+        self.check(addr);
+        self.mem[addr]
+    }
+
+    pub fn set(&mut self, addr: usize, data: Word) {
+        self.check(addr);
+        self.mem[addr] = data;
+    }
+
+    /// Allocates a block of `words` zeroed words for the instruction at `site`
+    /// and returns its address. The lowest free range that fits is used, or
+    /// else the heap grows.
+    pub fn alloc(&mut self, words: usize, site: usize) -> usize {
+        let fit = self.free.iter().find(|(_, size)| **size >= words).map(|(addr, size)| (*addr, *size));
+        let addr = match fit {
+            Some((addr, size)) => {
+                self.free.remove(&addr);
+                if size > words {
+                    self.free.insert(addr + words, size - words);
+                }
+                addr
+            },
+            None => {
+                if words > self.sp - self.heap_top {
+                    panic!("[INVM] Heap overflow: {words} words do not fit between the heap and the stack.")
+                }
+                self.heap_top += words;
+                self.heap_top - words
+            },
+        };
+        self.mem[addr..addr + words].fill(0);
+        self.blocks.insert(addr, Block { words, site });
+        addr
+    }
+
+    /// Frees the block allocated at `addr`, merging it with the free ranges
+    /// around it.
+    pub fn free(&mut self, addr: usize) {
+        let Some(block) = self.blocks.remove(&addr) else {
+            if self.freed.as_ref().is_some_and(|freed| freed.contains_key(&addr)) {
+                panic!("[INVM] Double free of address {addr}.")
+            }
+            panic!("[INVM] FREE of address {addr}, which is not the start of an allocated block.")
+        };
+        if let Some(freed) = &mut self.freed {
+            freed.insert(addr, block.words);
+            return;
+        }
+
+        let (mut start, mut words) = (addr, block.words);
+        if let Some((prev, size)) = self.free.range(..addr).next_back().map(|(a, s)| (*a, *s))
+            && prev + size == addr
+        {
+            self.free.remove(&prev);
+            start = prev;
+            words += size;
+        }
+        if let Some(size) = self.free.remove(&(addr + block.words)) {
+            words += size;
+        }
+        if start + words == self.heap_top {
+            self.heap_top = start;
+        } else {
+            self.free.insert(start, words);
+        }
+    }
+
+    /// The blocks still allocated, by address.
+    pub fn blocks(&self) -> &BTreeMap<usize, Block> {
+        &self.blocks
+    }
+
+    /// Stores a string on the heap as its length followed by its characters.
+    pub fn alloc_str(&mut self, data: &str, site: usize) -> usize {
+        let chars: Vec<char> = data.chars().collect();
+        let addr = self.alloc(chars.len() + 1, site);
+        self.mem[addr] = chars.len() as Word;
+        for (i, c) in chars.into_iter().enumerate() {
+            self.mem[addr + 1 + i] = c as Word;
+        }
+        addr
+    }
+
+    pub fn get_str(&self, addr: usize) -> String {
+        let size = self.get(addr);
+
+        let mut str = String::new();
+
+        for i in 0..size.max(0) as usize {
+            let c = self.get(addr + 1 + i);
+            let c = u32::try_from(c).ok().and_then(char::from_u32).expect("[INVM] Invalid conversion of character.");
+            str.push(c);
+        }
+
+        str
+    }
+}
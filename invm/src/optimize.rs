@@ -0,0 +1,563 @@
+//! Optimisations over parsed programs, enabled with `-O`.
+//!
+//! Every executed instruction lets the market tick, so removing instructions
+//! changes the prices a program sees. With `keep_ticks`, instructions that may
+//! run are replaced by `NOP` instead of removed and jumps are never shortened,
+//! so every run executes the same number of instructions as the original one.
+
+use std::collections::HashMap;
+
+use crate::{parser::Program, vm::{self, arith::Arith, money, GeneralRegister, Instruction, Reference, Register, Word}};
+
+/// Rounds of all passes before giving up on reaching a fixed point.
+const MAX_ROUNDS: usize = 32;
+
+/// What is known about a register before an instruction runs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Fact {
+    /// Never written on any path.
+    Undef,
+    Const(Word),
+    /// Written on every path, with an unknown value.
+    Init,
+    /// Written on some paths only.
+    Any,
+}
+
+impl Fact {
+    fn join(self, other: Fact) -> Fact {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (Fact::Const(_) | Fact::Init, Fact::Const(_) | Fact::Init) => Fact::Init,
+            _ => Fact::Any,
+        }
+    }
+
+    fn is_init(self) -> bool {
+        matches!(self, Fact::Const(_) | Fact::Init)
+    }
+}
+
+type State = [Fact; Register::COUNT];
+
+pub fn optimize(program: &Program, keep_ticks: bool) -> Program {
+    let mut opt = Optimizer {
+        code: program.instructions.clone(),
+        lines: program.lines.clone(),
+        keep_ticks,
+        arith: program.arith,
+        deleted: vec![],
+    };
+    for _ in 0..MAX_ROUNDS {
+        let mut changed = opt.propagate();
+        changed |= opt.eliminate_dead_stores();
+        if !keep_ticks {
+            changed |= opt.thread_jumps();
+        }
+        changed |= opt.remove_unreachable();
+        if !keep_ticks {
+            changed |= opt.remove_unused_labels();
+        }
+        if !changed {
+            break;
+        }
+    }
+    Program { instructions: opt.code, lines: opt.lines, data: program.data.clone(), arith: program.arith }
+}
+
+struct Optimizer {
+    code: Vec<Instruction>,
+    lines: Vec<usize>,
+    keep_ticks: bool,
+    /// How the program does arithmetic, which decides what folds.
+    arith: Arith,
+    /// Instructions to drop at the end of the current pass.
+    deleted: Vec<usize>,
+}
+
+impl Optimizer {
+    /// Drops an instruction that may run, keeping its tick if asked to.
+    fn remove(&mut self, pc: usize) {
+        if self.keep_ticks {
+            self.code[pc] = Instruction::Nop;
+        } else {
+            self.deleted.push(pc);
+        }
+    }
+
+    /// Drops the instructions marked by the pass. Returns whether any was.
+    fn sweep(&mut self) -> bool {
+        if self.deleted.is_empty() {
+            return false;
+        }
+        self.deleted.sort();
+        self.deleted.dedup();
+        for pc in self.deleted.drain(..).rev() {
+            self.code.remove(pc);
+            self.lines.remove(pc);
+        }
+        true
+    }
+
+    /// Instructions that may run right after `pc`. Jumps resume after the
+    /// declaration of their label, jumps to unknown labels fault, and `RET`
+    /// may return after any `CALL`.
+    fn successors(&self, labels: &HashMap<String, usize>, pc: usize) -> Vec<usize> {
+        let next = pc + 1;
+        let mut succ = match &self.code[pc] {
+            Instruction::Goto(label) | Instruction::Call(label) => {
+                return labels.get(label).map(|l| l + 1).into_iter().collect()
+            },
+            Instruction::Ret => {
+                return (0..self.code.len())
+                    .filter(|call| matches!(self.code[*call], Instruction::Call(_)) && call + 1 < self.code.len())
+                    .map(|call| call + 1)
+                    .collect()
+            },
+            Instruction::GoIf(_, _, _, label) => labels.get(label).map(|l| l + 1).into_iter().collect(),
+            Instruction::Crash => return vec![],
+            _ => vec![],
+        };
+        succ.push(next);
+        succ.retain(|s| *s < self.code.len());
+        succ
+    }
+
+    /// Forward dataflow of the register values, `None` for unreachable instructions.
+    fn facts(&self, labels: &HashMap<String, usize>) -> Vec<Option<State>> {
+        let mut facts: Vec<Option<State>> = vec![None; self.code.len()];
+        if self.code.is_empty() {
+            return facts;
+        }
+        let mut start = [Fact::Undef; Register::COUNT];
+        start[Register::Fp.index()] = Fact::Init;
+        facts[0] = Some(start);
+        let mut work = vec![0];
+        while let Some(pc) = work.pop() {
+            let out = transfer(self.arith, &self.code[pc], facts[pc].unwrap());
+            for next in self.successors(labels, pc) {
+                let joined = match facts[next] {
+                    None => out,
+                    Some(old) => std::array::from_fn(|r| old[r].join(out[r])),
+                };
+                if facts[next] != Some(joined) {
+                    facts[next] = Some(joined);
+                    work.push(next);
+                }
+            }
+        }
+        facts
+    }
+
+    /// Constant propagation and folding: reads of registers with a known value
+    /// become literals, arithmetic on known values becomes a `SET`, branches on
+    /// known values become a `GOTO` or go away, and instructions that leave
+    /// every value as it was are removed.
+    fn propagate(&mut self) -> bool {
+        let labels = vm::resolve_labels(&self.code);
+        let facts = self.facts(&labels);
+        let mut changed = false;
+        let arith = self.arith;
+
+        for (pc, state) in facts.iter().enumerate() {
+            let Some(state) = *state else { continue };
+            let new = match &self.code[pc] {
+                Instruction::Set(r, v) => {
+                    let v = constant(arith, v, &state);
+                    match (r, &v) {
+                        (Reference::Register(r), Reference::Value(n)) if state[r.index()] == Fact::Const(*n) => None,
+                        (Reference::Register(r), Reference::Register(s)) if r == s && state[r.index()].is_init() => None,
+                        _ if self.copies_back(pc) => None,
+                        _ => Some(Instruction::Set(address(arith, r, &state), v)),
+                    }
+                },
+                Instruction::Not(v, r) | Instruction::Neg(v, r) | Instruction::Abs(v, r) => {
+                    let v = constant(arith, v, &state);
+                    match &v {
+                                Reference::Value(n) if let Some(res) = fold(arith, &self.code[pc], *n, *n) => {
+                            Some(Instruction::Set(address(arith, r, &state), Reference::Value(res)))
+                        },
+                        _ => Some(rebuild(&self.code[pc], v, address(arith, r, &state))),
+                    }
+                },
+                Instruction::Add(v, r) | Instruction::Sub(v, r) | Instruction::Mult(v, r) | Instruction::Div(v, r)
+                | Instruction::Mod(v, r) | Instruction::And(v, r) | Instruction::Or(v, r) | Instruction::Xor(v, r)
+                | Instruction::Shl(v, r) | Instruction::Shr(v, r) | Instruction::Min(v, r) | Instruction::Max(v, r)
+                | Instruction::MMult(v, r) | Instruction::MDiv(v, r) => {
+                    let v = constant(arith, v, &state);
+                    let value = match &v {
+                        Reference::Value(n) => Some(*n),
+                        _ => None,
+                    };
+                    match r {
+                        Reference::Register(reg) => match (value, state[reg.index()]) {
+                            (Some(n), fact) if fact.is_init() && is_identity(arith, &self.code[pc], n) => None,
+                            (Some(n), Fact::Const(current)) => match fold(arith, &self.code[pc], n, current) {
+                                Some(res) => Some(Instruction::Set(r.clone(), Reference::Value(res))),
+                                None => Some(rebuild(&self.code[pc], v, r.clone())),
+                            },
+                            _ => Some(rebuild(&self.code[pc], v, r.clone())),
+                        },
+                        _ => Some(rebuild(&self.code[pc], v, address(arith, r, &state))),
+                    }
+                },
+                Instruction::GoIf(cond, a, b, label) => match (constant(arith, a, &state), constant(arith, b, &state)) {
+                    (Reference::Value(a), Reference::Value(b)) if cond.test(a, b) => Some(Instruction::Goto(label.clone())),
+                    (Reference::Value(_), Reference::Value(_)) => None,
+                    (a, b) => Some(Instruction::GoIf(*cond, a, b, label.clone())),
+                },
+                Instruction::Print(v, t, s) => Some(Instruction::Print(constant(arith, v, &state), *t, *s)),
+                Instruction::Put(v, t, s) => Some(Instruction::Put(constant(arith, v, &state), *t, *s)),
+                Instruction::Printf(v, s) => Some(Instruction::Printf(constant(arith, v, &state), *s)),
+                Instruction::Push(v) => Some(Instruction::Push(constant(arith, v, &state))),
+                Instruction::Pop(r) => Some(Instruction::Pop(address(arith, r, &state))),
+                Instruction::Alloc(n, r) => Some(Instruction::Alloc(constant(arith, n, &state), address(arith, r, &state))),
+                Instruction::Free(v) => Some(Instruction::Free(constant(arith, v, &state))),
+                Instruction::Read(r, t) => Some(Instruction::Read(address(arith, r, &state), *t)),
+                Instruction::Open(name, mode, r) => Some(Instruction::Open(constant(arith, name, &state), *mode, address(arith, r, &state))),
+                Instruction::ReadLine(h, r) => Some(Instruction::ReadLine(constant(arith, h, &state), address(arith, r, &state))),
+                Instruction::Write(h, v) => Some(Instruction::Write(constant(arith, h, &state), constant(arith, v, &state))),
+                Instruction::Close(h) => Some(Instruction::Close(constant(arith, h, &state))),
+                Instruction::Wait(n) => Some(Instruction::Wait(constant(arith, n, &state))),
+                Instruction::Buy(n) => Some(Instruction::Buy(constant(arith, n, &state))),
+                Instruction::Sell(n) => Some(Instruction::Sell(constant(arith, n, &state))),
+                inst => Some(inst.clone()),
+            };
+            match new {
+                None => {
+                    self.remove(pc);
+                    changed = true;
+                },
+                Some(new) => {
+                    changed |= new != self.code[pc];
+                    self.code[pc] = new;
+                },
+            }
+        }
+        self.sweep() || changed
+    }
+
+    /// `SET a b` right after `SET b a`, which can only be reached from it.
+    fn copies_back(&self, pc: usize) -> bool {
+        let Some(prev) = pc.checked_sub(1) else { return false };
+        match (&self.code[prev], &self.code[pc]) {
+            (
+                Instruction::Set(Reference::Register(a), Reference::Register(b)),
+                Instruction::Set(Reference::Register(c), Reference::Register(d)),
+            ) => a == d && b == c,
+            _ => false,
+        }
+    }
+
+    /// Removes `SET`s to registers that are written again before being read.
+    fn eliminate_dead_stores(&mut self) -> bool {
+        let labels = vm::resolve_labels(&self.code);
+        let facts = self.facts(&labels);
+        let successors: Vec<Vec<usize>> = (0..self.code.len()).map(|pc| self.successors(&labels, pc)).collect();
+
+        // Registers read later, as bit sets indexed by `Register::index`.
+        let mut live_in = vec![0u64; self.code.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for pc in (0..self.code.len()).rev() {
+                let out = successors[pc].iter().fold(0, |set, s| set | live_in[*s]);
+                let (uses, defs) = uses_defs(&self.code[pc]);
+                let live = (out & !defs) | uses;
+                if live != live_in[pc] {
+                    live_in[pc] = live;
+                    changed = true;
+                }
+            }
+        }
+
+        let mut removed = false;
+        for pc in 0..self.code.len() {
+            let Some(state) = facts[pc] else { continue };
+            if let Instruction::Set(Reference::Register(r), v) = &self.code[pc] {
+                let out = successors[pc].iter().fold(0, |set, s| set | live_in[*s]);
+                if out & bit(r) == 0 && cannot_fault(v, &state) {
+                    self.remove(pc);
+                    removed = true;
+                }
+            }
+        }
+        self.sweep() || removed
+    }
+
+    /// Points jumps past labels and `GOTO`s they would only pass through, and
+    /// removes jumps to the instruction that follows them anyway.
+    fn thread_jumps(&mut self) -> bool {
+        let labels = vm::resolve_labels(&self.code);
+        let facts = self.facts(&labels);
+        let mut changed = false;
+
+        for (pc, state) in facts.iter().enumerate() {
+            let target = match &self.code[pc] {
+                Instruction::Goto(label) | Instruction::GoIf(_, _, _, label) => label.clone(),
+                _ => continue,
+            };
+            let mut label = target.clone();
+            let mut seen = vec![target.clone()];
+            while let Some(decl) = labels.get(&label) {
+                let next = match self.code.get(decl + 1) {
+                    Some(Instruction::DeclareLabel(next) | Instruction::Goto(next)) => next,
+                    _ => break,
+                };
+                if seen.contains(next) {
+                    break;
+                }
+                seen.push(next.clone());
+                label = next.clone();
+            }
+
+            let falls_into = matches!(self.code.get(pc + 1), Some(Instruction::DeclareLabel(l)) if *l == target);
+            match &mut self.code[pc] {
+                Instruction::Goto(_) if falls_into => self.deleted.push(pc),
+                Instruction::GoIf(_, a, b, _)
+                    if falls_into && state.is_some_and(|s| cannot_fault(a, &s) && cannot_fault(b, &s)) =>
+                {
+                    self.deleted.push(pc)
+                },
+                Instruction::Goto(l) | Instruction::GoIf(_, _, _, l) if *l != label => {
+                    *l = label;
+                    changed = true;
+                },
+                _ => (),
+            }
+        }
+        self.sweep() || changed
+    }
+
+    /// Removes instructions no run can reach. They never tick, so this keeps
+    /// instruction counts too.
+    fn remove_unreachable(&mut self) -> bool {
+        let labels = vm::resolve_labels(&self.code);
+        let mut reached = vec![false; self.code.len()];
+        let mut work = if self.code.is_empty() { vec![] } else { vec![0] };
+        while let Some(pc) = work.pop() {
+            if reached[pc] {
+                continue;
+            }
+            reached[pc] = true;
+            work.extend(self.successors(&labels, pc));
+        }
+        // Jumps skip the declaration of their label, which must stay anyway.
+        let mut targeted = vec![false; self.code.len()];
+        for (pc, inst) in self.code.iter().enumerate() {
+            if let Instruction::Goto(label) | Instruction::GoIf(_, _, _, label) | Instruction::Call(label) = inst
+                && reached[pc]
+                && let Some(decl) = labels.get(label)
+            {
+                targeted[*decl] = true;
+            }
+        }
+        for (pc, reached) in reached.iter().enumerate() {
+            let nop = !self.keep_ticks && matches!(self.code[pc], Instruction::Nop);
+            if !(*reached || targeted[pc]) || nop {
+                self.deleted.push(pc);
+            }
+        }
+        self.sweep()
+    }
+
+    fn remove_unused_labels(&mut self) -> bool {
+        let used: Vec<&String> = self.code.iter().filter_map(|inst| match inst {
+            Instruction::Goto(label) | Instruction::GoIf(_, _, _, label) | Instruction::Call(label) => Some(label),
+            _ => None,
+        }).collect();
+        self.deleted = self.code.iter().enumerate()
+            .filter(|(_, inst)| matches!(inst, Instruction::DeclareLabel(l) if !used.contains(&l)))
+            .map(|(pc, _)| pc)
+            .collect();
+        self.sweep()
+    }
+}
+
+/// Register values after `inst` runs without faulting.
+fn transfer(arith: Arith, inst: &Instruction, mut state: State) -> State {
+    match inst {
+        Instruction::Set(Reference::Register(r), v) => state[r.index()] = match constant(arith, v, &state) {
+            Reference::Value(n) => Fact::Const(n),
+            _ => Fact::Init,
+        },
+        Instruction::Not(v, Reference::Register(r))
+        | Instruction::Neg(v, Reference::Register(r))
+        | Instruction::Abs(v, Reference::Register(r)) => {
+            state[r.index()] = match constant(arith, v, &state) {
+                Reference::Value(n) => fold(arith, inst, n, n).map_or(Fact::Init, Fact::Const),
+                _ => Fact::Init,
+            }
+        },
+        Instruction::Add(v, Reference::Register(r))
+        | Instruction::Sub(v, Reference::Register(r))
+        | Instruction::Mult(v, Reference::Register(r))
+        | Instruction::Div(v, Reference::Register(r))
+        | Instruction::Mod(v, Reference::Register(r))
+        | Instruction::And(v, Reference::Register(r))
+        | Instruction::Or(v, Reference::Register(r))
+        | Instruction::Xor(v, Reference::Register(r))
+        | Instruction::Shl(v, Reference::Register(r))
+        | Instruction::Shr(v, Reference::Register(r))
+        | Instruction::Min(v, Reference::Register(r))
+        | Instruction::Max(v, Reference::Register(r))
+        | Instruction::MMult(v, Reference::Register(r))
+        | Instruction::MDiv(v, Reference::Register(r)) => {
+            state[r.index()] = match (constant(arith, v, &state), state[r.index()]) {
+                (Reference::Value(n), Fact::Const(current)) => fold(arith, inst, n, current).map_or(Fact::Init, Fact::Const),
+                _ => Fact::Init,
+            }
+        },
+        Instruction::Pop(Reference::Register(r))
+        | Instruction::Read(Reference::Register(r), _)
+        | Instruction::Alloc(_, Reference::Register(r))
+        | Instruction::Open(_, _, Reference::Register(r))
+        | Instruction::ReadLine(_, Reference::Register(r)) => {
+            state[r.index()] = Fact::Init
+        },
+        Instruction::Enter(_) | Instruction::Leave => state[Register::Fp.index()] = Fact::Init,
+        _ => (),
+    }
+    state
+}
+
+/// The result the VM stores for an ALU instruction, or `None` if it would
+/// fault. Unary instructions ignore `current`.
+fn fold(arith: Arith, inst: &Instruction, val: Word, current: Word) -> Option<Word> {
+    let (op, ..) = inst.alu()?;
+    arith.apply(op, val, current).ok()
+}
+
+fn is_identity(arith: Arith, inst: &Instruction, val: Word) -> bool {
+    match inst {
+        Instruction::Add(..) | Instruction::Sub(..) | Instruction::Or(..) | Instruction::Xor(..) => val == 0,
+        Instruction::Shl(..) | Instruction::Shr(..) => val == 0,
+        Instruction::Mult(..) | Instruction::Div(..) => val == 1,
+        Instruction::MMult(..) | Instruction::MDiv(..) => val == money::SCALE,
+        Instruction::And(..) => val == -1,
+        Instruction::Min(..) => val == arith.width.max(),
+        Instruction::Max(..) => val == arith.width.min(),
+        _ => false,
+    }
+}
+
+fn rebuild(inst: &Instruction, v: Reference, r: Reference) -> Instruction {
+    match inst {
+        Instruction::Add(..) => Instruction::Add(v, r),
+        Instruction::Sub(..) => Instruction::Sub(v, r),
+        Instruction::Mult(..) => Instruction::Mult(v, r),
+        Instruction::Div(..) => Instruction::Div(v, r),
+        Instruction::Mod(..) => Instruction::Mod(v, r),
+        Instruction::And(..) => Instruction::And(v, r),
+        Instruction::Or(..) => Instruction::Or(v, r),
+        Instruction::Xor(..) => Instruction::Xor(v, r),
+        Instruction::Not(..) => Instruction::Not(v, r),
+        Instruction::Shl(..) => Instruction::Shl(v, r),
+        Instruction::Shr(..) => Instruction::Shr(v, r),
+        Instruction::Neg(..) => Instruction::Neg(v, r),
+        Instruction::Abs(..) => Instruction::Abs(v, r),
+        Instruction::Min(..) => Instruction::Min(v, r),
+        Instruction::Max(..) => Instruction::Max(v, r),
+        Instruction::MMult(..) => Instruction::MMult(v, r),
+        Instruction::MDiv(..) => Instruction::MDiv(v, r),
+        inst => unreachable!("[Optimize] {inst} is not arithmetic."),
+    }
+}
+
+/// A read of `r` with known register values replaced by literals.
+fn constant(arith: Arith, r: &Reference, state: &State) -> Reference {
+    match r {
+        Reference::Register(reg) => match state[reg.index()] {
+            Fact::Const(n) => Reference::Value(n),
+            _ => r.clone(),
+        },
+        _ => address(arith, r, state),
+    }
+}
+
+/// `r` with the known registers used in its address replaced by literals. An
+/// offset or a sum of literals becomes a plain address when it fits in a word.
+fn address(arith: Arith, r: &Reference, state: &State) -> Reference {
+    let known = |g: &GeneralRegister| match g {
+        GeneralRegister::Register(reg) => match state[reg.index()] {
+            Fact::Const(n) => GeneralRegister::Value(n),
+            _ => g.clone(),
+        },
+        _ => g.clone(),
+    };
+    match r {
+        Reference::Address(g) => Reference::Address(known(g)),
+        Reference::Offset(g, n) => match known(g) {
+            GeneralRegister::Value(a) if let Some(sum) = literal_sum(arith, a, *n) => {
+                Reference::Address(GeneralRegister::Value(sum))
+            },
+            g => Reference::Offset(g, *n),
+        },
+        Reference::Sum(a, b) => match (known(a), known(b)) {
+            (GeneralRegister::Value(a), GeneralRegister::Value(b)) if let Some(sum) = literal_sum(arith, a, b) => {
+                Reference::Address(GeneralRegister::Value(sum))
+            },
+            (a, b) => Reference::Sum(a, b),
+        },
+        Reference::Index(g, i) => Reference::Index(known(g), known(i)),
+        _ => r.clone(),
+    }
+}
+
+fn literal_sum(arith: Arith, a: Word, b: Word) -> Option<Word> {
+    let sum = i128::from(a) + i128::from(b);
+    arith.width.contains(sum).then_some(sum as Word)
+}
+
+/// Whether reading `r` can never stop the program.
+fn cannot_fault(r: &Reference, state: &State) -> bool {
+    match r {
+        Reference::Register(reg) => state[reg.index()].is_init(),
+        Reference::Sensor(_) | Reference::Value(_) => true,
+        Reference::Address(_) | Reference::Offset(..) | Reference::Sum(..) | Reference::Index(..) => false,
+    }
+}
+
+fn bit(r: &Register) -> u64 {
+    1 << r.index()
+}
+
+/// Registers read and registers overwritten by an instruction.
+fn uses_defs(inst: &Instruction) -> (u64, u64) {
+    let base = |r: &Reference| {
+        r.bases().into_iter().fold(0, |bits, g| match g {
+            GeneralRegister::Register(reg) => bits | bit(reg),
+            _ => bits,
+        })
+    };
+    let read = |r: &Reference| match r {
+        Reference::Register(reg) => bit(reg),
+        _ => base(r),
+    };
+    let written = |r: &Reference| match r {
+        Reference::Register(reg) => bit(reg),
+        _ => 0,
+    };
+    match inst {
+        Instruction::Set(r, v) | Instruction::Not(v, r) | Instruction::Neg(v, r) | Instruction::Abs(v, r) => {
+            (read(v) | base(r), written(r))
+        },
+        Instruction::Add(v, r) | Instruction::Sub(v, r) | Instruction::Mult(v, r) | Instruction::Div(v, r)
+        | Instruction::Mod(v, r) | Instruction::And(v, r) | Instruction::Or(v, r) | Instruction::Xor(v, r)
+        | Instruction::Shl(v, r) | Instruction::Shr(v, r) | Instruction::Min(v, r) | Instruction::Max(v, r)
+        | Instruction::MMult(v, r) | Instruction::MDiv(v, r) => {
+            (read(v) | read(r), written(r))
+        },
+        Instruction::GoIf(_, a, b, _) => (read(a) | read(b), 0),
+        Instruction::Print(v, _, _) | Instruction::Put(v, _, _) | Instruction::Printf(v, _)
+        | Instruction::Push(v) | Instruction::Free(v) => (read(v), 0),
+        Instruction::Alloc(n, r) | Instruction::Open(n, _, r) | Instruction::ReadLine(n, r) => {
+            (read(n) | base(r), written(r))
+        },
+        Instruction::Write(h, v) => (read(h) | read(v), 0),
+        Instruction::Close(h) | Instruction::Wait(h) | Instruction::Buy(h) | Instruction::Sell(h) => (read(h), 0),
+        Instruction::Pop(r) | Instruction::Read(r, _) => (base(r), written(r)),
+        Instruction::Enter(_) | Instruction::Leave => (bit(&Register::Fp), bit(&Register::Fp)),
+        _ => (0, 0),
+    }
+}
@@ -1,94 +1,384 @@
-use crate::{lexer::{Lexer, Token}, vm::{Condition, GeneralRegister, Instruction, Reference}};
+use std::{collections::HashMap, fmt::{self, Display, Formatter}};
+
+use crate::{lexer::{Lexer, Token}, vm::{arith::{Arith, Overflow, Width}, Condition, GeneralRegister, Instruction, Reference, Stream, Type, Word}};
 
 struct Parser<'a> {
-    lex: Lexer<'a>
+    lex: Lexer<'a>,
+    /// A token read ahead by `peek`.
+    peeked: Option<Token>,
+    /// Address of every data name.
+    addresses: HashMap<String, Word>,
+}
+
+/// A parsed program together with the source line of each instruction.
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+    pub lines: Vec<usize>,
+    /// Initialised data, laid out one after the other from address 0.
+    pub data: Vec<Data>,
+    /// How the program does arithmetic, set by `.arith` and `.width`.
+    pub arith: Arith,
+}
+
+impl Program {
+    /// The initial contents of the start of the static data region. Check
+    /// `data_size` against the layout first, since `.zero` may ask for any size.
+    pub fn data_words(&self) -> Vec<Word> {
+        self.data.iter().flat_map(Data::words).collect()
+    }
+
+    /// The number of words of `data_words`, without laying them out.
+    pub fn data_size(&self) -> usize {
+        self.data.iter().fold(0, |size, d| size.saturating_add(d.size()))
+    }
+}
+
+/// Data laid out by a `.string`, `.word` or `.zero` directive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Data {
+    pub name: String,
+    pub kind: DataKind,
 }
 
-pub fn read_lines(query: &str) -> Vec<Instruction> {
-    let mut parser = Parser::new(query);
-    parser.read_lines()
+#[derive(Debug, Clone, PartialEq)]
+pub enum DataKind {
+    /// The number of characters followed by each character, like strings read by `READ`.
+    String(String),
+    Word(Vec<Word>),
+    Zero(usize),
+}
+
+impl Data {
+    pub fn words(&self) -> Vec<Word> {
+        match &self.kind {
+            DataKind::String(s) => {
+                let chars: Vec<Word> = s.chars().map(|c| c as Word).collect();
+                std::iter::once(chars.len() as Word).chain(chars).collect()
+            },
+            DataKind::Word(words) => words.clone(),
+            DataKind::Zero(n) => vec![0; *n],
+        }
+    }
+
+    /// The number of words of `words`, without laying them out.
+    pub fn size(&self) -> usize {
+        match &self.kind {
+            DataKind::String(s) => 1 + s.chars().count(),
+            DataKind::Word(words) => words.len(),
+            DataKind::Zero(n) => *n,
+        }
+    }
+}
+
+/// Prints the directive back in the same syntax the parser accepts.
+impl Display for Data {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            DataKind::String(s) => {
+                write!(f, ".string {} \"", self.name)?;
+                for c in s.chars() {
+                    match c {
+                        '\n' => write!(f, "\\n")?,
+                        '\t' => write!(f, "\\t")?,
+                        '\r' => write!(f, "\\r")?,
+                        '\0' => write!(f, "\\0")?,
+                        '\\' | '"' => write!(f, "\\{c}")?,
+                        c => write!(f, "{c}")?,
+                    }
+                }
+                write!(f, "\"")
+            },
+            DataKind::Word(words) => {
+                write!(f, ".word {}", self.name)?;
+                words.iter().try_for_each(|w| write!(f, " {w}"))
+            },
+            DataKind::Zero(n) => write!(f, ".zero {} {n}", self.name),
+        }
+    }
+}
+
+pub fn read_program(query: &str) -> Program {
+    // The width is read first, since it decides which numbers fit in a word,
+    // and data next, so that names can be used before their directive.
+    let arith = Parser::new(query, HashMap::new(), Width::W64).read_arith();
+    let data = Parser::new(query, HashMap::new(), arith.width).read_data();
+    let mut addresses = HashMap::new();
+    let mut address: Word = 0;
+    for d in &data {
+        addresses.insert(d.name.clone(), address);
+        address = address.saturating_add(d.size() as Word);
+    }
+    let mut parser = Parser::new(query, addresses, arith.width);
+    let mut program = parser.read_program();
+    program.data = data;
+    program.arith = arith;
+    program
 }
 
 impl<'a> Parser<'a> {
-    fn new(query: &'a str) -> Self {
-        let lex = Lexer::new(query);
-        Parser { lex }
+    fn new(query: &'a str, addresses: HashMap<String, Word>, width: Width) -> Self {
+        let lex = Lexer::new(query, width);
+        Parser { lex, peeked: None, addresses }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        self.peeked.take().or_else(|| self.lex.next())
+    }
+
+    fn peek(&mut self) -> Option<&Token> {
+        if self.peeked.is_none() {
+            self.peeked = self.lex.next();
+        }
+        self.peeked.as_ref()
+    }
+
+    fn read_program(&mut self) -> Program {
+        let mut instructions = vec![];
+        let mut lines = vec![];
+        while let Some((line, instruction)) = self.match_instruction() {
+            instructions.push(instruction);
+            lines.push(line);
+        }
+        Program { instructions, lines, data: vec![], arith: Arith::default() }
+    }
+
+    /// Reads the `.arith` and `.width` directives, skipping everything else.
+    fn read_arith(&mut self) -> Arith {
+        let (mut overflow, mut width) = (None, None);
+        while let Some(token) = self.next() {
+            match token {
+                Token::Arith => {
+                    let mode = match self.next() {
+                        Some(Token::Name(n)) if n == "wrapping" => Overflow::Wrapping,
+                        Some(Token::Name(n)) if n == "saturating" => Overflow::Saturating,
+                        Some(Token::Name(n)) if n == "trapping" => Overflow::Trapping,
+                        _ => panic!("[Parser] Expected wrapping, saturating or trapping in .arith directive: .arith mode."),
+                    };
+                    if overflow.replace(mode).is_some() {
+                        panic!("[Parser] Duplicate .arith directive.");
+                    }
+                },
+                Token::Width => {
+                    let bits = match self.next() {
+                        Some(Token::Value(32)) => Width::W32,
+                        Some(Token::Value(64)) => Width::W64,
+                        _ => panic!("[Parser] Expected 32 or 64 in .width directive: .width bits."),
+                    };
+                    if width.replace(bits).is_some() {
+                        panic!("[Parser] Duplicate .width directive.");
+                    }
+                },
+                _ => continue,
+            }
+            self.expect_endline();
+        }
+        Arith { overflow: overflow.unwrap_or_default(), width: width.unwrap_or_default() }
     }
 
-    fn read_lines(&mut self) -> Vec<Instruction> {
-        let mut vec = vec![];
-        while let Some(instruction) = self.match_instruction() {
-            vec.push(instruction);
+    /// Reads every directive, skipping the instructions.
+    fn read_data(&mut self) -> Vec<Data> {
+        let mut data: Vec<Data> = vec![];
+        while let Some(token) = self.next() {
+            let Some(d) = self.directive(token) else { continue };
+            if data.iter().any(|other| other.name == d.name) {
+                panic!("[Parser] Duplicate data name {}.", d.name);
+            }
+            data.push(d);
+        }
+        data
+    }
+
+    /// Parses the directive starting with `token`, or returns `None` for any
+    /// other token.
+    fn directive(&mut self, token: Token) -> Option<Data> {
+        let (directive, usage) = match token {
+            Token::DataString => (".string", ".string name \"text\""),
+            Token::DataWord => (".word", ".word name n [n ...]"),
+            Token::DataZero => (".zero", ".zero name n"),
+            _ => return None,
+        };
+        let name = match self.next() {
+            Some(Token::Name(name)) => name,
+            n => panic!("[Parser] Expected a name in {directive} directive: {usage}, got {n:?}."),
+        };
+        let kind = match token {
+            Token::DataString => match self.next() {
+                Some(Token::Str(s)) => DataKind::String(s),
+                _ => panic!("[Parser] Expected a string in {directive} directive: {usage}."),
+            },
+            Token::DataWord => {
+                let mut words = vec![];
+                loop {
+                    match self.next() {
+                        Some(Token::Value(n)) => words.push(n),
+                        Some(Token::Endline) | None if !words.is_empty() => {
+                            return Some(Data { name, kind: DataKind::Word(words) });
+                        },
+                        _ => panic!("[Parser] Expected numbers in {directive} directive: {usage}."),
+                    }
+                }
+            },
+            _ => match self.next() {
+                Some(Token::Value(n)) if n >= 0 => DataKind::Zero(n as usize),
+                _ => panic!("[Parser] Expected a size in {directive} directive: {usage}."),
+            },
+        };
+        self.expect_endline();
+        Some(Data { name, kind })
+    }
+
+    /// Address of the data called `name`.
+    fn data_address(&self, name: &str, inst: &str, usage: &str) -> Word {
+        match self.addresses.get(name) {
+            Some(address) => *address,
+            None => panic!("[Parser] Unknown register or data name {name} in {inst} instruction: {usage}."),
         }
-        vec
     }
 
     fn expect_endline(&mut self) {
-        match self.lex.next() {
+        match self.next() {
             Some(Token::Endline) | None => (),
             n => panic!("[INVM] Expected endline after instruction, got {n:?}.")
         }
     }
 
     fn expect_read_only(&mut self, inst: &str, usage: &str) -> Reference {
-        match self.lex.next() {
-            Some(Token::Reference) => {
-                let r = match self.lex.next() {
-                    Some(Token::Reg(r)) => GeneralRegister::Register(r),
-                    Some(Token::Value(n)) => GeneralRegister::Value(n),
-                    Some(Token::Sens(s)) => GeneralRegister::Sensor(s),
-                    _ => panic!("[Parser] Expected a register or number in {inst} instruction: {usage}."),
-                };
-                Reference::Address(r)
-            }
+        let token = self.next();
+        self.read_only(token, inst, usage)
+    }
+
+    /// Parses a `*R/n` operand starting at `token`.
+    fn read_only(&mut self, token: Option<Token>, inst: &str, usage: &str) -> Reference {
+        match token {
+            Some(Token::Reference) => self.address(inst, usage),
             Some(Token::Reg(r)) => Reference::Register(r),
             Some(Token::Value(n)) => Reference::Value(n),
             Some(Token::Sens(s)) => Reference::Sensor(s),
+            Some(Token::Name(n)) => Reference::Value(self.data_address(&n, inst, usage)),
             _ => panic!("[Parser] Expected a register or number in {inst} instruction: {usage}."),
         }
     }
 
     fn expect_write(&mut self, inst: &str, usage: &str) -> Reference {
-        match self.lex.next() {
-            Some(Token::Reference) => {
-                let r = match self.lex.next() {
-                    Some(Token::Reg(r)) => GeneralRegister::Register(r),
-                    Some(Token::Value(n)) => GeneralRegister::Value(n),
-                    Some(Token::Sens(s)) => GeneralRegister::Sensor(s),
-                    _ => panic!("[Parser] Expected a register in {inst} instruction: {usage}."),
-                };
-                Reference::Address(r)
-            }
+        match self.next() {
+            Some(Token::Reference) => self.address(inst, usage),
             Some(Token::Reg(r)) => Reference::Register(r),
             _ => panic!("[Parser] Expected a register in number {inst} instruction: {usage}."),
         }
+    }
+
+    /// Parses the operand after a `*`: `*R`, `*R+n`, `*R-n`, `*(R+R)` or `*R[i]`.
+    fn address(&mut self, inst: &str, usage: &str) -> Reference {
+        if let Some(Token::LParen) = self.peek() {
+            self.next();
+            let a = self.base(inst, usage);
+            if !matches!(self.next(), Some(Token::Plus)) {
+                panic!("[Parser] Expected + between the registers of *(R+R) in {inst} instruction: {usage}.");
+            }
+            let b = self.base(inst, usage);
+            if !matches!(self.next(), Some(Token::RParen)) {
+                panic!("[Parser] Expected ) to close *(R+R) in {inst} instruction: {usage}.");
+            }
+            return Reference::Sum(a, b);
+        }
+
+        let base = self.base(inst, usage);
+        match self.peek() {
+            Some(Token::Plus | Token::Minus) => {
+                let negative = matches!(self.next(), Some(Token::Minus));
+                match self.next() {
+                    Some(Token::Value(n)) if n >= 0 => Reference::Offset(base, if negative { -n } else { n }),
+                    _ => panic!("[Parser] Expected a number after the sign of *R+n in {inst} instruction: {usage}."),
+                }
+            },
+            Some(Token::LBracket) => {
+                self.next();
+                let index = self.base(inst, usage);
+                if !matches!(self.next(), Some(Token::RBracket)) {
+                    panic!("[Parser] Expected ] to close *R[i] in {inst} instruction: {usage}.");
+                }
+                Reference::Index(base, index)
+            },
+            _ => Reference::Address(base),
+        }
+    }
 
+    /// Parses a register, sensor, number or data name used to compute an address.
+    fn base(&mut self, inst: &str, usage: &str) -> GeneralRegister {
+        match self.next() {
+            Some(Token::Reg(r)) => GeneralRegister::Register(r),
+            Some(Token::Value(n)) => GeneralRegister::Value(n),
+            Some(Token::Sens(s)) => GeneralRegister::Sensor(s),
+            Some(Token::Name(n)) => GeneralRegister::Value(self.data_address(&n, inst, usage)),
+            _ => panic!("[Parser] Expected a register or number in {inst} instruction: {usage}."),
+        }
     }
-    fn match_instruction(&mut self) -> Option<Instruction> {
+
+    fn match_instruction(&mut self) -> Option<(usize, Instruction)> {
+        let mut line;
         let inst = loop {
-            break match self.lex.next()? {
+            line = self.lex.line;
+            break match self.next()? {
                 Token::Endline => continue,
                 Token::Set => self.set(),
                 Token::Add => self.add(),
                 Token::Sub => self.sub(),
                 Token::Mult => self.mult(),
                 Token::Div => self.div(),
+                Token::Mod => self.alu("MOD", Instruction::Mod),
+                Token::And => self.alu("AND", Instruction::And),
+                Token::Or => self.alu("OR", Instruction::Or),
+                Token::Xor => self.alu("XOR", Instruction::Xor),
+                Token::Not => self.alu("NOT", Instruction::Not),
+                Token::Shl => self.alu("SHL", Instruction::Shl),
+                Token::Shr => self.alu("SHR", Instruction::Shr),
+                Token::Neg => self.alu("NEG", Instruction::Neg),
+                Token::Abs => self.alu("ABS", Instruction::Abs),
+                Token::Min => self.alu("MIN", Instruction::Min),
+                Token::Max => self.alu("MAX", Instruction::Max),
+                Token::MMult => self.alu("MMULT", Instruction::MMult),
+                Token::MDiv => self.alu("MDIV", Instruction::MDiv),
                 Token::Goto => self.goto(),
                 Token::GoIf => self.go_if(),
-                Token::Print => self.print(),
+                Token::Print => self.print("PRINT", Instruction::Print),
+                Token::Put => self.print("PUT", Instruction::Put),
+                Token::Printf => self.printf(),
                 Token::Push => self.push(),
                 Token::Pop => self.pop(),
                 Token::Crash => self.crash(),
-                Token::Buy => self.buy(),
-                Token::Sell => self.sell(),
+                Token::Nop => Instruction::Nop,
+                Token::Wait => Instruction::Wait(self.expect_read_only("WAIT", "WAIT *R/n")),
+                Token::Call => self.call(),
+                Token::Ret => Instruction::Ret,
+                Token::Alloc => self.alloc(),
+                Token::Free => Instruction::Free(self.expect_read_only("FREE", "FREE *R/n")),
+                Token::Enter => self.enter(),
+                Token::Leave => Instruction::Leave,
+                Token::Open => self.open(),
+                Token::ReadLine => self.read_line(),
+                Token::Write => self.write(),
+                Token::Close => Instruction::Close(self.expect_read_only("CLOSE", "CLOSE *R/n")),
+                Token::Buy => Instruction::Buy(self.expect_read_only("BUY", "BUY *R/n")),
+                Token::Sell => Instruction::Sell(self.expect_read_only("SELL", "SELL *R/n")),
                 Token::LabelDeclare(n) => Instruction::DeclareLabel(n),
                 Token::Read => self.read(),
                 Token::Label(n) => panic!("[Parser] Incorrect use of label {n}."),
+                Token::Name(n) => panic!("[Parser] Unknown instruction {n}."),
+                // Read before the instructions.
+                token @ (Token::DataString | Token::DataWord | Token::DataZero) => {
+                    self.directive(token);
+                    continue;
+                },
+                Token::Arith | Token::Width => {
+                    self.next();
+                    self.expect_endline();
+                    continue;
+                },
                 n => panic!("[Parser] Unknown instruction {n:?}.")
             };
         };
         self.expect_endline();
-        Some(inst)
+        Some((line, inst))
     }
 
     fn set(&mut self) -> Instruction {
@@ -136,15 +426,47 @@ impl<'a> Parser<'a> {
         Instruction::Div(value, reg)
     }
 
+    /// Parses the `*R/n *R` operands shared by the other arithmetic and bitwise instructions.
+    fn alu(&mut self, inst: &str, build: fn(Reference, Reference) -> Instruction) -> Instruction {
+        let usage = format!("{inst} *R/n *R");
+        let value = self.expect_read_only(inst, &usage);
+        let reg = self.expect_write(inst, &usage);
+
+        build(value, reg)
+    }
+
+    fn alloc(&mut self) -> Instruction {
+        let inst = "ALLOC";
+        let usage = "ALLOC *R/n *R";
+        let words = self.expect_read_only(inst, usage);
+        let reg = self.expect_write(inst, usage);
+
+        Instruction::Alloc(words, reg)
+    }
+
     fn goto(&mut self) -> Instruction {
-        match self.lex.next() {
+        match self.next() {
             Some(Token::Label(s)) => Instruction::Goto(s),
             _ => panic!("[Parser] Expected a label in GOTO instruction: GOTO label.")
         }
     }
 
+    fn call(&mut self) -> Instruction {
+        match self.next() {
+            Some(Token::Label(s)) => Instruction::Call(s),
+            _ => panic!("[Parser] Expected a label in CALL instruction: CALL label.")
+        }
+    }
+
+    fn enter(&mut self) -> Instruction {
+        match self.next() {
+            Some(Token::Value(n)) if n >= 0 => Instruction::Enter(n as usize),
+            _ => panic!("[Parser] Expected a number of locals in ENTER instruction: ENTER n.")
+        }
+    }
+
     fn go_if(&mut self) -> Instruction {
-        let cond = match self.lex.next() {
+        let cond = match self.next() {
             Some(Token::Equals) => Condition::Equals,
             Some(Token::Greater) => Condition::Greater,
             Some(Token::Lesser) => Condition::Lesser,
@@ -154,24 +476,48 @@ impl<'a> Parser<'a> {
             n => panic!("[Parser] Expected a Condition in GOIF instruction (GOIF COND R label), got {n:?}"),
         };
 
-        let reg = self.expect_read_only("GOIF", "GOIF COND *R/n label");
-
-        let label = match self.lex.next() {
-            Some(Token::Label(s)) => s,
-            _ => panic!("[Parser] Expected a label in GOIF instruction: GOIF R label.")
+        let usage = "GOIF COND *R/n [*R/n] label";
+        let reg = self.expect_read_only("GOIF", usage);
+
+        // Without a second operand the first one is compared with 0.
+        let (other, label) = match self.next() {
+            Some(Token::Label(s)) => (Reference::Value(0), s),
+            token => {
+                let other = self.read_only(token, "GOIF", usage);
+                match self.next() {
+                    Some(Token::Label(s)) => (other, s),
+                    _ => panic!("[Parser] Expected a label in GOIF instruction: {usage}.")
+                }
+            }
         };
 
-        Instruction::GoIf(cond, reg, label)
+        Instruction::GoIf(cond, reg, other, label)
     }
 
-    fn print(&mut self) -> Instruction {
-        let val = self.expect_read_only("PRINT", "PRINT *R/n type");
+    fn print(&mut self, inst: &str, build: fn(Reference, Type, Stream) -> Instruction) -> Instruction {
+        let usage = format!("{inst} *R/n type [err]");
+        let val = self.expect_read_only(inst, &usage);
 
-        let t = match self.lex.next() {
+        let t = match self.next() {
             Some(Token::Type(t)) => t,
-            _ => panic!("[Parser] Expected a type after PRINT instruction (PRINT R/n type).")
+            _ => panic!("[Parser] Expected a type after {inst} instruction ({usage}).")
         };
-        Instruction::Print(val, t)
+        build(val, t, self.stream())
+    }
+
+    fn printf(&mut self) -> Instruction {
+        let format = self.expect_read_only("PRINTF", "PRINTF *R/n [err]");
+        Instruction::Printf(format, self.stream())
+    }
+
+    /// The optional `err` at the end of a print instruction.
+    fn stream(&mut self) -> Stream {
+        if let Some(Token::Stream(s)) = self.peek() {
+            let s = *s;
+            self.next();
+            return s;
+        }
+        Stream::Out
     }
 
     fn push(&mut self) -> Instruction {
@@ -189,25 +535,40 @@ impl<'a> Parser<'a> {
         Instruction::Crash
     }
 
-    fn buy(&mut self) -> Instruction {
-        let val = match self.lex.next() {
-            Some(Token::Value(n)) => n,
-            _ => panic!("[Parser] Expected a positive value in BUY instruction: BUY n.")
+    fn open(&mut self) -> Instruction {
+        let inst = "OPEN";
+        let usage = "OPEN *R/n read|write|append *R";
+        let name = self.expect_read_only(inst, usage);
+        let mode = match self.next() {
+            Some(Token::Mode(mode)) => mode,
+            _ => panic!("[Parser] Expected read, write or append in OPEN instruction: {usage}.")
         };
-        Instruction::Buy(val)
+        let reg = self.expect_write(inst, usage);
+
+        Instruction::Open(name, mode, reg)
     }
 
-    fn sell(&mut self) -> Instruction {
-        let val = match self.lex.next() {
-            Some(Token::Value(n)) => n,
-            _ => panic!("[INVM] Expected a positive value in SELL instruction: SELL n.")
-        };
-        Instruction::Sell(val)
+    fn read_line(&mut self) -> Instruction {
+        let inst = "READLINE";
+        let usage = "READLINE *R/n *R";
+        let handle = self.expect_read_only(inst, usage);
+        let reg = self.expect_write(inst, usage);
+
+        Instruction::ReadLine(handle, reg)
+    }
+
+    fn write(&mut self) -> Instruction {
+        let inst = "WRITE";
+        let usage = "WRITE *R/n *R/n";
+        let handle = self.expect_read_only(inst, usage);
+        let text = self.expect_read_only(inst, usage);
+
+        Instruction::Write(handle, text)
     }
 
     fn read(&mut self) -> Instruction {
         let reg = self.expect_write("READ", "READ *R type");
-        let t = match self.lex.next() {
+        let t = match self.next() {
             Some(Token::Type(t)) => t,
             _ => panic!("[Parser] Expected a type after READ instruction (READ *R type).")
         };
@@ -1,19 +1,32 @@
+/// Removes `#` comments, keeping a `#` written in a character or string literal.
 pub fn filter(query: String) -> String {
     let mut new_query = String::new();
     let mut in_comment = false;
-    for i in 0..query.len() {
-
-        if query.get(i..=i) == Some("#") {
+    let mut chars = query.chars();
+    while let Some(c) = chars.next() {
+        if c == '\n' {
+            in_comment = false;
+        } else if c == '#' {
             in_comment = true;
         }
 
-        let c = query.get(i..i+1).unwrap();
-        if c == "\n" {
-            in_comment = false;
+        if in_comment {
+            continue;
         }
+        new_query.push(c);
 
-        if !in_comment {
-            new_query.push_str(c);
+        // Copies a literal as is, up to its closing quote or the end of the line.
+        if c == '\'' || c == '"' {
+            let mut escaped = false;
+            for next in chars.by_ref() {
+                new_query.push(next);
+                match next {
+                    '\n' => break,
+                    '\\' if !escaped => escaped = true,
+                    _ if next == c && !escaped => break,
+                    _ => escaped = false,
+                }
+            }
         }
     }
     new_query
@@ -0,0 +1,292 @@
+use std::{collections::VecDeque, fmt::{self, Display, Formatter}};
+
+use crate::{cfg::{Cfg, Target}, parser::{DataKind, Program}, vm::{self, Instruction, Piece, Reference, Type}};
+
+/// How many times a block may see its maximum depth grow through a backwards
+/// edge before the growth is considered unbounded.
+const WIDEN_AFTER: usize = 2;
+
+/// The range of stack depths possible before an instruction. A `max` of `None`
+/// means the depth has no static bound.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Depth {
+    pub min: usize,
+    pub max: Option<usize>,
+}
+
+impl Depth {
+    fn join(self, other: Depth) -> Depth {
+        let max = match (self.max, other.max) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            _ => None,
+        };
+        Depth { min: self.min.min(other.min), max }
+    }
+}
+
+/// The depth before an instruction, with the depth before every `ENTER` whose
+/// frame is still open, so that `LEAVE` can go back to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct State {
+    depth: Depth,
+    frames: Vec<Depth>,
+}
+
+impl State {
+    /// Frames are kept only as deep as on both paths, outermost first.
+    fn join(&self, other: &State) -> State {
+        State {
+            depth: self.depth.join(other.depth),
+            frames: self.frames.iter().zip(&other.frames).map(|(a, b)| a.join(*b)).collect(),
+        }
+    }
+}
+
+impl Display for Depth {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.max {
+            Some(max) if max == self.min => write!(f, "{max}"),
+            Some(max) => write!(f, "{}..{max}", self.min),
+            None => write!(f, "{}..", self.min),
+        }
+    }
+}
+
+pub enum Problem {
+    /// A `POP` that may run on an empty stack, or a `PRINTF` on a stack with
+    /// fewer values than its placeholders, with the instruction indexes of a
+    /// path from the start of the program that reaches it.
+    Underflow { pc: usize, path: Vec<usize> },
+    /// A loop, identified by its first instruction, whose every iteration may
+    /// leave more values on the stack.
+    UnboundedLoop { pc: usize },
+    /// A `READ str` or `READLINE`, whose heap usage depends on the length of
+    /// the input, or an `ALLOC`, both taking room the stack may need.
+    HeapUse { pc: usize },
+    /// The program may use more stack than the VM has, reported at the first
+    /// instruction that may go over.
+    Overflow { pc: usize, depth: usize },
+}
+
+pub struct Analysis {
+    /// Possible depth before every instruction, or `None` if it is unreachable.
+    pub depths: Vec<Option<Depth>>,
+    pub problems: Vec<Problem>,
+    /// The largest depth the program may reach, or `None` if it has no bound.
+    pub max_usage: Option<usize>,
+    /// The most values the stack of the VM can hold.
+    pub limit: usize,
+}
+
+impl Analysis {
+    /// Runs the analysis over the control-flow graph of `program`, for a stack
+    /// of at most `limit` values.
+    pub fn run(program: &Program, limit: usize) -> Self {
+        let placeholders = Self::placeholders(program);
+        let program = &program.instructions;
+        let cfg = Cfg::build(program);
+        let mut entries: Vec<Option<State>> = vec![None; cfg.blocks.len()];
+        // Predecessor block that lowered the minimum depth of each block, for witness paths.
+        let mut min_pred: Vec<Option<usize>> = vec![None; cfg.blocks.len()];
+        let mut growths = vec![0; cfg.blocks.len()];
+        let mut unbounded_loops = vec![];
+        let mut queue = VecDeque::new();
+
+        if !cfg.blocks.is_empty() {
+            entries[0] = Some(State { depth: Depth { min: 0, max: Some(0) }, frames: vec![] });
+            queue.push_back(0);
+        }
+
+        while let Some(b) = queue.pop_front() {
+            let block = &cfg.blocks[b];
+            let mut state = entries[b].clone().unwrap();
+            for pc in block.start..block.end {
+                state = Self::apply(&program[pc], placeholders[pc], state);
+            }
+
+            for edge in block.successors.iter() {
+                let Target::Block(next) = edge.target else { continue };
+                let new = match &entries[next] {
+                    None => state.clone(),
+                    Some(old) => {
+                        let mut new = old.join(&state);
+                        if new == *old {
+                            continue;
+                        }
+                        let backwards = cfg.blocks[next].start <= block.start;
+                        if backwards && new.depth.max != old.depth.max && old.depth.max.is_some() {
+                            growths[next] += 1;
+                            if growths[next] > WIDEN_AFTER {
+                                new.depth.max = None;
+                                unbounded_loops.push(cfg.blocks[next].start);
+                            }
+                        }
+                        if new.depth.min < old.depth.min {
+                            min_pred[next] = Some(b);
+                        }
+                        new
+                    }
+                };
+                if entries[next].is_none() {
+                    min_pred[next] = Some(b);
+                }
+                entries[next] = Some(new);
+                queue.push_back(next);
+            }
+        }
+
+        let mut depths = vec![None; program.len()];
+        let mut problems = vec![];
+        let mut max_usage = Some(0);
+        let mut overflow = None;
+        for (b, block) in cfg.blocks.iter().enumerate() {
+            let Some(mut state) = entries[b].clone() else { continue };
+            for pc in block.start..block.end {
+                let depth = state.depth;
+                depths[pc] = Some(depth);
+                match &program[pc] {
+                    Instruction::Pop(_) if depth.min == 0 => problems.push(Problem::Underflow {
+                        pc,
+                        path: Self::witness(&cfg, &min_pred, b, pc),
+                    }),
+                    Instruction::Printf(..) if placeholders[pc].is_some_and(|n| depth.min < n) => {
+                        problems.push(Problem::Underflow { pc, path: Self::witness(&cfg, &min_pred, b, pc) })
+                    },
+                    Instruction::Read(_, Type::Str) | Instruction::ReadLine(..) | Instruction::Alloc(..) => {
+                        problems.push(Problem::HeapUse { pc })
+                    },
+                    _ => (),
+                }
+                state = Self::apply(&program[pc], placeholders[pc], state);
+                max_usage = match (max_usage, state.depth.max) {
+                    (Some(usage), Some(max)) => Some(usage.max(max)),
+                    _ => None,
+                };
+                if let Some(max) = state.depth.max && max > limit && overflow.is_none() {
+                    overflow = Some(Problem::Overflow { pc, depth: max });
+                }
+            }
+        }
+        problems.extend(overflow);
+        unbounded_loops.sort();
+        unbounded_loops.dedup();
+        problems.extend(unbounded_loops.into_iter().map(|pc| Problem::UnboundedLoop { pc }));
+
+        Analysis { depths, problems, max_usage, limit }
+    }
+
+    /// The number of placeholders of every `PRINTF` whose format is the name
+    /// of a `.string`, assuming the program does not change it.
+    fn placeholders(program: &Program) -> Vec<Option<usize>> {
+        let mut strings = vec![];
+        let mut address: vm::Word = 0;
+        for data in &program.data {
+            if let DataKind::String(s) = &data.kind {
+                strings.push((address, s));
+            }
+            address = address.saturating_add(data.size() as vm::Word);
+        }
+        program.instructions.iter().map(|inst| {
+            let Instruction::Printf(Reference::Value(addr), _) = inst else { return None };
+            let (_, format) = strings.iter().find(|(a, _)| a == addr)?;
+            let pieces = vm::parse_format(format).ok()?;
+            Some(pieces.iter().filter(|p| matches!(p, Piece::Value(_))).count())
+        }).collect()
+    }
+
+    /// Stack depth after running `inst` from `state`. A `LEAVE` whose `ENTER`
+    /// is not known, such as in a function entered from several depths, may
+    /// leave any depth up to the current one, and so may a `PRINTF` whose
+    /// number of `placeholders` is not known.
+    fn apply(inst: &Instruction, placeholders: Option<usize>, mut state: State) -> State {
+        let depth = state.depth;
+        let pop = |n: usize| Depth { min: depth.min.saturating_sub(n), max: depth.max.map(|m| m.saturating_sub(n)) };
+        state.depth = match inst {
+            Instruction::Push(_) => Depth { min: depth.min + 1, max: depth.max.map(|m| m + 1) },
+            Instruction::Pop(_) => pop(1),
+            Instruction::Printf(..) => match placeholders {
+                Some(n) => pop(n),
+                None => Depth { min: 0, max: depth.max },
+            },
+            Instruction::Enter(words) => {
+                state.frames.push(depth);
+                Depth { min: depth.min + 1 + words, max: depth.max.map(|m| m + 1 + words) }
+            },
+            Instruction::Leave => state.frames.pop().unwrap_or(Depth { min: 0, max: depth.max }),
+            _ => depth,
+        };
+        state
+    }
+
+    /// Follows the predecessors that lowered the minimum depth back to the start
+    /// of the program, returning the first instruction of every block on the way.
+    fn witness(cfg: &Cfg, min_pred: &[Option<usize>], block: usize, pc: usize) -> Vec<usize> {
+        let mut path = vec![pc];
+        let mut visited = vec![false; cfg.blocks.len()];
+        let mut current = block;
+        while !visited[current] {
+            visited[current] = true;
+            if cfg.blocks[current].start != pc {
+                path.push(cfg.blocks[current].start);
+            }
+            match min_pred[current] {
+                Some(pred) => current = pred,
+                None => break,
+            }
+        }
+        path.reverse();
+        path
+    }
+
+    /// Prints every instruction with its possible depths, followed by the problems found.
+    pub fn report(&self, program: &Program) {
+        for (pc, inst) in program.instructions.iter().enumerate() {
+            let depth = match self.depths[pc] {
+                Some(d) => d.to_string(),
+                None => "-".to_string(),
+            };
+            let indent = if matches!(inst, Instruction::DeclareLabel(_)) { "" } else { "  " };
+            println!("{:>5} | {depth:>8} | {indent}{inst}", program.lines[pc]);
+        }
+        println!();
+
+        for problem in self.problems.iter() {
+            match problem {
+                Problem::Underflow { pc, path } => {
+                    let path: Vec<String> = path.iter().map(|p| program.lines[*p].to_string()).collect();
+                    let what = match program.instructions[*pc] {
+                        Instruction::Printf(..) => "PRINTF may run with fewer values on the stack than placeholders",
+                        _ => "POP may run on an empty stack",
+                    };
+                    println!("[Stack] Line {}: {what} (path through lines {}).", program.lines[*pc], path.join(" -> "));
+                }
+                Problem::UnboundedLoop { pc } => println!(
+                    "[Stack] Line {}: stack depth grows without bound in the loop starting at {}.",
+                    program.lines[*pc], program.instructions[*pc]
+                ),
+                Problem::HeapUse { pc } => {
+                    let what = match program.instructions[*pc] {
+                        Instruction::ReadLine(..) => "READLINE uses heap proportional to the input length",
+                        Instruction::Alloc(..) => "ALLOC uses heap",
+                        _ => "READ str uses heap proportional to the input length",
+                    };
+                    println!("[Stack] Line {}: {what}, leaving less room for the stack.", program.lines[*pc])
+                },
+                Problem::Overflow { pc, depth } => println!(
+                    "[Stack] Line {}: stack depth may reach {depth}, over the limit of {}.",
+                    program.lines[*pc], self.limit
+                ),
+            }
+        }
+
+        match self.max_usage {
+            Some(usage) => println!("[Stack] Maximum stack usage: {usage} entries."),
+            None => println!("[Stack] Maximum stack usage: unbounded."),
+        }
+    }
+
+    /// Whether any problem can crash the program at runtime.
+    pub fn has_errors(&self) -> bool {
+        self.problems.iter().any(|p| !matches!(p, Problem::HeapUse { .. }))
+    }
+}
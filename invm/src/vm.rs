@@ -1,66 +1,376 @@
-use std::{collections::HashMap, io::stdin};
+use std::{collections::HashMap, io::{self, Write, stdin}, panic::{self, AssertUnwindSafe}, path::PathBuf, time::{Duration, Instant}};
+
+use rand::{SeedableRng, rngs::StdRng};
 
 mod simulation;
+mod display;
+mod ir;
+mod files;
+pub mod arith;
+pub mod money;
+#[cfg(feature = "jit")]
+pub mod jit;
+
+use crate::{coverage::Coverage, memory::{Layout, Memory}, parser::Program, vm::{arith::{Alu, Arith, Fault}, files::Files, ir::{Base, Op, Operand, Target}}};
+
+/// A value in a register, sensor or memory word. Programs with 32-bit words
+/// only ever hold values that fit in an i32.
+pub type Word = i64;
 
-use crate::{parser, stack::Stack};
+/// Options chosen when the VM is created.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Seed for the market simulation. Runs with the same seed and input see the same prices.
+    pub seed: Option<u64>,
+    /// Sizes of the memory regions.
+    pub memory: Layout,
+    /// Most nested `CALL`s before the program faults.
+    pub call_limit: usize,
+    /// Number of general-purpose registers, `R0` up to at most `Register::MAX_GENERAL`.
+    pub registers: usize,
+    /// Never reuses freed heap blocks, faulting when they are used or freed
+    /// again, and reports the blocks left allocated when the program ends.
+    pub heap_debug: bool,
+    /// Directory whose files `OPEN` may use. Without it every `OPEN` is denied.
+    pub files: Option<PathBuf>,
+    /// Writes a summary of the prices seen by every `WAIT` to stderr.
+    pub trace: bool,
+}
 
-pub fn run(query: &str) {
-    let mut vm = VM::new(query);
-    while !vm.crash {
-        vm.step();
+impl Default for Config {
+    fn default() -> Self {
+        Config { seed: None, memory: Layout::default(), call_limit: 1024, registers: 16, heap_debug: false, files: None, trace: false }
     }
 }
 
-#[derive(Debug, Clone)]
+/// Most frames, and locals in each, shown when the program faults.
+const MAX_FRAMES_SHOWN: usize = 16;
+const MAX_LOCALS_SHOWN: usize = 8;
+
+pub fn run(program: &Program, config: &Config) {
+    let mut vm = VM::new(program, config);
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        while !vm.crash {
+            vm.step();
+        }
+    }));
+    if let Err(fault) = result {
+        vm.report_calls();
+        vm.report_frames();
+        panic::resume_unwind(fault);
+    }
+    if config.heap_debug {
+        vm.report_leaks();
+    }
+}
+
+/// Runs the program `runs` times, returning the time spent in the interpreter
+/// loop and the number of instructions executed by every run.
+pub fn bench(program: &Program, config: &Config, runs: usize) -> Vec<(Duration, u64)> {
+    (0..runs).map(|_| {
+        let mut vm = VM::new(program, config);
+        let mut steps = 0;
+        let start = Instant::now();
+        while !vm.crash {
+            vm.step();
+            steps += 1;
+        }
+        (start.elapsed(), steps)
+    }).collect()
+}
+
+/// Runs the program recording which instructions and branches executed.
+/// The coverage is also returned if the program faults, along with the panic.
+pub fn run_with_coverage(program: &Program, config: &Config) -> (Coverage, Option<Box<dyn std::any::Any + Send>>) {
+    let mut vm = VM::new(program, config);
+    vm.coverage = Some(Coverage::new(program));
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        while !vm.crash {
+            vm.step();
+        }
+    }));
+    if result.is_err() {
+        vm.report_calls();
+        vm.report_frames();
+    }
+    (vm.coverage.take().unwrap(), result.err())
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Instruction {
     Set(Reference, Reference),
     Add(Reference, Reference),
     Sub(Reference, Reference),
     Mult(Reference, Reference),
+    /// Divides the destination by the value, rounding toward zero.
     Div(Reference, Reference),
+    /// Remainder of the destination divided by the value, with the sign of the destination.
+    Mod(Reference, Reference),
+    And(Reference, Reference),
+    Or(Reference, Reference),
+    Xor(Reference, Reference),
+    /// Stores the bitwise complement of the value.
+    Not(Reference, Reference),
+    /// Shifts the destination left by the value, from 0 to one less than the word size.
+    Shl(Reference, Reference),
+    /// Shifts the destination right by the value, keeping its sign.
+    Shr(Reference, Reference),
+    /// Stores the value negated.
+    Neg(Reference, Reference),
+    /// Stores the absolute value of the value.
+    Abs(Reference, Reference),
+    Min(Reference, Reference),
+    Max(Reference, Reference),
+    /// Multiplies the destination by the value as amounts of money, keeping whole cents.
+    MMult(Reference, Reference),
+    /// Divides the destination by the value as amounts of money, keeping whole cents.
+    MDiv(Reference, Reference),
     Goto(String),
-    GoIf(Condition, Reference, String),
-    Print(Reference, Type),
+    /// Jumps if the first value compares to the second with the condition.
+    /// `GOIF cond v label` compares `v` with 0.
+    GoIf(Condition, Reference, Reference, String),
+    /// Prints the value followed by a newline.
+    Print(Reference, Type, Stream),
+    /// Prints the value without a newline.
+    Put(Reference, Type, Stream),
+    /// Prints the format string at the address, with its placeholders replaced
+    /// by values popped from the stack.
+    Printf(Reference, Stream),
     Push(Reference),
     Pop(Reference),
     Crash,
-    Buy(i32),
-    Sell(i32),
+    /// Buys the number of stocks, faulting without buying any if the balance is short.
+    Buy(Reference),
+    /// Sells the number of stocks, faulting without selling any if fewer are owned.
+    Sell(Reference),
     DeclareLabel(String),
-    Read(Reference, Type)
+    Read(Reference, Type),
+    /// Does nothing but let the market tick.
+    Nop,
+    /// Lets the market tick the number of times, instead of once.
+    Wait(Reference),
+    /// Jumps to the label, returning to the next instruction on `Ret`.
+    Call(String),
+    Ret,
+    /// Allocates a block of the number of words and stores its address.
+    Alloc(Reference, Reference),
+    /// Frees the block at the address.
+    Free(Reference),
+    /// Opens the file named by the string at the address, storing its handle
+    /// or a negative status.
+    Open(Reference, FileMode, Reference),
+    /// Reads the next line of the file as a string on the heap, storing its
+    /// address or a negative status.
+    ReadLine(Reference, Reference),
+    /// Writes the string at the second address to the file.
+    Write(Reference, Reference),
+    Close(Reference),
+    /// Pushes `FP`, points `FP` at it and reserves the number of zeroed words
+    /// below it for locals, `*FP-1` and down.
+    Enter(usize),
+    /// Drops the locals and restores the `FP` saved by `Enter`.
+    Leave,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Copy)]
 pub enum Type {
-    Int, Bool, Char, Str
+    Int, Bool, Char, Str,
+    /// Cents, shown as `R$ 1.234,56`.
+    Money,
 }
 
-#[derive(Debug, Clone)]
+/// How `OPEN` opens a file: `write` empties it first, and both `write` and
+/// `append` create it if needed.
+#[derive(Debug, Clone, PartialEq, Copy)]
+pub enum FileMode {
+    Read, Write, Append
+}
+
+/// Where `PRINT`, `PUT` and `PRINTF` write: stdout, or stderr with `err`.
+#[derive(Debug, Clone, PartialEq, Copy)]
+pub enum Stream {
+    Out, Err
+}
+
+/// A piece of a `PRINTF` format: text, or a placeholder for a value of the type.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Piece {
+    Text(String),
+    Value(Type),
+}
+
+/// Splits a `PRINTF` format at its placeholders: `%d`, `%b`, `%c`, `%s` and
+/// `%m` for `int`, `bool`, `char`, `str` and `money`, and `%%` for a `%`. Returns the
+/// invalid placeholder as the error.
+pub fn parse_format(format: &str) -> Result<Vec<Piece>, String> {
+    let mut pieces = vec![];
+    let mut text = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            text.push(c);
+            continue;
+        }
+        let t = match chars.next() {
+            Some('%') => {
+                text.push('%');
+                continue;
+            },
+            Some('d') => Type::Int,
+            Some('b') => Type::Bool,
+            Some('c') => Type::Char,
+            Some('s') => Type::Str,
+            Some('m') => Type::Money,
+            Some(c) => return Err(format!("%{c}")),
+            None => return Err("%".to_string()),
+        };
+        if !text.is_empty() {
+            pieces.push(Piece::Text(std::mem::take(&mut text)));
+        }
+        pieces.push(Piece::Value(t));
+    }
+    if !text.is_empty() {
+        pieces.push(Piece::Text(text));
+    }
+    Ok(pieces)
+}
+
+#[derive(Debug, Clone, PartialEq, Copy)]
 pub enum Condition {
     Equals, Different, Greater, Lesser, GreaterOrEqual, LesserOrEqual
 }
 
-#[derive(Debug, Clone)]
+impl Condition {
+    /// Whether `a` compares to `b` with this condition.
+    pub fn test(self, a: Word, b: Word) -> bool {
+        match self {
+            Condition::Equals => a == b,
+            Condition::Different => a != b,
+            Condition::Lesser => a < b,
+            Condition::Greater => a > b,
+            Condition::GreaterOrEqual => a >= b,
+            Condition::LesserOrEqual => a <= b,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Reference {
     Register(Register),
     Sensor(Sensor),
-    Value(i32),
-    Address(GeneralRegister)
+    Value(Word),
+    Address(GeneralRegister),
+    /// `*R+n` or `*R-n`: `n` words after or before the address in R.
+    Offset(GeneralRegister, Word),
+    /// `*(R+R)`: the sum of two addresses.
+    Sum(GeneralRegister, GeneralRegister),
+    /// `*R[i]`: element `i` of the array at R, which starts with its length.
+    Index(GeneralRegister, GeneralRegister),
 }
 
-#[derive(Debug, Clone)]
+impl Instruction {
+    /// Every operand of the instruction.
+    pub fn references(&self) -> Vec<&Reference> {
+        match self {
+            Instruction::Set(a, b) | Instruction::Add(a, b) | Instruction::Sub(a, b)
+            | Instruction::Mult(a, b) | Instruction::Div(a, b) | Instruction::Mod(a, b)
+            | Instruction::And(a, b) | Instruction::Or(a, b) | Instruction::Xor(a, b)
+            | Instruction::Not(a, b) | Instruction::Shl(a, b) | Instruction::Shr(a, b)
+            | Instruction::Neg(a, b) | Instruction::Abs(a, b) | Instruction::Min(a, b)
+            | Instruction::Max(a, b) | Instruction::MMult(a, b) | Instruction::MDiv(a, b)
+            | Instruction::GoIf(_, a, b, _) | Instruction::Alloc(a, b)
+            | Instruction::Open(a, _, b) | Instruction::ReadLine(a, b) | Instruction::Write(a, b) => vec![a, b],
+            Instruction::Free(r) | Instruction::Print(r, _, _) | Instruction::Put(r, _, _)
+            | Instruction::Printf(r, _) | Instruction::Push(r) | Instruction::Pop(r)
+            | Instruction::Read(r, _) | Instruction::Close(r) | Instruction::Wait(r)
+            | Instruction::Buy(r) | Instruction::Sell(r) => vec![r],
+            _ => vec![],
+        }
+    }
+
+    /// The operation, value and destination of an ALU instruction.
+    pub fn alu(&self) -> Option<(Alu, &Reference, &Reference)> {
+        let (op, v, r) = match self {
+            Instruction::Add(v, r) => (Alu::Add, v, r),
+            Instruction::Sub(v, r) => (Alu::Sub, v, r),
+            Instruction::Mult(v, r) => (Alu::Mult, v, r),
+            Instruction::Div(v, r) => (Alu::Div, v, r),
+            Instruction::Mod(v, r) => (Alu::Mod, v, r),
+            Instruction::And(v, r) => (Alu::And, v, r),
+            Instruction::Or(v, r) => (Alu::Or, v, r),
+            Instruction::Xor(v, r) => (Alu::Xor, v, r),
+            Instruction::Not(v, r) => (Alu::Not, v, r),
+            Instruction::Shl(v, r) => (Alu::Shl, v, r),
+            Instruction::Shr(v, r) => (Alu::Shr, v, r),
+            Instruction::Neg(v, r) => (Alu::Neg, v, r),
+            Instruction::Abs(v, r) => (Alu::Abs, v, r),
+            Instruction::Min(v, r) => (Alu::Min, v, r),
+            Instruction::Max(v, r) => (Alu::Max, v, r),
+            Instruction::MMult(v, r) => (Alu::MMult, v, r),
+            Instruction::MDiv(v, r) => (Alu::MDiv, v, r),
+            _ => return None,
+        };
+        Some((op, v, r))
+    }
+}
+
+impl Reference {
+    /// The values the address of a memory operand is computed from.
+    pub fn bases(&self) -> Vec<&GeneralRegister> {
+        match self {
+            Reference::Address(g) | Reference::Offset(g, _) => vec![g],
+            Reference::Sum(a, b) | Reference::Index(a, b) => vec![a, b],
+            _ => vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum GeneralRegister {
     Register(Register),
     Sensor(Sensor),
-    Value(i32),
+    Value(Word),
 }
 
-#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
 pub enum Register {
-    Fund1, Fund2
+    Fund1, Fund2,
+    /// General-purpose register `Rn`.
+    General(u8),
+    /// Frame pointer, set by `ENTER` and `LEAVE`. It starts at the end of the
+    /// memory, where the stack is empty.
+    Fp,
+}
+
+impl Register {
+    /// Most general-purpose registers a VM can have, so that every register
+    /// fits in a `u64` bit set.
+    pub const MAX_GENERAL: usize = 30;
+    pub const COUNT: usize = 3 + Register::MAX_GENERAL;
+
+    /// `Fp` comes after the general-purpose registers, keeping their indexes
+    /// in bytecode from before it existed.
+    pub fn index(self) -> usize {
+        match self {
+            Register::Fund1 => 0,
+            Register::Fund2 => 1,
+            Register::General(n) => 2 + n as usize,
+            Register::Fp => 2 + Register::MAX_GENERAL,
+        }
+    }
+
+    pub fn from_index(index: usize) -> Option<Register> {
+        match index {
+            0 => Some(Register::Fund1),
+            1 => Some(Register::Fund2),
+            _ if index < 2 + Register::MAX_GENERAL => Some(Register::General((index - 2) as u8)),
+            _ if index == Register::Fp.index() => Some(Register::Fp),
+            _ => None,
+        }
+    }
 }
 
-#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
 pub enum Sensor {
     Shares, 
     Stockprice,
@@ -71,280 +381,670 @@ pub enum Sensor {
     Balance
 }
 
+impl Sensor {
+    pub const COUNT: usize = 7;
+    pub const ALL: [Sensor; Sensor::COUNT] = [
+        Sensor::Shares,
+        Sensor::Stockprice,
+        Sensor::Reputation,
+        Sensor::MarketValue,
+        Sensor::Equity,
+        Sensor::Owned,
+        Sensor::Balance,
+    ];
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Text that does not end a line is flushed, so that a prompt shows before `READ`.
+fn write(stream: Stream, text: &str) {
+    match stream {
+        Stream::Out => {
+            print!("{text}");
+            if !text.ends_with('\n') {
+                io::stdout().flush().expect("[INVM] Failed to write to stdout.");
+            }
+        },
+        Stream::Err => eprint!("{text}"),
+    }
+}
+
+/// Checks that every general-purpose register used by the program exists in a
+/// VM with `count` of them.
+pub fn check_registers(program: &[Instruction], count: usize) {
+    let used = |r: &Reference| {
+        let direct = match r {
+            Reference::Register(reg) => Some(*reg),
+            _ => None,
+        };
+        let bases = r.bases().into_iter().filter_map(|g| match g {
+            GeneralRegister::Register(reg) => Some(*reg),
+            _ => None,
+        });
+        direct.into_iter().chain(bases).filter_map(|reg| match reg {
+            Register::General(n) => Some(n),
+            _ => None,
+        }).collect::<Vec<_>>()
+    };
+    for inst in program {
+        for n in inst.references().into_iter().flat_map(used) {
+            if n as usize >= count {
+                panic!("[INVM] Use of register R{n}, but the VM has {count} general-purpose registers.");
+            }
+        }
+    }
+}
+
+/// Maps every declared label to the index of its `DeclareLabel` instruction.
+pub fn resolve_labels(lines: &[Instruction]) -> HashMap<String, usize> {
+    let mut labels = HashMap::new();
+
+    for (i, inst) in lines.iter().enumerate() {
+        if let Instruction::DeclareLabel(s) = inst {
+            if labels.contains_key(s) {
+                panic!("[INVM] Duplicate label {s}.")
+            }
+            labels.insert(s.to_string(), i);
+        }
+    }
+    labels
+}
+
 struct VM {
-    registers: HashMap<Register, i32>,
-    sensors: HashMap<Sensor, i32>,
+    registers: [Option<Word>; Register::COUNT],
+    sensors: [Word; Sensor::COUNT],
     labels: HashMap<String, usize>,
     pc: usize,
     program: Vec<Instruction>,
-    stack: Stack,
-    crash: bool
+    code: Vec<Op>,
+    memory: Memory,
+    /// Index of every `CALL` waiting for its `RET`, innermost last.
+    calls: Vec<usize>,
+    call_limit: usize,
+    crash: bool,
+    coverage: Option<Coverage>,
+    files: Files,
+    arith: Arith,
+    trace: bool,
+    rng: StdRng
 }
 
 impl VM {
-    fn new(query: &str) -> Self {
-        let program = parser::read_lines(query);
+    fn new(program: &Program, config: &Config) -> Self {
+        config.memory.check_data(program.data_size());
+        let data = program.data_words();
+        let arith = program.arith;
+        let program = program.instructions.clone();
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_os_rng(),
+        };
+        check_registers(&program, config.registers);
+        let labels = resolve_labels(&program);
+        let mut registers = [None; Register::COUNT];
+        registers[Register::Fp.index()] = Some(config.memory.size as Word);
         VM {
-            registers: HashMap::new(),
+            registers,
             sensors: Self::init_sensors(),
-            labels: Self::init_labels(&program),
+            code: ir::lower(&program, &labels),
+            labels,
             pc: 0,
             program,
-            stack: Stack::new(),
-            crash: false
+            memory: Memory::new(&config.memory, &data, config.heap_debug),
+            calls: vec![],
+            call_limit: config.call_limit,
+            crash: false,
+            coverage: None,
+            files: Files::new(config.files.as_deref()),
+            arith,
+            trace: config.trace,
+            rng
         }
     }
 
-    fn init_labels(lines: &[Instruction]) -> HashMap<String, usize> {
-        let mut labels = HashMap::new();
-
-        for (i, inst) in lines.iter().enumerate() {
-            if let Instruction::DeclareLabel(s) = inst {
-                if labels.contains_key(s) {
-                    panic!("[INVM] Duplicate label {s}.")
-                }
-                labels.insert(s.to_string(), i);
-            }
-        }
-        labels
-    }
-
-    fn init_sensors() -> HashMap<Sensor, i32> {
-        let mut sensors = HashMap::new();
-        sensors.insert(Sensor::Shares, 0);
-        sensors.insert(Sensor::Stockprice, 200);
-        sensors.insert(Sensor::Reputation, 50);
-        sensors.insert(Sensor::MarketValue, 200);
-        sensors.insert(Sensor::Equity, 0);
-        sensors.insert(Sensor::Owned, 0);
-        sensors.insert(Sensor::Balance, 10000);
+    /// `STOCKPRICE`, `MARKETVAL`, `EQUITY` and `BALANCE` hold cents.
+    fn init_sensors() -> [Word; Sensor::COUNT] {
+        let mut sensors = [0; Sensor::COUNT];
+        sensors[Sensor::Shares.index()] = 0;
+        sensors[Sensor::Stockprice.index()] = 200 * money::SCALE;
+        sensors[Sensor::Reputation.index()] = 50;
+        sensors[Sensor::MarketValue.index()] = 200 * money::SCALE;
+        sensors[Sensor::Equity.index()] = 0;
+        sensors[Sensor::Owned.index()] = 0;
+        sensors[Sensor::Balance.index()] = 10000 * money::SCALE;
         sensors
     }
 
-    fn expect_register_value(&self, reg: &Register) -> i32 {
-        match self.registers.get(reg) {
-            None => panic!("[INVM] Use of uninitialized register {reg:?}."),
-            Some(n) => *n
+    fn expect_register_value(&self, reg: &Register) -> Word {
+        match self.registers[reg.index()] {
+            None => panic!("[INVM] Use of uninitialized register {reg}."),
+            Some(n) => n
         }
     }
 
-    fn set_at_reference(&mut self, reg: Reference, val: i32) {
+    /// Stores `val` in the destination of the instruction `what`.
+    fn set_at_reference(&mut self, what: &str, reg: Operand, val: Word) {
         match reg {
-            Reference::Register(r) => { self.registers.insert(r, val); },
-            Reference::Address(g) => {
-                let v = self.expect_general_reg(&g);
-                self.stack.set(self.to_address(v), val)
+            Operand::Register(r) => self.registers[r.index()] = Some(val),
+            Operand::Sensor(_) | Operand::Value(_) => {
+                panic!("[INVM] Unable to modify readonly value {reg:?} at {what} instruction.")
             },
-            _ => panic!("[INVM] Unable to modify readonly value {reg:?} at SUB instruction.")
+            _ => self.memory.set(self.address(&reg), val),
         }
 
     }
 
-    fn expect_sensor_value(&self, reg: &Sensor) -> i32 {
-        *self.sensors.get(reg).unwrap()
+    fn expect_sensor_value(&self, reg: &Sensor) -> Word {
+        self.sensors[reg.index()]
     }
 
-    fn expect_label(&mut self, label: &str) -> usize {
+    fn set_sensor_value(&mut self, reg: Sensor, val: Word) {
+        self.sensors[reg.index()] = val;
+    }
+
+    fn expect_label(&self, label: &str) -> usize {
         match self.labels.get(label) {
             None => panic!("[INVM] Use of unknown label {label}."),
             Some(n) => *n
         }
     }
 
-    fn expect_general_reg(&self, gr: &GeneralRegister) -> i32 {
-        match gr {
-            GeneralRegister::Register(r) => self.expect_register_value(r),
-            GeneralRegister::Sensor(s) => self.expect_sensor_value(s),
-            GeneralRegister::Value(v) => *v,
+    fn expect_base(&self, b: &Base) -> Word {
+        match b {
+            Base::Register(r) => self.expect_register_value(r),
+            Base::Sensor(s) => self.expect_sensor_value(s),
+            Base::Value(v) => *v,
+        }
+    }
+
+    fn expect_reference(&self, r: &Operand) -> Word {
+        match r {
+            Operand::Register(r) => self.expect_register_value(r),
+            Operand::Sensor(s) => self.expect_sensor_value(s),
+            Operand::Value(n) => *n,
+            _ => self.memory.get(self.address(r)),
         }
     }
 
-    fn expect_reference(&self, r: &Reference) -> i32 {
+    /// The address a memory operand points to. Offsets and sums are computed
+    /// without overflow, so that they fault at the address they reach.
+    fn address(&self, r: &Operand) -> usize {
         match r {
-            Reference::Register(r) => self.expect_register_value(r),
-            Reference::Sensor(s) => self.expect_sensor_value(s),
-            Reference::Value(n) => *n,
-            Reference::Address(gr) => self.stack.get(
-                self.to_address(self.expect_general_reg(gr))
-            ),
+            Operand::Address(b) => self.to_address(self.expect_base(b).into()),
+            Operand::Offset(b, n) => self.to_address(i128::from(self.expect_base(b)) + i128::from(*n)),
+            Operand::Sum(a, b) => self.to_address(i128::from(self.expect_base(a)) + i128::from(self.expect_base(b))),
+            Operand::Index(b, i) => self.element(self.expect_base(b), self.expect_base(i)),
+            _ => unreachable!("[INVM] {r:?} is not a memory operand."),
         }
     }
 
-    fn to_address(&self, v: i32) -> u16 {
-        if !(0..65535).contains(&v) {
-            panic!("[INVM] Segmentation fault.");
+    /// The address of element `i` of the array at `base`, whose first word is its length.
+    fn element(&self, base: Word, i: Word) -> usize {
+        let addr = self.to_address(base.into());
+        let len = self.memory.get(addr);
+        if !(0..len).contains(&i) {
+            panic!("[INVM] Index {i} out of bounds for the array of length {len} at address {addr}.");
         }
-        v as u16
+        addr + 1 + i as usize
+    }
+
+    fn to_address(&self, v: i128) -> usize {
+        if v < 0 {
+            panic!("[INVM] Segmentation fault at address {v}.");
+        }
+        v as usize
     }
 
     fn step(&mut self) {
-        let inst = match self.program.get(self.pc) {
+        let op = match self.code.get(self.pc) {
             None => {
                 self.crash = true;
                 return;
             }
-            Some(n) => n.clone(),
+            Some(op) => *op,
         };
 
-        match inst {
-            Instruction::Set(r, v) => self.set(r, v),
-            Instruction::Add(v, r) => self.add(v, r),
-            Instruction::Sub(v, r) => self.sub(v, r),
-            Instruction::Mult(v, r) => self.mult(v, r),
-            Instruction::Div(v, r) => self.div(v, r),
-            Instruction::Goto(label) => self.goto(label),
-            Instruction::GoIf(cond, val, label) => self.go_if(cond, val, label),
-            Instruction::Print(val, t) => self.print(val, t),
-            Instruction::Push(val) => self.push(val),
-            Instruction::Pop(reg) => self.pop(reg),
-            Instruction::Crash => self.crash(),
-            Instruction::Buy(amount) => self.buy(amount),
-            Instruction::Sell(amount) => self.sell(amount),
-            Instruction::DeclareLabel(_) => (),
-            Instruction::Read(r, t) => self.read(r, t) 
+        if let Some(coverage) = &mut self.coverage {
+            coverage.hit(self.pc);
+        }
+
+        match op {
+            Op::Set(r, v) => self.set(r, v),
+            Op::Alu(op, v, r) => self.alu(op, v, r),
+            Op::Goto(target) => self.goto(target),
+            Op::GoIf(cond, a, b, target) => self.go_if(cond, a, b, target),
+            Op::Print(val, t, stream) => self.print(val, t, stream, true),
+            Op::Put(val, t, stream) => self.print(val, t, stream, false),
+            Op::Printf(format, stream) => self.printf(format, stream),
+            Op::Push(val) => self.push(val),
+            Op::Pop(reg) => self.pop(reg),
+            Op::Crash => self.crash(),
+            Op::Buy(amount) => self.buy(amount),
+            Op::Sell(amount) => self.sell(amount),
+            Op::Nop => (),
+            Op::Wait(ticks) => {
+                self.wait(ticks);
+                self.pc += 1;
+                return;
+            },
+            Op::Read(r, t) => self.read(r, t),
+            Op::Call(target) => self.call(target),
+            Op::Ret => self.ret(),
+            Op::Alloc(n, r) => self.alloc(n, r),
+            Op::Free(v) => self.free(v),
+            Op::Enter(words) => self.enter_frame(words),
+            Op::Leave => self.leave_frame(),
+            Op::Open(name, mode, r) => self.open(name, mode, r),
+            Op::ReadLine(handle, r) => self.read_line(handle, r),
+            Op::Write(handle, text) => self.write_file(handle, text),
+            Op::Close(handle) => self.close(handle),
         }
         self.pc += 1;
         self.simulate();
     }
 
 
-    fn set(&mut self, reg: Reference, reg2: Reference) {
+    fn set(&mut self, reg: Operand, reg2: Operand) {
         let val = self.expect_reference(&reg2); 
-        self.set_at_reference(reg, val);
+        self.set_at_reference("SET", reg, val);
     }
 
-    fn add(&mut self, reg: Reference, reg2: Reference) {
+    /// Stores the result of `op` in the destination, which is not read by
+    /// `NOT`, `NEG` and `ABS`.
+    fn alu(&mut self, op: Alu, reg: Operand, reg2: Operand) {
         let val = self.expect_reference(&reg);
-        let current = self.expect_reference(&reg2);
+        let current = if op.is_unary() { val } else { self.expect_reference(&reg2) };
+        let res = self.compute(op, val, current);
+        self.set_at_reference(op.name(), reg2, res);
+    }
 
-        let sum = val + current;
+    fn compute(&self, op: Alu, val: Word, current: Word) -> Word {
+        self.arith.apply(op, val, current).unwrap_or_else(|fault| self.fault(op.name(), fault))
+    }
 
-        self.set_at_reference(reg2, sum);
+    /// `a + b`, `a - b` and `a * b` for `what`, faulting if the program traps on overflow.
+    fn add(&self, what: &str, a: Word, b: Word) -> Word {
+        self.arith.add(a, b).unwrap_or_else(|fault| self.fault(what, fault))
     }
 
-    fn sub(&mut self, reg: Reference, reg2: Reference) {
-        let val = self.expect_reference(&reg);
-        let current = self.expect_reference(&reg2);
-        let res = current - val;
-        self.set_at_reference(reg2, res);
+    fn sub(&self, what: &str, a: Word, b: Word) -> Word {
+        self.arith.sub(a, b).unwrap_or_else(|fault| self.fault(what, fault))
     }
 
-    fn mult(&mut self, reg: Reference, reg2: Reference) {
-        let val = self.expect_reference(&reg);
-        let current = self.expect_reference(&reg2);
-        let res = val * current;
-        self.set_at_reference(reg2, res);
+    fn mul(&self, what: &str, a: Word, b: Word) -> Word {
+        self.arith.mul(a, b).unwrap_or_else(|fault| self.fault(what, fault))
     }
 
-    fn div(&mut self, reg: Reference, reg2: Reference) {
-        let val = self.expect_reference(&reg);
-        let current = self.expect_reference(&reg2);
-        let res = val / current;
-        self.set_at_reference(reg2, res);
+    #[cold]
+    fn fault(&self, what: &str, fault: Fault) -> ! {
+        panic!("{}", fault.message(what, self.arith))
     }
 
-    fn goto(&mut self, label: String) {
-        self.pc = self.expect_label(&label);
+    fn goto(&mut self, target: Target) {
+        self.pc = match target {
+            Target::Label(pc) => pc,
+            Target::Unknown(pc) => match &self.program[pc] {
+                Instruction::Goto(label) | Instruction::GoIf(_, _, _, label) | Instruction::Call(label) => self.expect_label(label),
+                inst => unreachable!("[INVM] {inst} does not jump."),
+            },
+        };
     }
 
-    fn go_if(&mut self, cond: Condition, reg: Reference, label: String) {
-        let val = self.expect_reference(&reg);
+    fn call(&mut self, target: Target) {
+        self.enter(self.pc);
+        self.goto(target);
+    }
+
+    /// Records the `CALL` at `pc`, which `RET` returns after.
+    fn enter(&mut self, pc: usize) {
+        if self.calls.len() == self.call_limit {
+            panic!("[INVM] Call stack overflow: more than {} nested calls.", self.call_limit);
+        }
+        self.calls.push(pc);
+    }
+
+    fn ret(&mut self) {
+        match self.calls.pop() {
+            None => panic!("[INVM] RET without a caller."),
+            Some(pc) => self.pc = pc,
+        }
+    }
 
-        let c = match cond {
-            Condition::Equals => val == 0,
-            Condition::Different => val != 0,
-            Condition::Lesser => val < 0,
-            Condition::Greater => val > 0,
-            Condition::GreaterOrEqual => val >= 0,
-            Condition::LesserOrEqual => val <= 0,
+    fn enter_frame(&mut self, words: usize) {
+        let fp = self.expect_register_value(&Register::Fp);
+        self.memory.push(fp);
+        self.registers[Register::Fp.index()] = Some(self.memory.sp as Word);
+        for _ in 0..words {
+            self.memory.push(0);
+        }
+    }
+
+    fn leave_frame(&mut self) {
+        let fp = self.expect_register_value(&Register::Fp);
+        if fp == self.memory.size() as Word {
+            panic!("[INVM] LEAVE without a frame.");
+        }
+        if !(self.memory.sp as Word..self.memory.size() as Word).contains(&fp) {
+            panic!("[INVM] LEAVE with FP at {fp}, outside of the stack.");
+        }
+        self.memory.sp = fp as usize;
+        let saved = self.memory.pop();
+        self.registers[Register::Fp.index()] = Some(saved);
+    }
+
+    /// Prints the frames of `ENTER` from `FP` outwards, with the words between
+    /// each frame pointer and the frame below it: its locals and the values it
+    /// pushed. The chain stops at a saved `FP` that does not point further up
+    /// the stack.
+    fn report_frames(&self) {
+        let size = self.memory.size();
+        let mut fp = match self.registers[Register::Fp.index()] {
+            Some(fp) if (self.memory.sp as Word..size as Word).contains(&fp) => fp as usize,
+            _ => return,
         };
+        let mut bottom = self.memory.sp;
+        eprintln!("[INVM] Frames, innermost first:");
+        for shown in 0.. {
+            if shown == MAX_FRAMES_SHOWN {
+                eprintln!("[INVM]   ...");
+                break;
+            }
+            let mut locals: Vec<String> = (bottom..fp).rev().take(MAX_LOCALS_SHOWN)
+                .map(|addr| format!("FP-{} = {}", fp - addr, self.memory.get(addr)))
+                .collect();
+            if fp - bottom > MAX_LOCALS_SHOWN {
+                locals.push("...".to_string());
+            }
+            let locals = if locals.is_empty() { "no locals".to_string() } else { locals.join(", ") };
+            eprintln!("[INVM]   FP {fp}: {locals}");
+
+            let saved = self.memory.get(fp);
+            if saved <= fp as Word || saved >= size as Word {
+                break;
+            }
+            bottom = fp + 1;
+            fp = saved as usize;
+        }
+    }
+
+    /// Prints the labels of the calls that were running, outermost first, with
+    /// repeated recursive calls folded together.
+    fn report_calls(&self) {
+        if self.calls.is_empty() {
+            return;
+        }
+        let mut chain: Vec<(&str, usize)> = vec![];
+        for pc in &self.calls {
+            let Instruction::Call(label) = &self.program[*pc] else { continue };
+            match chain.last_mut() {
+                Some((last, count)) if last == label => *count += 1,
+                _ => chain.push((label, 1)),
+            }
+        }
+        let chain: Vec<String> = chain.iter().map(|(label, count)| match count {
+            1 => format!("${label}"),
+            n => format!("${label} (x{n})"),
+        }).collect();
+        eprintln!("[INVM] Call chain: {}", chain.join(" -> "));
+    }
+
+    fn go_if(&mut self, cond: Condition, reg: Operand, reg2: Operand, target: Target) {
+        let val = self.expect_reference(&reg);
+        let other = self.expect_reference(&reg2);
+        let c = cond.test(val, other);
+
+        if let Some(coverage) = &mut self.coverage {
+            coverage.branch(self.pc, c);
+        }
 
         if c {
-            self.goto(label);
+            self.goto(target);
         }
     }
 
-    fn print(&mut self, reg: Reference, t: Type) {
+    fn print(&mut self, reg: Operand, t: Type, stream: Stream, newline: bool) {
         let val = self.expect_reference(&reg);
+        let mut text = self.show(val, t);
+        if newline {
+            text.push('\n');
+        }
+        write(stream, &text);
+    }
+
+    /// The text `PRINT` shows for a value of the type.
+    fn show(&self, val: Word, t: Type) -> String {
         match t {
-            Type::Int => println!("{val}"),
-            Type::Bool => {
-                let p = if val == 0 {
-                    "bizarro"
-                } else {
-                    "certeza"
-                };
-                println!("{}", p);
+            Type::Int => val.to_string(),
+            Type::Bool => if val == 0 { "bizarro" } else { "certeza" }.to_string(),
+            Type::Char => ((val % 256) as u8 as char).to_string(),
+            Type::Str => self.memory.get_str(self.to_address(val.into())),
+            Type::Money => money::format(val),
+        }
+    }
+
+    /// The first placeholder takes the deepest of the values it pops, so that
+    /// they are pushed in the order they are printed.
+    fn printf(&mut self, format: Operand, stream: Stream) {
+        let addr = self.to_address(self.expect_reference(&format).into());
+        let pieces = match parse_format(&self.memory.get_str(addr)) {
+            Ok(pieces) => pieces,
+            Err(p) => panic!("[INVM] Invalid placeholder {p} in the PRINTF format at address {addr}."),
+        };
+        let count = pieces.iter().filter(|p| matches!(p, Piece::Value(_))).count();
+        let depth = self.memory.size() - self.memory.sp;
+        if depth < count {
+            panic!("[INVM] PRINTF needs {count} values, but the stack has {depth}.");
+        }
+
+        let mut text = String::new();
+        let mut arg = self.memory.sp + count;
+        for piece in pieces {
+            match piece {
+                Piece::Text(s) => text.push_str(&s),
+                Piece::Value(t) => {
+                    arg -= 1;
+                    text.push_str(&self.show(self.memory.get(arg), t));
+                },
             }
-            Type::Char => println!("{}", (val % 256) as u8 as char),
-            Type::Str => println!("{}", self.stack.get_str(val as u16))
         }
+        self.memory.sp += count;
+        write(stream, &text);
     }
 
-    fn push(&mut self, reg: Reference) {
+    fn push(&mut self, reg: Operand) {
         let val = self.expect_reference(&reg);
-        self.stack.push(val); 
+        self.memory.push(val); 
     }
 
-    fn pop(&mut self, reg: Reference) {
-        let val = self.stack.pop();
-        self.set_at_reference(reg, val);
+    fn pop(&mut self, reg: Operand) {
+        let val = self.memory.pop();
+        self.set_at_reference("POP", reg, val);
     }
 
     fn crash(&mut self) {
         self.crash = true;
     }
 
-    fn buy(&mut self, amount: i32) {
+    /// Buys `amount` stocks. The total price is computed exactly, so that an
+    /// order too large for a word is refused instead of wrapping, and both
+    /// sensors are only written once every check passed.
+    fn buy(&mut self, amount: Operand) {
+        let amount = self.stocks("BUY", amount);
         let balance = self.expect_sensor_value(&Sensor::Balance);
         let stockprice = self.expect_sensor_value(&Sensor::Stockprice);
-        let total_price = amount * stockprice;
-        if balance < total_price {
-            panic!("[INVM] Insufficient balance ({balance}) to buy {amount} stocks at price {stockprice} (total price: {total_price}).");
+        let total_price = i128::from(amount) * i128::from(stockprice);
+        if i128::from(balance) < total_price {
+            panic!(
+                "[INVM] Insufficient balance ({}) to buy {amount} stocks at price {} (total price: {}).",
+                money::format(balance), money::format(stockprice), money::format(total_price),
+            );
         }
 
-
         let owned = self.expect_sensor_value(&Sensor::Owned);
-        self.sensors.insert(Sensor::Balance, balance - total_price);
-        self.sensors.insert(Sensor::Owned, owned + amount);
+        let owned = self.add("BUY", owned, amount);
+        // At least 0 and at most the balance, so it fits.
+        let balance = (i128::from(balance) - total_price) as Word;
+        self.set_sensor_value(Sensor::Balance, balance);
+        self.set_sensor_value(Sensor::Owned, owned);
     }
 
-    fn sell(&mut self, amount: i32) {
+    fn sell(&mut self, amount: Operand) {
+        let amount = self.stocks("SELL", amount);
         let owned = self.expect_sensor_value(&Sensor::Owned);
         if amount > owned {
             panic!("[INVM] Insufficient stocks to sell (owned: {owned}, sell: {amount}).");
         }
 
         let stockprice = self.expect_sensor_value(&Sensor::Stockprice);
-        let total_price = amount * stockprice;
+        let total_price = i128::from(amount) * i128::from(stockprice);
         let balance = self.expect_sensor_value(&Sensor::Balance);
-        self.sensors.insert(Sensor::Balance, balance + total_price);
-        self.sensors.insert(Sensor::Owned, owned - amount);
+        let balance = self.arith.fit(i128::from(balance) + total_price)
+            .unwrap_or_else(|fault| self.fault("SELL", fault));
+        self.set_sensor_value(Sensor::Balance, balance);
+        self.set_sensor_value(Sensor::Owned, owned - amount);
+    }
+
+    /// The number of stocks `what` trades, which may be 0 but not negative.
+    fn stocks(&self, what: &str, amount: Operand) -> Word {
+        let amount = self.expect_reference(&amount);
+        if amount < 0 {
+            panic!("[INVM] {what} of {amount} stocks, expected at least 0.");
+        }
+        amount
+    }
+
+    /// Runs the market simulation `ticks` times, which `step` then does not
+    /// run for the `WAIT` itself.
+    fn wait(&mut self, ticks: Operand) {
+        let ticks = self.expect_reference(&ticks);
+        self.wait_ticks(ticks);
+    }
+
+    /// Runs `ticks` market ticks for the current instruction, tracing the prices they went through.
+    fn wait_ticks(&mut self, ticks: Word) {
+        if ticks < 0 {
+            panic!("[INVM] WAIT of {ticks} ticks, expected at least 0.");
+        }
+        let first = self.expect_sensor_value(&Sensor::Stockprice);
+        let (mut low, mut high) = (first, first);
+        for _ in 0..ticks {
+            self.simulate();
+            let price = self.expect_sensor_value(&Sensor::Stockprice);
+            low = low.min(price);
+            high = high.max(price);
+        }
+        if self.trace {
+            let last = self.expect_sensor_value(&Sensor::Stockprice);
+            eprintln!(
+                "[Trace] {} at instruction {}: {ticks} ticks, STOCKPRICE {} -> {} (low {}, high {}).",
+                self.program[self.pc], self.pc, money::format(first), money::format(last), money::format(low), money::format(high),
+            );
+        }
+    }
+
+    fn alloc(&mut self, n: Operand, r: Operand) {
+        let words = self.expect_reference(&n);
+        let addr = self.allocate(words);
+        self.set_at_reference("ALLOC", r, addr);
+    }
+
+    /// Allocates `words` on the heap for the current instruction.
+    fn allocate(&mut self, words: Word) -> Word {
+        if words < 1 {
+            panic!("[INVM] ALLOC of {words} words, expected at least 1.");
+        }
+        self.memory.alloc(words as usize, self.pc) as Word
+    }
+
+    fn free(&mut self, v: Operand) {
+        let addr = self.expect_reference(&v);
+        self.memory.free(self.to_address(addr.into()));
     }
 
-    fn read(&mut self, r: Reference, t: Type) {
+    /// Prints the heap blocks that were never freed and the instructions that allocated them.
+    fn report_leaks(&self) {
+        let blocks = self.memory.blocks();
+        if blocks.is_empty() {
+            return;
+        }
+        let words: usize = blocks.values().map(|b| b.words).sum();
+        eprintln!("[INVM] Leak: {} blocks, {words} words still allocated.", blocks.len());
+        for (addr, block) in blocks {
+            eprintln!("[INVM]   {} words at address {addr}, allocated by instruction {}: {}",
+                block.words, block.site, self.program[block.site]);
+        }
+    }
+
+    fn open(&mut self, name: Operand, mode: FileMode, r: Operand) {
+        let handle = self.open_file(name, mode);
+        self.set_at_reference("OPEN", r, handle);
+    }
+
+    fn open_file(&mut self, name: Operand, mode: FileMode) -> Word {
+        let addr = self.expect_reference(&name);
+        let name = self.memory.get_str(self.to_address(addr.into()));
+        self.files.open(&name, mode)
+    }
+
+    fn read_line(&mut self, handle: Operand, r: Operand) {
+        let val = self.read_file_line(handle);
+        self.set_at_reference("READLINE", r, val);
+    }
+
+    /// Reads a line of the file as a string on the heap, allocated by the
+    /// current instruction, returning its address or a negative status.
+    fn read_file_line(&mut self, handle: Operand) -> Word {
+        let handle = self.expect_reference(&handle);
+        match self.files.read_line(handle) {
+            Ok(line) => self.memory.alloc_str(&line, self.pc) as Word,
+            Err(status) => status,
+        }
+    }
+
+    fn write_file(&mut self, handle: Operand, text: Operand) {
+        let handle = self.expect_reference(&handle);
+        let addr = self.expect_reference(&text);
+        let text = self.memory.get_str(self.to_address(addr.into()));
+        self.files.write(handle, &text);
+    }
+
+    fn close(&mut self, handle: Operand) {
+        let handle = self.expect_reference(&handle);
+        self.files.close(handle);
+    }
+
+    fn read(&mut self, r: Operand, t: Type) {
+        let val = self.read_value(&t);
+        self.set_at_reference("READ", r, val);
+    }
+
+    /// Reads a line from the terminal and converts it to `t`. Strings are
+    /// allocated on the heap and their address is returned, and money is
+    /// written as in `money::parse`.
+    fn read_value(&mut self, t: &Type) -> Word {
         let mut res = String::new();
 
         stdin().read_line(&mut res).expect("[INVM] Failed to READ from terminal.");
         let res = res.trim_end();
 
         match t {
-            Type::Int | Type::Bool => {
-                let n = res.parse::<i32>().expect("[INVM] Failed to convert READ value to int.");
-                self.set_at_reference(r, n);
-            },
+            Type::Int | Type::Bool => res.parse::<Word>().ok()
+                .filter(|n| self.arith.width.contains((*n).into()))
+                .expect("[INVM] Failed to convert READ value to int."),
             Type::Char => {
                 if res.len() != 1 {
                     panic!("[INVM] Failed to convert READ value to char. Expected a single character, not multiple.")
                 }
 
                 let c = res.chars().next().expect("[INVM] Failed to get character of READ buffer.");
-                self.set_at_reference(r, c as i32);
+                c as Word
             },
-            Type::Str => {
-                let addr = self.stack.alloc_str(res.to_string());
-                self.set_at_reference(r, addr as i32);
-            }
-        };
+            Type::Str => self.memory.alloc_str(res, self.pc) as Word,
+            Type::Money => money::parse(res)
+                .filter(|n| self.arith.width.contains(*n))
+                .expect("[INVM] Failed to convert READ value to money.") as Word,
+        }
     }
 }
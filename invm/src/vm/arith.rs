@@ -0,0 +1,247 @@
+//! Integer arithmetic of the VM, shared by every backend.
+//!
+//! A program chooses the size of its words, 32 or 64 bits, and what happens
+//! when a result does not fit in one. Results are computed exactly in an i128
+//! and then brought into a word, so that the ALU instructions, `BUY`, `SELL`
+//! and the market simulation all overflow the same way.
+
+use crate::vm::{money, Word};
+
+/// What happens to a result that does not fit in a word, set by `.arith`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overflow {
+    /// Keeps the low bits of the result, as two's complement hardware does.
+    #[default]
+    Wrapping,
+    /// Keeps the largest or the smallest word.
+    Saturating,
+    /// Stops the program.
+    Trapping,
+}
+
+impl Overflow {
+    /// The mode as written after `.arith`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Overflow::Wrapping => "wrapping",
+            Overflow::Saturating => "saturating",
+            Overflow::Trapping => "trapping",
+        }
+    }
+}
+
+/// Size of registers, sensors and memory words, set by `.width`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Width {
+    #[default]
+    W32,
+    W64,
+}
+
+impl Width {
+    pub fn bits(self) -> u32 {
+        match self {
+            Width::W32 => 32,
+            Width::W64 => 64,
+        }
+    }
+
+    pub fn min(self) -> Word {
+        match self {
+            Width::W32 => i32::MIN.into(),
+            Width::W64 => i64::MIN,
+        }
+    }
+
+    pub fn max(self) -> Word {
+        match self {
+            Width::W32 => i32::MAX.into(),
+            Width::W64 => i64::MAX,
+        }
+    }
+
+    /// Whether `n` is a word of this width.
+    #[inline]
+    pub fn holds(self, n: Word) -> bool {
+        match self {
+            Width::W32 => i32::try_from(n).is_ok(),
+            Width::W64 => true,
+        }
+    }
+
+    /// The low bits of `n` as a word of this width.
+    #[inline]
+    pub fn wrap(self, n: i128) -> Word {
+        match self {
+            Width::W32 => (n as i32).into(),
+            Width::W64 => n as i64,
+        }
+    }
+
+    pub fn contains(self, n: i128) -> bool {
+        match self {
+            Width::W32 => i32::try_from(n).is_ok(),
+            Width::W64 => i64::try_from(n).is_ok(),
+        }
+    }
+}
+
+/// How a program does arithmetic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Arith {
+    pub overflow: Overflow,
+    pub width: Width,
+}
+
+/// An ALU instruction, which stores the result of its value and its
+/// destination in the destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alu {
+    Add, Sub, Mult, Div, Mod, And, Or, Xor, Not, Shl, Shr, Neg, Abs, Min, Max, MMult, MDiv
+}
+
+impl Alu {
+    /// Every operation, in the order of their index in JIT callbacks.
+    #[cfg(feature = "jit")]
+    pub const ALL: [Alu; 17] = [
+        Alu::Add, Alu::Sub, Alu::Mult, Alu::Div, Alu::Mod, Alu::And, Alu::Or, Alu::Xor,
+        Alu::Not, Alu::Shl, Alu::Shr, Alu::Neg, Alu::Abs, Alu::Min, Alu::Max, Alu::MMult, Alu::MDiv,
+    ];
+
+    /// Whether the result depends on the value only, so the destination is not read.
+    pub fn is_unary(self) -> bool {
+        matches!(self, Alu::Not | Alu::Neg | Alu::Abs)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Alu::Add => "ADD",
+            Alu::Sub => "SUB",
+            Alu::Mult => "MULT",
+            Alu::Div => "DIV",
+            Alu::Mod => "MOD",
+            Alu::And => "AND",
+            Alu::Or => "OR",
+            Alu::Xor => "XOR",
+            Alu::Not => "NOT",
+            Alu::Shl => "SHL",
+            Alu::Shr => "SHR",
+            Alu::Neg => "NEG",
+            Alu::Abs => "ABS",
+            Alu::Min => "MIN",
+            Alu::Max => "MAX",
+            Alu::MMult => "MMULT",
+            Alu::MDiv => "MDIV",
+        }
+    }
+}
+
+/// Why arithmetic stops the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The exact result, which does not fit in a word of a trapping program.
+    Overflow(i128),
+    /// `DIV`, `MOD` or `MDIV` by 0.
+    DivideByZero,
+    /// A shift by a number of bits outside of the word.
+    Shift(Word),
+}
+
+impl Fault {
+    /// The message the VM stops with when `what` faults.
+    pub fn message(self, what: &str, arith: Arith) -> String {
+        match self {
+            Fault::Overflow(exact) => {
+                format!("[INVM] Overflow in {what}: {exact} does not fit in {} bits.", arith.width.bits())
+            },
+            Fault::DivideByZero => format!("[INVM] Division by zero in {what}."),
+            Fault::Shift(val) => format!("[INVM] Shift by {val} bits, expected 0 to {}.", arith.width.bits() - 1),
+        }
+    }
+}
+
+impl Arith {
+    /// The word holding `exact`, following the overflow mode when it does not fit.
+    #[inline]
+    pub fn fit(self, exact: i128) -> Result<Word, Fault> {
+        if self.width.contains(exact) {
+            return Ok(exact as Word);
+        }
+        match self.overflow {
+            Overflow::Wrapping => Ok(self.width.wrap(exact)),
+            Overflow::Saturating => Ok(if exact < 0 { self.width.min() } else { self.width.max() }),
+            Overflow::Trapping => Err(Fault::Overflow(exact)),
+        }
+    }
+
+    /// `a + b`, `a - b` and `a * b`, computed in a word when the program wraps
+    /// or they fit, and exactly otherwise.
+    #[inline]
+    pub fn add(self, a: Word, b: Word) -> Result<Word, Fault> {
+        if self.overflow == Overflow::Wrapping {
+            return Ok(self.width.wrap(i128::from(a.wrapping_add(b))));
+        }
+        match a.checked_add(b) {
+            Some(n) if self.width.holds(n) => Ok(n),
+            _ => self.fit(i128::from(a) + i128::from(b)),
+        }
+    }
+
+    #[inline]
+    pub fn sub(self, a: Word, b: Word) -> Result<Word, Fault> {
+        if self.overflow == Overflow::Wrapping {
+            return Ok(self.width.wrap(i128::from(a.wrapping_sub(b))));
+        }
+        match a.checked_sub(b) {
+            Some(n) if self.width.holds(n) => Ok(n),
+            _ => self.fit(i128::from(a) - i128::from(b)),
+        }
+    }
+
+    #[inline]
+    pub fn mul(self, a: Word, b: Word) -> Result<Word, Fault> {
+        if self.overflow == Overflow::Wrapping {
+            return Ok(self.width.wrap(i128::from(a.wrapping_mul(b))));
+        }
+        match a.checked_mul(b) {
+            Some(n) if self.width.holds(n) => Ok(n),
+            _ => self.fit(i128::from(a) * i128::from(b)),
+        }
+    }
+
+    /// The result `op` stores for the value `val` and the destination
+    /// `current`. `SHL` overflows like a multiplication by a power of two,
+    /// and `MMULT` and `MDIV` truncate to whole cents.
+    pub fn apply(self, op: Alu, val: Word, current: Word) -> Result<Word, Fault> {
+        let (v, c) = (i128::from(val), i128::from(current));
+        let exact = match op {
+            Alu::Add => return self.add(val, current),
+            Alu::Sub => return self.sub(current, val),
+            Alu::Mult => return self.mul(val, current),
+            Alu::Div | Alu::Mod | Alu::MDiv if v == 0 => return Err(Fault::DivideByZero),
+            Alu::Div => c / v,
+            Alu::Mod => c % v,
+            Alu::And => c & v,
+            Alu::Or => c | v,
+            Alu::Xor => c ^ v,
+            Alu::Not => !v,
+            Alu::Shl => c << self.shift_amount(val)?,
+            Alu::Shr => c >> self.shift_amount(val)?,
+            Alu::Neg => -v,
+            Alu::Abs => v.abs(),
+            Alu::Min => c.min(v),
+            Alu::Max => c.max(v),
+            Alu::MMult => c * v / i128::from(money::SCALE),
+            Alu::MDiv => c * i128::from(money::SCALE) / v,
+        };
+        self.fit(exact)
+    }
+
+    /// Number of bits moved by `SHL` and `SHR`.
+    fn shift_amount(self, val: Word) -> Result<u32, Fault> {
+        if !(0..Word::from(self.width.bits())).contains(&val) {
+            return Err(Fault::Shift(val));
+        }
+        Ok(val as u32)
+    }
+}
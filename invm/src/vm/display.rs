@@ -0,0 +1,153 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::vm::{Condition, FileMode, GeneralRegister, Instruction, Reference, Register, Sensor, Stream, Type};
+
+/// Prints instructions back in the same syntax the parser accepts.
+impl Display for Instruction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::Set(r, v) => write!(f, "SET {r} {v}"),
+            Instruction::Add(v, r) => write!(f, "ADD {v} {r}"),
+            Instruction::Sub(v, r) => write!(f, "SUB {v} {r}"),
+            Instruction::Mult(v, r) => write!(f, "MULT {v} {r}"),
+            Instruction::Div(v, r) => write!(f, "DIV {v} {r}"),
+            Instruction::Mod(v, r) => write!(f, "MOD {v} {r}"),
+            Instruction::And(v, r) => write!(f, "AND {v} {r}"),
+            Instruction::Or(v, r) => write!(f, "OR {v} {r}"),
+            Instruction::Xor(v, r) => write!(f, "XOR {v} {r}"),
+            Instruction::Not(v, r) => write!(f, "NOT {v} {r}"),
+            Instruction::Shl(v, r) => write!(f, "SHL {v} {r}"),
+            Instruction::Shr(v, r) => write!(f, "SHR {v} {r}"),
+            Instruction::Neg(v, r) => write!(f, "NEG {v} {r}"),
+            Instruction::Abs(v, r) => write!(f, "ABS {v} {r}"),
+            Instruction::Min(v, r) => write!(f, "MIN {v} {r}"),
+            Instruction::Max(v, r) => write!(f, "MAX {v} {r}"),
+            Instruction::MMult(v, r) => write!(f, "MMULT {v} {r}"),
+            Instruction::MDiv(v, r) => write!(f, "MDIV {v} {r}"),
+            Instruction::Goto(label) => write!(f, "GOTO ${label}"),
+            Instruction::GoIf(cond, v, Reference::Value(0), label) => write!(f, "GOIF {cond} {v} ${label}"),
+            Instruction::GoIf(cond, a, b, label) => write!(f, "GOIF {cond} {a} {b} ${label}"),
+            Instruction::Print(v, t, s) => write!(f, "PRINT {v} {t}{s}"),
+            Instruction::Put(v, t, s) => write!(f, "PUT {v} {t}{s}"),
+            Instruction::Printf(v, s) => write!(f, "PRINTF {v}{s}"),
+            Instruction::Push(v) => write!(f, "PUSH {v}"),
+            Instruction::Pop(r) => write!(f, "POP {r}"),
+            Instruction::Crash => write!(f, "CRASH"),
+            Instruction::Buy(n) => write!(f, "BUY {n}"),
+            Instruction::Sell(n) => write!(f, "SELL {n}"),
+            Instruction::DeclareLabel(label) => write!(f, "{label}:"),
+            Instruction::Read(r, t) => write!(f, "READ {r} {t}"),
+            Instruction::Nop => write!(f, "NOP"),
+            Instruction::Wait(n) => write!(f, "WAIT {n}"),
+            Instruction::Call(label) => write!(f, "CALL ${label}"),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::Alloc(n, r) => write!(f, "ALLOC {n} {r}"),
+            Instruction::Free(v) => write!(f, "FREE {v}"),
+            Instruction::Enter(words) => write!(f, "ENTER {words}"),
+            Instruction::Leave => write!(f, "LEAVE"),
+            Instruction::Open(name, mode, r) => write!(f, "OPEN {name} {mode} {r}"),
+            Instruction::ReadLine(h, r) => write!(f, "READLINE {h} {r}"),
+            Instruction::Write(h, v) => write!(f, "WRITE {h} {v}"),
+            Instruction::Close(h) => write!(f, "CLOSE {h}"),
+        }
+    }
+}
+
+impl Display for Reference {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Reference::Register(r) => write!(f, "{r}"),
+            Reference::Sensor(s) => write!(f, "{s}"),
+            Reference::Value(n) => write!(f, "{n}"),
+            Reference::Address(g) => write!(f, "*{g}"),
+            Reference::Offset(g, n) if *n < 0 => write!(f, "*{g}{n}"),
+            Reference::Offset(g, n) => write!(f, "*{g}+{n}"),
+            Reference::Sum(a, b) => write!(f, "*({a}+{b})"),
+            Reference::Index(g, i) => write!(f, "*{g}[{i}]"),
+        }
+    }
+}
+
+impl Display for GeneralRegister {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            GeneralRegister::Register(r) => write!(f, "{r}"),
+            GeneralRegister::Sensor(s) => write!(f, "{s}"),
+            GeneralRegister::Value(n) => write!(f, "{n}"),
+        }
+    }
+}
+
+impl Display for Register {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Register::Fund1 => write!(f, "FUND1"),
+            Register::Fund2 => write!(f, "FUND2"),
+            Register::General(n) => write!(f, "R{n}"),
+            Register::Fp => write!(f, "FP"),
+        }
+    }
+}
+
+impl Display for Sensor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Sensor::Shares => "SHARES",
+            Sensor::Stockprice => "STOCKPRICE",
+            Sensor::Reputation => "REPUTATION",
+            Sensor::MarketValue => "MARKETVAL",
+            Sensor::Equity => "EQUITY",
+            Sensor::Owned => "OWNED",
+            Sensor::Balance => "BALANCE",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            Condition::Equals => "==",
+            Condition::Different => "!=",
+            Condition::Greater => ">",
+            Condition::Lesser => "<",
+            Condition::GreaterOrEqual => ">=",
+            Condition::LesserOrEqual => "<=",
+        };
+        write!(f, "{symbol}")
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Type::Int => "int",
+            Type::Bool => "bool",
+            Type::Char => "char",
+            Type::Str => "str",
+            Type::Money => "money",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl Display for FileMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FileMode::Read => "read",
+            FileMode::Write => "write",
+            FileMode::Append => "append",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Written after the operands of a print instruction: nothing for stdout.
+impl Display for Stream {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Stream::Out => Ok(()),
+            Stream::Err => write!(f, " err"),
+        }
+    }
+}
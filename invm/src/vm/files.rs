@@ -0,0 +1,169 @@
+//! Files used by `OPEN`, `READLINE`, `WRITE` and `CLOSE`.
+//!
+//! Programs only see the directory granted with `--files`: names are paths
+//! relative to it, made only of normal components, symbolic links that lead
+//! outside of it are refused, and files are never written through a symbolic
+//! link. Conditions a program can expect, such as a
+//! missing file, are returned as negative statuses instead of faulting.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, ErrorKind, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::vm::{FileMode, Word};
+
+/// Stored by `OPEN` when the file does not exist.
+pub const FILE_NOT_FOUND: Word = -1;
+/// Stored by `OPEN` when the file is outside of the granted directory, no
+/// directory was granted, or the system refuses access.
+pub const FILE_DENIED: Word = -2;
+/// Stored by `OPEN` and `READLINE` on any other error, such as too many open files.
+pub const FILE_ERROR: Word = -3;
+/// Stored by `READLINE` when there are no more lines.
+pub const FILE_END: Word = -4;
+
+/// Most files open at the same time.
+const MAX_OPEN: usize = 64;
+
+enum OpenFile {
+    Read(BufReader<File>),
+    Write(File),
+}
+
+pub struct Files {
+    /// The granted directory, canonical, or `None` if no file may be opened.
+    dir: Option<PathBuf>,
+    /// Open files by handle, starting at 1.
+    open: Vec<Option<OpenFile>>,
+}
+
+impl Files {
+    pub fn new(dir: Option<&Path>) -> Self {
+        let dir = dir.map(|dir| match dir.canonicalize() {
+            Ok(dir) if dir.is_dir() => dir,
+            Ok(_) => panic!("[INVM] {} is not a directory.", dir.display()),
+            Err(e) => panic!("[INVM] Failed to use {} for files: {e}.", dir.display()),
+        });
+        Files { dir, open: vec![] }
+    }
+
+    /// Opens the file `name`, returning its handle or a negative status.
+    pub fn open(&mut self, name: &str, mode: FileMode) -> Word {
+        let (path, exists) = match self.resolve(name, mode) {
+            Ok(resolved) => resolved,
+            Err(status) => return status,
+        };
+        // A file created since `resolve` is not overwritten, and a link put in
+        // place of the checked path is not followed.
+        let mut options = OpenOptions::new();
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::custom_flags(&mut options, libc::O_NOFOLLOW);
+        match mode {
+            FileMode::Read => options.read(true),
+            FileMode::Write => options.write(true).truncate(true),
+            FileMode::Append => options.append(true),
+        };
+        options.create_new(!exists);
+        let file = match options.open(&path) {
+            // Only writing fails on a directory.
+            Ok(file) if file.metadata().is_ok_and(|m| m.is_dir()) => return FILE_ERROR,
+            Ok(file) if mode == FileMode::Read => OpenFile::Read(BufReader::new(file)),
+            Ok(file) => OpenFile::Write(file),
+            Err(e) => return status(&e),
+        };
+
+        let slot = match self.open.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None if self.open.len() < MAX_OPEN => {
+                self.open.push(None);
+                self.open.len() - 1
+            },
+            None => return FILE_ERROR,
+        };
+        self.open[slot] = Some(file);
+        slot as Word + 1
+    }
+
+    /// The path of `name` inside of the granted directory, and whether the file exists.
+    fn resolve(&self, name: &str, mode: FileMode) -> Result<(PathBuf, bool), Word> {
+        let dir = self.dir.as_ref().ok_or(FILE_DENIED)?;
+        let relative = Path::new(name);
+        if name.is_empty() || !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(FILE_DENIED);
+        }
+        let path = dir.join(relative);
+        // A link that leads nowhere yet would create its target wherever it points.
+        if mode != FileMode::Read && path.symlink_metadata().is_ok_and(|m| m.file_type().is_symlink()) {
+            return Err(FILE_DENIED);
+        }
+        // Symbolic links are followed to check where they lead. A file that
+        // does not exist yet is created in the real directory of its parent.
+        let (real, exists) = match path.canonicalize() {
+            Ok(real) => (real, true),
+            Err(e) if e.kind() == ErrorKind::NotFound && mode != FileMode::Read => {
+                let parent = path.parent().unwrap().canonicalize().map_err(|e| status(&e))?;
+                (parent.join(path.file_name().unwrap()), false)
+            },
+            Err(e) => return Err(status(&e)),
+        };
+        if !real.starts_with(dir) {
+            return Err(FILE_DENIED);
+        }
+        Ok((real, exists))
+    }
+
+    /// Reads the next line without its line ending, or returns `FILE_END` or `FILE_ERROR`.
+    pub fn read_line(&mut self, handle: Word) -> Result<String, Word> {
+        let reader = match self.get(handle) {
+            OpenFile::Read(reader) => reader,
+            OpenFile::Write(_) => panic!("[INVM] READLINE on file handle {handle}, which was opened for writing."),
+        };
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) => Err(FILE_END),
+            Ok(_) => {
+                let line = line.strip_suffix('\n').unwrap_or(&line);
+                Ok(line.strip_suffix('\r').unwrap_or(line).to_string())
+            },
+            Err(_) => Err(FILE_ERROR),
+        }
+    }
+
+    pub fn write(&mut self, handle: Word, text: &str) {
+        let file = match self.get(handle) {
+            OpenFile::Write(file) => file,
+            OpenFile::Read(_) => panic!("[INVM] WRITE on file handle {handle}, which was opened for reading."),
+        };
+        if let Err(e) = file.write_all(text.as_bytes()) {
+            panic!("[INVM] Failed to WRITE to file handle {handle}: {e}.");
+        }
+    }
+
+    pub fn close(&mut self, handle: Word) {
+        self.get(handle);
+        self.open[handle as usize - 1] = None;
+    }
+
+    fn get(&mut self, handle: Word) -> &mut OpenFile {
+        let slot = usize::try_from(handle).ok().and_then(|h| h.checked_sub(1));
+        match slot.and_then(|slot| self.open.get_mut(slot)) {
+            Some(Some(file)) => file,
+            _ => panic!("[INVM] File handle {handle} is not open."),
+        }
+    }
+}
+
+fn status(e: &io::Error) -> Word {
+    // Opening a symbolic link without following it.
+    #[cfg(unix)]
+    if e.raw_os_error() == Some(libc::ELOOP) {
+        return FILE_DENIED;
+    }
+    match e.kind() {
+        ErrorKind::NotFound => FILE_NOT_FOUND,
+        ErrorKind::PermissionDenied => FILE_DENIED,
+        _ => FILE_ERROR,
+    }
+}
@@ -0,0 +1,141 @@
+//! The form of a program executed by `VM::step`.
+//!
+//! Every `Instruction` is lowered to exactly one `Op`, so program counters,
+//! coverage and line tables keep working on the same indexes. Ops are `Copy`:
+//! labels are resolved to instruction indexes and nothing is allocated or
+//! hashed while the program runs.
+
+use std::collections::HashMap;
+
+use crate::vm::{arith::Alu, Condition, FileMode, GeneralRegister, Instruction, Reference, Register, Sensor, Stream, Type, Word};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Operand {
+    Register(Register),
+    Sensor(Sensor),
+    Value(Word),
+    Address(Base),
+    Offset(Base, Word),
+    Sum(Base, Base),
+    /// Element of the array at the first base, at the index in the second.
+    Index(Base, Base),
+}
+
+/// The value an `Operand::Address` points with.
+#[derive(Debug, Clone, Copy)]
+pub enum Base {
+    Register(Register),
+    Sensor(Sensor),
+    Value(Word),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Target {
+    /// Index of the `DeclareLabel` instruction of the label.
+    Label(usize),
+    /// A label that was never declared, kept as the index of the jump using it
+    /// so the fault can name it.
+    Unknown(usize),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Op {
+    Set(Operand, Operand),
+    /// An ALU instruction with its value and destination.
+    Alu(Alu, Operand, Operand),
+    Goto(Target),
+    GoIf(Condition, Operand, Operand, Target),
+    Print(Operand, Type, Stream),
+    Put(Operand, Type, Stream),
+    Printf(Operand, Stream),
+    Push(Operand),
+    Pop(Operand),
+    Crash,
+    Buy(Operand),
+    Sell(Operand),
+    Nop,
+    Wait(Operand),
+    Read(Operand, Type),
+    Call(Target),
+    Ret,
+    Alloc(Operand, Operand),
+    Free(Operand),
+    Enter(usize),
+    Leave,
+    Open(Operand, FileMode, Operand),
+    ReadLine(Operand, Operand),
+    Write(Operand, Operand),
+    Close(Operand),
+}
+
+pub fn lower(program: &[Instruction], labels: &HashMap<String, usize>) -> Vec<Op> {
+    program.iter().enumerate().map(|(pc, inst)| {
+        let target = |label: &str| match labels.get(label) {
+            Some(i) => Target::Label(*i),
+            None => Target::Unknown(pc),
+        };
+        match inst {
+            Instruction::Set(r, v) => Op::Set(operand(r), operand(v)),
+            Instruction::Add(v, r) => Op::Alu(Alu::Add, operand(v), operand(r)),
+            Instruction::Sub(v, r) => Op::Alu(Alu::Sub, operand(v), operand(r)),
+            Instruction::Mult(v, r) => Op::Alu(Alu::Mult, operand(v), operand(r)),
+            Instruction::Div(v, r) => Op::Alu(Alu::Div, operand(v), operand(r)),
+            Instruction::Mod(v, r) => Op::Alu(Alu::Mod, operand(v), operand(r)),
+            Instruction::And(v, r) => Op::Alu(Alu::And, operand(v), operand(r)),
+            Instruction::Or(v, r) => Op::Alu(Alu::Or, operand(v), operand(r)),
+            Instruction::Xor(v, r) => Op::Alu(Alu::Xor, operand(v), operand(r)),
+            Instruction::Not(v, r) => Op::Alu(Alu::Not, operand(v), operand(r)),
+            Instruction::Shl(v, r) => Op::Alu(Alu::Shl, operand(v), operand(r)),
+            Instruction::Shr(v, r) => Op::Alu(Alu::Shr, operand(v), operand(r)),
+            Instruction::Neg(v, r) => Op::Alu(Alu::Neg, operand(v), operand(r)),
+            Instruction::Abs(v, r) => Op::Alu(Alu::Abs, operand(v), operand(r)),
+            Instruction::Min(v, r) => Op::Alu(Alu::Min, operand(v), operand(r)),
+            Instruction::Max(v, r) => Op::Alu(Alu::Max, operand(v), operand(r)),
+            Instruction::MMult(v, r) => Op::Alu(Alu::MMult, operand(v), operand(r)),
+            Instruction::MDiv(v, r) => Op::Alu(Alu::MDiv, operand(v), operand(r)),
+            Instruction::Goto(label) => Op::Goto(target(label)),
+            Instruction::GoIf(cond, a, b, label) => Op::GoIf(*cond, operand(a), operand(b), target(label)),
+            Instruction::Print(v, t, s) => Op::Print(operand(v), *t, *s),
+            Instruction::Put(v, t, s) => Op::Put(operand(v), *t, *s),
+            Instruction::Printf(v, s) => Op::Printf(operand(v), *s),
+            Instruction::Push(v) => Op::Push(operand(v)),
+            Instruction::Pop(r) => Op::Pop(operand(r)),
+            Instruction::Crash => Op::Crash,
+            Instruction::Buy(n) => Op::Buy(operand(n)),
+            Instruction::Sell(n) => Op::Sell(operand(n)),
+            Instruction::DeclareLabel(_) | Instruction::Nop => Op::Nop,
+            Instruction::Call(label) => Op::Call(target(label)),
+            Instruction::Ret => Op::Ret,
+            Instruction::Alloc(n, r) => Op::Alloc(operand(n), operand(r)),
+            Instruction::Free(v) => Op::Free(operand(v)),
+            Instruction::Enter(words) => Op::Enter(*words),
+            Instruction::Leave => Op::Leave,
+            Instruction::Open(name, mode, r) => Op::Open(operand(name), *mode, operand(r)),
+            Instruction::ReadLine(h, r) => Op::ReadLine(operand(h), operand(r)),
+            Instruction::Write(h, v) => Op::Write(operand(h), operand(v)),
+            Instruction::Close(h) => Op::Close(operand(h)),
+            Instruction::Wait(n) => Op::Wait(operand(n)),
+            Instruction::Read(r, t) => Op::Read(operand(r), *t),
+        }
+    }).collect()
+}
+
+fn operand(r: &Reference) -> Operand {
+    match r {
+        Reference::Register(r) => Operand::Register(*r),
+        Reference::Sensor(s) => Operand::Sensor(*s),
+        Reference::Value(n) => Operand::Value(*n),
+        Reference::Address(g) => Operand::Address(base(g)),
+        Reference::Offset(g, n) => Operand::Offset(base(g), *n),
+        Reference::Sum(a, b) => Operand::Sum(base(a), base(b)),
+        Reference::Index(g, i) => Operand::Index(base(g), base(i)),
+    }
+}
+
+fn base(g: &GeneralRegister) -> Base {
+    match g {
+        GeneralRegister::Register(r) => Base::Register(*r),
+        GeneralRegister::Sensor(s) => Base::Sensor(*s),
+        GeneralRegister::Value(n) => Base::Value(*n),
+    }
+}
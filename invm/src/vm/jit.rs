@@ -0,0 +1,902 @@
+//! Compiles the lowered program (see `ir`) to native code with Cranelift.
+//!
+//! Control flow, registers and arithmetic run natively. Everything else calls
+//! back into the `VM` that owns the program, so the market simulation, memory
+//! and I/O behave exactly as in the interpreter. Panics inside callbacks are
+//! caught and stored, the compiled code returns, and the panic is resumed on
+//! the Rust side, since unwinding through compiled frames is not possible.
+
+use std::{
+    any::Any,
+    env,
+    ffi::OsStr,
+    io::{self, Read, Write},
+    panic::{self, AssertUnwindSafe},
+    process::{self, Command, Output, Stdio},
+};
+
+use cranelift_codegen::{
+    ir::{condcodes::IntCC, types, AbiParam, Block, FuncRef, InstBuilder, JumpTableData, StackSlot, StackSlotData, StackSlotKind, Value},
+    settings::{self, Configurable},
+};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Linkage, Module};
+
+use crate::{parser::Program, vm::{arith::{Alu, Arith, Overflow, Width}, Condition, Config, FileMode, Register, Sensor, Stream, Type, VM, Word, ir::{Base, Op, Operand, Target}}};
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+compile_error!("The jit feature is only supported on x86-64 Linux.");
+
+const TYPES: [Type; 5] = [Type::Int, Type::Bool, Type::Char, Type::Str, Type::Money];
+const STREAMS: [Stream; 2] = [Stream::Out, Stream::Err];
+const MODES: [FileMode; 3] = [FileMode::Read, FileMode::Write, FileMode::Append];
+
+struct Runtime {
+    vm: VM,
+    fault: Option<Box<dyn Any + Send>>,
+}
+
+/// Compiles and runs the program.
+pub fn run(program: &Program, config: &Config) {
+    let vm = VM::new(program, config);
+    let compiled = Compiled::new(&vm.code, vm.arith);
+    let mut rt = Box::new(Runtime { vm, fault: None });
+
+    // SAFETY: the code was generated for this signature and only passes the
+    // runtime pointer back to the callbacks below.
+    let entry: extern "C" fn(*mut Runtime) -> i32 = unsafe { std::mem::transmute(compiled.entry) };
+    entry(&mut *rt);
+
+    if let Some(fault) = rt.fault.take() {
+        rt.vm.report_calls();
+        rt.vm.report_frames();
+        panic::resume_unwind(fault);
+    }
+    if config.heap_debug {
+        rt.vm.report_leaks();
+    }
+}
+
+/// Runs the program with the interpreter and with the JIT, using the same seed
+/// and input, and compares the output and the fault of both runs. Both runs
+/// optimise the program the same way, with `-O` and `--keep-ticks`.
+pub fn diff(filepath: &str, config: &Config, optimize: bool, keep_ticks: bool) {
+    let seed = config.seed.unwrap_or_else(rand::random);
+    let mut input = vec![];
+    io::stdin().read_to_end(&mut input).expect("[JIT] Failed to read input.");
+
+    let run = |command: &str| -> Output {
+        let exe = env::current_exe().expect("[JIT] Failed to locate invm.");
+        let mut child = Command::new(exe)
+            .args([command, filepath, "--seed", &seed.to_string()])
+            .args(["--memory", &config.memory.size.to_string(), "--data", &config.memory.data.to_string()])
+            .args(["--call-limit", &config.call_limit.to_string(), "--registers", &config.registers.to_string()])
+            .args(config.heap_debug.then_some("--heap-debug"))
+            .args(config.trace.then_some("--trace"))
+            .args(optimize.then_some("-O"))
+            .args(keep_ticks.then_some("--keep-ticks"))
+            .args(config.files.iter().flat_map(|dir| [OsStr::new("--files"), dir.as_os_str()]))
+            .env("RUST_BACKTRACE", "0")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("[JIT] Failed to start invm.");
+        // The program may exit before reading all of its input.
+        let _ = child.stdin.take().unwrap().write_all(&input);
+        child.wait_with_output().expect("[JIT] Failed to wait for invm.")
+    };
+    let interpreted = run("run");
+    let compiled = run("jit");
+
+    let mut equal = true;
+    if interpreted.stdout != compiled.stdout {
+        equal = false;
+        println!("[JIT] Output differs.");
+        println!("--- interpreter\n{}", String::from_utf8_lossy(&interpreted.stdout));
+        println!("--- jit\n{}", String::from_utf8_lossy(&compiled.stdout));
+    }
+    let (fault_i, fault_c) = (fault_message(&interpreted), fault_message(&compiled));
+    if interpreted.status.code() != compiled.status.code() || fault_i != fault_c {
+        equal = false;
+        println!("[JIT] Exit differs.");
+        println!("--- interpreter: {:?} {}", interpreted.status.code(), fault_i.unwrap_or_default());
+        println!("--- jit: {:?} {}", compiled.status.code(), fault_c.unwrap_or_default());
+    }
+
+    if !equal {
+        println!("[JIT] Mismatch with seed {seed}.");
+        process::exit(1);
+    }
+    println!("[JIT] Interpreter and JIT agree with seed {seed}.");
+}
+
+/// The panic message of a faulted run, without the thread name and location.
+fn fault_message(output: &Output) -> Option<String> {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut lines = stderr.lines().skip_while(|l| !l.contains("panicked at"));
+    lines.next()?;
+    lines.next().map(str::to_string)
+}
+
+/// Runs `f` on the VM, storing its panic if it faults.
+fn guard<T>(rt: *mut Runtime, f: impl FnOnce(&mut VM) -> T) -> Option<T> {
+    // SAFETY: compiled code only passes back the pointer given to it in `run`.
+    let rt = unsafe { &mut *rt };
+    match panic::catch_unwind(AssertUnwindSafe(|| f(&mut rt.vm))) {
+        Ok(v) => Some(v),
+        Err(fault) => {
+            rt.fault = Some(fault);
+            None
+        }
+    }
+}
+
+fn status(result: Option<()>) -> i32 {
+    match result {
+        Some(()) => 0,
+        None => 1,
+    }
+}
+
+/// Stores the result of a value callback in `out`. Any word is a valid
+/// result, so faults are reported through the status like the other callbacks.
+fn value(result: Option<Word>, out: *mut Word) -> i32 {
+    match result {
+        Some(v) => {
+            // SAFETY: compiled code passes a stack slot of the translated function.
+            unsafe { *out = v };
+            0
+        },
+        None => 1,
+    }
+}
+
+extern "C" fn invm_simulate(rt: *mut Runtime) -> i32 {
+    status(guard(rt, |vm| vm.simulate()))
+}
+
+extern "C" fn invm_sensor(rt: *mut Runtime, s: i32) -> Word {
+    // SAFETY: see `guard`.
+    unsafe { &*rt }.vm.expect_sensor_value(&Sensor::ALL[s as usize])
+}
+
+/// The base and offset are added exactly, so that offsets and sums fault at
+/// the address they reach.
+extern "C" fn invm_load(rt: *mut Runtime, base: Word, offset: Word, out: *mut Word) -> i32 {
+    value(guard(rt, |vm| vm.memory.get(vm.to_address(i128::from(base) + i128::from(offset)))), out)
+}
+
+extern "C" fn invm_store(rt: *mut Runtime, base: Word, offset: Word, val: Word) -> i32 {
+    status(guard(rt, |vm| {
+        let addr = vm.to_address(i128::from(base) + i128::from(offset));
+        vm.memory.set(addr, val)
+    }))
+}
+
+extern "C" fn invm_push(rt: *mut Runtime, val: Word) -> i32 {
+    status(guard(rt, |vm| vm.memory.push(val)))
+}
+
+extern "C" fn invm_pop(rt: *mut Runtime, out: *mut Word) -> i32 {
+    value(guard(rt, |vm| vm.memory.pop()), out)
+}
+
+/// `PRINT` when `newline` is 1 and `PUT` when it is 0.
+extern "C" fn invm_print(rt: *mut Runtime, val: Word, t: i32, stream: i32, newline: i32) -> i32 {
+    status(guard(rt, |vm| vm.print(Operand::Value(val), TYPES[t as usize], STREAMS[stream as usize], newline != 0)))
+}
+
+extern "C" fn invm_printf(rt: *mut Runtime, format: Word, stream: i32) -> i32 {
+    status(guard(rt, |vm| vm.printf(Operand::Value(format), STREAMS[stream as usize])))
+}
+
+/// `pc` is the `READ`, which allocates strings on the heap.
+extern "C" fn invm_read(rt: *mut Runtime, pc: i32, t: i32, out: *mut Word) -> i32 {
+    value(guard(rt, |vm| {
+        vm.pc = pc as usize;
+        vm.read_value(&TYPES[t as usize])
+    }), out)
+}
+
+extern "C" fn invm_alloc(rt: *mut Runtime, pc: i32, words: Word, out: *mut Word) -> i32 {
+    value(guard(rt, |vm| {
+        vm.pc = pc as usize;
+        vm.allocate(words)
+    }), out)
+}
+
+extern "C" fn invm_open(rt: *mut Runtime, name: Word, mode: i32, out: *mut Word) -> i32 {
+    value(guard(rt, |vm| vm.open_file(Operand::Value(name), MODES[mode as usize])), out)
+}
+
+/// `pc` is the `READLINE`, which allocates lines on the heap.
+extern "C" fn invm_readline(rt: *mut Runtime, pc: i32, handle: Word, out: *mut Word) -> i32 {
+    value(guard(rt, |vm| {
+        vm.pc = pc as usize;
+        vm.read_file_line(Operand::Value(handle))
+    }), out)
+}
+
+extern "C" fn invm_write(rt: *mut Runtime, handle: Word, text: Word) -> i32 {
+    status(guard(rt, |vm| vm.write_file(Operand::Value(handle), Operand::Value(text))))
+}
+
+extern "C" fn invm_close(rt: *mut Runtime, handle: Word) -> i32 {
+    status(guard(rt, |vm| vm.close(Operand::Value(handle))))
+}
+
+/// `pc` is the `WAIT`, shown in its trace.
+extern "C" fn invm_wait(rt: *mut Runtime, pc: i32, ticks: Word) -> i32 {
+    status(guard(rt, |vm| {
+        vm.pc = pc as usize;
+        vm.wait_ticks(ticks)
+    }))
+}
+
+/// `FP` stays in the VM, so that a fault can show the frames.
+extern "C" fn invm_fp(rt: *mut Runtime) -> Word {
+    // SAFETY: see `guard`.
+    unsafe { &*rt }.vm.expect_register_value(&Register::Fp)
+}
+
+extern "C" fn invm_set_fp(rt: *mut Runtime, val: Word) -> i32 {
+    status(guard(rt, |vm| vm.registers[Register::Fp.index()] = Some(val)))
+}
+
+extern "C" fn invm_enter(rt: *mut Runtime, words: i32) -> i32 {
+    status(guard(rt, |vm| vm.enter_frame(words as usize)))
+}
+
+extern "C" fn invm_leave(rt: *mut Runtime) -> i32 {
+    status(guard(rt, |vm| vm.leave_frame()))
+}
+
+/// The address of an element of an array, checked against its length.
+extern "C" fn invm_element(rt: *mut Runtime, base: Word, i: Word, out: *mut Word) -> i32 {
+    value(guard(rt, |vm| vm.element(base, i) as Word), out)
+}
+
+extern "C" fn invm_free(rt: *mut Runtime, addr: Word) -> i32 {
+    status(guard(rt, |vm| vm.free(Operand::Value(addr))))
+}
+
+extern "C" fn invm_buy(rt: *mut Runtime, amount: Word) -> i32 {
+    status(guard(rt, |vm| vm.buy(Operand::Value(amount))))
+}
+
+extern "C" fn invm_sell(rt: *mut Runtime, amount: Word) -> i32 {
+    status(guard(rt, |vm| vm.sell(Operand::Value(amount))))
+}
+
+/// ALU instructions that are not compiled inline, and the overflowing results
+/// of the ones that are. `op` is the index of the operation in `Alu::ALL`.
+extern "C" fn invm_arith(rt: *mut Runtime, op: i32, val: Word, current: Word, out: *mut Word) -> i32 {
+    value(guard(rt, |vm| vm.compute(Alu::ALL[op as usize], val, current)), out)
+}
+
+/// Registers live in compiled code, so the VM's register map stays empty and
+/// reading from it raises the uninitialized register fault.
+extern "C" fn invm_uninit(rt: *mut Runtime, r: i32) -> i32 {
+    status(guard(rt, |vm| {
+        vm.expect_register_value(&Register::from_index(r as usize).unwrap());
+    }))
+}
+
+extern "C" fn invm_call(rt: *mut Runtime, pc: i32) -> i32 {
+    status(guard(rt, |vm| vm.enter(pc as usize)))
+}
+
+/// Returns the index of the `CALL` to go back to.
+extern "C" fn invm_ret(rt: *mut Runtime, out: *mut Word) -> i32 {
+    value(guard(rt, |vm| {
+        vm.ret();
+        vm.pc as Word
+    }), out)
+}
+
+extern "C" fn invm_unknown_label(rt: *mut Runtime, pc: i32) -> i32 {
+    status(guard(rt, |vm| vm.goto(Target::Unknown(pc as usize))))
+}
+
+struct Compiled {
+    /// Keeps the compiled code alive.
+    _module: JITModule,
+    entry: *const u8,
+}
+
+impl Compiled {
+    fn new(code: &[Op], arith: Arith) -> Self {
+        let mut flags = settings::builder();
+        flags.set("opt_level", "speed").unwrap();
+        let isa = cranelift_native::builder()
+            .unwrap_or_else(|e| panic!("[JIT] Unsupported host: {e}."))
+            .finish(settings::Flags::new(flags))
+            .expect("[JIT] Failed to configure the code generator.");
+
+        let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+        for (name, ptr) in Callbacks::symbols() {
+            builder.symbol(name, ptr);
+        }
+        let mut module = JITModule::new(builder);
+
+        let mut ctx = module.make_context();
+        ctx.func.signature.params.push(AbiParam::new(types::I64));
+        ctx.func.signature.returns.push(AbiParam::new(types::I32));
+        let id = module
+            .declare_function("invm_main", Linkage::Export, &ctx.func.signature)
+            .expect("[JIT] Failed to declare the program.");
+
+        let mut fctx = FunctionBuilderContext::new();
+        let builder = FunctionBuilder::new(&mut ctx.func, &mut fctx);
+        let callbacks = Callbacks::declare(&mut module, builder.func);
+        Translator::new(builder, callbacks, arith).translate(code);
+
+        module
+            .define_function(id, &mut ctx)
+            .unwrap_or_else(|e| panic!("[JIT] Failed to compile the program: {e:?}."));
+        module.clear_context(&mut ctx);
+        module.finalize_definitions().expect("[JIT] Failed to finalize the program.");
+        let entry = module.get_finalized_function(id);
+
+        Compiled { _module: module, entry }
+    }
+}
+
+struct Callbacks {
+    simulate: FuncRef,
+    sensor: FuncRef,
+    load: FuncRef,
+    store: FuncRef,
+    push: FuncRef,
+    pop: FuncRef,
+    print: FuncRef,
+    printf: FuncRef,
+    read: FuncRef,
+    buy: FuncRef,
+    sell: FuncRef,
+    arith: FuncRef,
+    uninit: FuncRef,
+    unknown_label: FuncRef,
+    call: FuncRef,
+    ret: FuncRef,
+    alloc: FuncRef,
+    free: FuncRef,
+    element: FuncRef,
+    fp: FuncRef,
+    set_fp: FuncRef,
+    enter: FuncRef,
+    leave: FuncRef,
+    open: FuncRef,
+    readline: FuncRef,
+    write: FuncRef,
+    close: FuncRef,
+    wait: FuncRef,
+}
+
+impl Callbacks {
+    fn symbols() -> Vec<(&'static str, *const u8)> {
+        vec![
+            ("invm_simulate", invm_simulate as *const u8),
+            ("invm_sensor", invm_sensor as *const u8),
+            ("invm_load", invm_load as *const u8),
+            ("invm_store", invm_store as *const u8),
+            ("invm_push", invm_push as *const u8),
+            ("invm_pop", invm_pop as *const u8),
+            ("invm_print", invm_print as *const u8),
+            ("invm_printf", invm_printf as *const u8),
+            ("invm_read", invm_read as *const u8),
+            ("invm_buy", invm_buy as *const u8),
+            ("invm_sell", invm_sell as *const u8),
+            ("invm_arith", invm_arith as *const u8),
+            ("invm_uninit", invm_uninit as *const u8),
+            ("invm_unknown_label", invm_unknown_label as *const u8),
+            ("invm_call", invm_call as *const u8),
+            ("invm_ret", invm_ret as *const u8),
+            ("invm_alloc", invm_alloc as *const u8),
+            ("invm_free", invm_free as *const u8),
+            ("invm_element", invm_element as *const u8),
+            ("invm_fp", invm_fp as *const u8),
+            ("invm_set_fp", invm_set_fp as *const u8),
+            ("invm_enter", invm_enter as *const u8),
+            ("invm_leave", invm_leave as *const u8),
+            ("invm_open", invm_open as *const u8),
+            ("invm_readline", invm_readline as *const u8),
+            ("invm_write", invm_write as *const u8),
+            ("invm_close", invm_close as *const u8),
+            ("invm_wait", invm_wait as *const u8),
+        ]
+    }
+
+    fn declare(module: &mut JITModule, func: &mut cranelift_codegen::ir::Function) -> Self {
+        use types::{I32, I64};
+        let mut import = |name: &str, params: &[types::Type], ret: types::Type| {
+            let mut sig = module.make_signature();
+            sig.params.push(AbiParam::new(I64));
+            for param in params {
+                sig.params.push(AbiParam::new(*param));
+            }
+            sig.returns.push(AbiParam::new(ret));
+            let id = module
+                .declare_function(name, Linkage::Import, &sig)
+                .expect("[JIT] Failed to declare callback.");
+            module.declare_func_in_func(id, func)
+        };
+
+        Callbacks {
+            simulate: import("invm_simulate", &[], I32),
+            sensor: import("invm_sensor", &[I32], I64),
+            load: import("invm_load", &[I64, I64, I64], I32),
+            store: import("invm_store", &[I64, I64, I64], I32),
+            push: import("invm_push", &[I64], I32),
+            pop: import("invm_pop", &[I64], I32),
+            print: import("invm_print", &[I64, I32, I32, I32], I32),
+            printf: import("invm_printf", &[I64, I32], I32),
+            read: import("invm_read", &[I32, I32, I64], I32),
+            buy: import("invm_buy", &[I64], I32),
+            sell: import("invm_sell", &[I64], I32),
+            arith: import("invm_arith", &[I32, I64, I64, I64], I32),
+            uninit: import("invm_uninit", &[I32], I32),
+            unknown_label: import("invm_unknown_label", &[I32], I32),
+            call: import("invm_call", &[I32], I32),
+            ret: import("invm_ret", &[I64], I32),
+            alloc: import("invm_alloc", &[I32, I64, I64], I32),
+            free: import("invm_free", &[I64], I32),
+            element: import("invm_element", &[I64, I64, I64], I32),
+            fp: import("invm_fp", &[], I64),
+            set_fp: import("invm_set_fp", &[I64], I32),
+            enter: import("invm_enter", &[I32], I32),
+            leave: import("invm_leave", &[], I32),
+            open: import("invm_open", &[I64, I32, I64], I32),
+            readline: import("invm_readline", &[I32, I64, I64], I32),
+            write: import("invm_write", &[I64, I64], I32),
+            close: import("invm_close", &[I64], I32),
+            wait: import("invm_wait", &[I32, I64], I32),
+        }
+    }
+}
+
+struct Translator<'a> {
+    builder: FunctionBuilder<'a>,
+    callbacks: Callbacks,
+    rt: Value,
+    /// One block per instruction, plus one for running off the end.
+    blocks: Vec<Block>,
+    /// Takes the index of a `CALL` and continues after it. Shared by every `RET`.
+    returns: Block,
+    fault: Block,
+    /// Where value callbacks store their result.
+    out: StackSlot,
+    arith: Arith,
+}
+
+impl<'a> Translator<'a> {
+    fn new(mut builder: FunctionBuilder<'a>, callbacks: Callbacks, arith: Arith) -> Self {
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        let rt = builder.block_params(entry)[0];
+        let fault = builder.create_block();
+        let returns = builder.create_block();
+        builder.append_block_param(returns, types::I32);
+        let out = builder.create_sized_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, 8, 3));
+
+        Translator { builder, callbacks, rt, blocks: vec![], returns, fault, out, arith }
+    }
+
+    fn value_var(r: usize) -> Variable {
+        Variable::from_u32(2 * r as u32)
+    }
+
+    fn init_var(r: usize) -> Variable {
+        Variable::from_u32(2 * r as u32 + 1)
+    }
+
+    fn translate(mut self, code: &[Op]) {
+        for r in 0..Register::COUNT {
+            self.builder.declare_var(Self::value_var(r), types::I64);
+            self.builder.declare_var(Self::init_var(r), types::I8);
+            let zero = self.builder.ins().iconst(types::I64, 0);
+            self.builder.def_var(Self::value_var(r), zero);
+            let uninit = self.builder.ins().iconst(types::I8, 0);
+            self.builder.def_var(Self::init_var(r), uninit);
+        }
+
+        self.blocks = (0..=code.len()).map(|_| self.builder.create_block()).collect();
+        self.builder.ins().jump(self.blocks[0], &[]);
+
+        for (pc, op) in code.iter().enumerate() {
+            self.builder.switch_to_block(self.blocks[pc]);
+            self.op(pc, *op);
+        }
+
+        self.builder.switch_to_block(self.blocks[code.len()]);
+        let ok = self.builder.ins().iconst(types::I32, 0);
+        self.builder.ins().return_(&[ok]);
+
+        self.builder.switch_to_block(self.returns);
+        let site = self.builder.block_params(self.returns)[0];
+        let fault = self.builder.func.dfg.block_call(self.fault, &[]);
+        let table: Vec<_> = code.iter().enumerate().map(|(pc, op)| match op {
+            Op::Call(_) => self.builder.func.dfg.block_call(self.blocks[pc + 1], &[]),
+            _ => fault,
+        }).collect();
+        let table = self.builder.create_jump_table(JumpTableData::new(fault, &table));
+        self.builder.ins().br_table(site, table);
+
+        self.builder.switch_to_block(self.fault);
+        let fault = self.builder.ins().iconst(types::I32, 1);
+        self.builder.ins().return_(&[fault]);
+
+        self.builder.seal_all_blocks();
+        self.builder.finalize();
+    }
+
+    fn op(&mut self, pc: usize, op: Op) {
+        match op {
+            Op::Set(r, v) => {
+                let val = self.read(v);
+                self.write(r, val);
+            },
+            Op::Alu(op, v, r) => self.alu(op, v, r),
+            Op::Goto(target) => {
+                self.simulate();
+                self.jump(pc, target);
+                return;
+            },
+            Op::GoIf(cond, a, b, target) => {
+                let val = self.read(a);
+                let other = self.read(b);
+                let cc = match cond {
+                    Condition::Equals => IntCC::Equal,
+                    Condition::Different => IntCC::NotEqual,
+                    Condition::Greater => IntCC::SignedGreaterThan,
+                    Condition::Lesser => IntCC::SignedLessThan,
+                    Condition::GreaterOrEqual => IntCC::SignedGreaterThanOrEqual,
+                    Condition::LesserOrEqual => IntCC::SignedLessThanOrEqual,
+                };
+                let c = self.builder.ins().icmp(cc, val, other);
+                let taken = self.builder.create_block();
+                let not_taken = self.builder.create_block();
+                self.builder.ins().brif(c, taken, &[], not_taken, &[]);
+
+                self.builder.switch_to_block(taken);
+                // An unknown label faults before the tick, as in `VM::go_if`.
+                if let Target::Label(_) = target {
+                    self.simulate();
+                }
+                self.jump(pc, target);
+                self.builder.switch_to_block(not_taken);
+            },
+            Op::Print(v, t, stream) | Op::Put(v, t, stream) => {
+                let val = self.read(v);
+                let t = self.builder.ins().iconst(types::I32, type_index(t));
+                let stream = self.builder.ins().iconst(types::I32, stream as i64);
+                let newline = self.builder.ins().iconst(types::I32, matches!(op, Op::Print(..)) as i64);
+                let args = [self.rt, val, t, stream, newline];
+                self.call_status(self.callbacks.print, &args);
+            },
+            Op::Printf(v, stream) => {
+                let format = self.read(v);
+                let stream = self.builder.ins().iconst(types::I32, stream as i64);
+                let args = [self.rt, format, stream];
+                self.call_status(self.callbacks.printf, &args);
+            },
+            Op::Push(v) => {
+                let val = self.read(v);
+                let args = [self.rt, val];
+                self.call_status(self.callbacks.push, &args);
+            },
+            Op::Pop(r) => {
+                let args = [self.rt];
+                let val = self.call_value(self.callbacks.pop, &args);
+                self.write(r, val);
+            },
+            Op::Crash => {
+                self.simulate();
+                let ok = self.builder.ins().iconst(types::I32, 0);
+                self.builder.ins().return_(&[ok]);
+                return;
+            },
+            Op::Buy(amount) | Op::Sell(amount) => {
+                let callback = match op {
+                    Op::Buy(_) => self.callbacks.buy,
+                    _ => self.callbacks.sell,
+                };
+                let amount = self.read(amount);
+                let args = [self.rt, amount];
+                self.call_status(callback, &args);
+            },
+            Op::Nop => (),
+            Op::Wait(n) => {
+                let ticks = self.read(n);
+                let site = self.builder.ins().iconst(types::I32, pc as i64);
+                let args = [self.rt, site, ticks];
+                self.call_status(self.callbacks.wait, &args);
+                self.builder.ins().jump(self.blocks[pc + 1], &[]);
+                return;
+            },
+            Op::Read(r, t) => {
+                let site = self.builder.ins().iconst(types::I32, pc as i64);
+                let t = self.builder.ins().iconst(types::I32, type_index(t));
+                let args = [self.rt, site, t];
+                let val = self.call_value(self.callbacks.read, &args);
+                self.write(r, val);
+            },
+            Op::Alloc(n, r) => {
+                let words = self.read(n);
+                let site = self.builder.ins().iconst(types::I32, pc as i64);
+                let args = [self.rt, site, words];
+                let addr = self.call_value(self.callbacks.alloc, &args);
+                self.write(r, addr);
+            },
+            Op::Free(v) => {
+                let addr = self.read(v);
+                let args = [self.rt, addr];
+                self.call_status(self.callbacks.free, &args);
+            },
+            Op::Enter(words) => {
+                let words = self.builder.ins().iconst(types::I32, words as i64);
+                let args = [self.rt, words];
+                self.call_status(self.callbacks.enter, &args);
+            },
+            Op::Leave => {
+                let args = [self.rt];
+                self.call_status(self.callbacks.leave, &args);
+            },
+            Op::Open(name, mode, r) => {
+                let name = self.read(name);
+                let mode = self.builder.ins().iconst(types::I32, mode as i64);
+                let args = [self.rt, name, mode];
+                let handle = self.call_value(self.callbacks.open, &args);
+                self.write(r, handle);
+            },
+            Op::ReadLine(h, r) => {
+                let handle = self.read(h);
+                let site = self.builder.ins().iconst(types::I32, pc as i64);
+                let args = [self.rt, site, handle];
+                let val = self.call_value(self.callbacks.readline, &args);
+                self.write(r, val);
+            },
+            Op::Write(h, v) => {
+                let handle = self.read(h);
+                let text = self.read(v);
+                let args = [self.rt, handle, text];
+                self.call_status(self.callbacks.write, &args);
+            },
+            Op::Close(h) => {
+                let handle = self.read(h);
+                let args = [self.rt, handle];
+                self.call_status(self.callbacks.close, &args);
+            },
+            Op::Call(target) => {
+                let site = self.builder.ins().iconst(types::I32, pc as i64);
+                let args = [self.rt, site];
+                self.call_status(self.callbacks.call, &args);
+                if let Target::Label(_) = target {
+                    self.simulate();
+                }
+                self.jump(pc, target);
+                return;
+            },
+            Op::Ret => {
+                let args = [self.rt];
+                let site = self.call_value(self.callbacks.ret, &args);
+                let site = self.builder.ins().ireduce(types::I32, site);
+                self.simulate();
+                self.builder.ins().jump(self.returns, &[site]);
+                return;
+            },
+        }
+        self.simulate();
+        self.builder.ins().jump(self.blocks[pc + 1], &[]);
+    }
+
+    /// Jumps past the declaration of the label, like `VM::goto` followed by the
+    /// increment of `pc`.
+    fn jump(&mut self, pc: usize, target: Target) {
+        match target {
+            Target::Label(label) => {
+                self.builder.ins().jump(self.blocks[label + 1], &[]);
+            },
+            Target::Unknown(_) => {
+                let pc = self.builder.ins().iconst(types::I32, pc as i64);
+                let args = [self.rt, pc];
+                self.call_status(self.callbacks.unknown_label, &args);
+                self.builder.ins().jump(self.fault, &[]);
+            },
+        }
+    }
+
+    fn simulate(&mut self) {
+        let args = [self.rt];
+        self.call_status(self.callbacks.simulate, &args);
+    }
+
+    /// Calls a callback returning a status, leaving the function if it faulted.
+    fn call_status(&mut self, callback: FuncRef, args: &[Value]) {
+        let call = self.builder.ins().call(callback, args);
+        let status = self.builder.inst_results(call)[0];
+        let next = self.builder.create_block();
+        self.builder.ins().brif(status, self.fault, &[], next, &[]);
+        self.builder.switch_to_block(next);
+    }
+
+    /// Calls a callback storing a value, leaving the function if it faulted.
+    fn call_value(&mut self, callback: FuncRef, args: &[Value]) -> Value {
+        let out = self.builder.ins().stack_addr(types::I64, self.out, 0);
+        let args: Vec<Value> = args.iter().copied().chain([out]).collect();
+        self.call_status(callback, &args);
+        self.builder.ins().stack_load(types::I64, self.out, 0)
+    }
+
+    fn register(&mut self, r: Register) -> Value {
+        if r == Register::Fp {
+            let call = self.builder.ins().call(self.callbacks.fp, &[self.rt]);
+            return self.builder.inst_results(call)[0];
+        }
+        let index = r.index();
+        let init = self.builder.use_var(Self::init_var(index));
+        let ok = self.builder.create_block();
+        let uninit = self.builder.create_block();
+        self.builder.ins().brif(init, ok, &[], uninit, &[]);
+
+        self.builder.switch_to_block(uninit);
+        let r = self.builder.ins().iconst(types::I32, index as i64);
+        let args = [self.rt, r];
+        self.builder.ins().call(self.callbacks.uninit, &args);
+        self.builder.ins().jump(self.fault, &[]);
+
+        self.builder.switch_to_block(ok);
+        self.builder.use_var(Self::value_var(index))
+    }
+
+    fn sensor(&mut self, s: Sensor) -> Value {
+        let s = self.builder.ins().iconst(types::I32, s.index() as i64);
+        let call = self.builder.ins().call(self.callbacks.sensor, &[self.rt, s]);
+        self.builder.inst_results(call)[0]
+    }
+
+    fn base(&mut self, b: Base) -> Value {
+        match b {
+            Base::Register(r) => self.register(r),
+            Base::Sensor(s) => self.sensor(s),
+            Base::Value(n) => self.builder.ins().iconst(types::I64, n),
+        }
+    }
+
+    fn read(&mut self, r: Operand) -> Value {
+        match r {
+            Operand::Register(r) => self.register(r),
+            Operand::Sensor(s) => self.sensor(s),
+            Operand::Value(n) => self.builder.ins().iconst(types::I64, n),
+            _ => {
+                let (base, offset) = self.address(r);
+                let args = [self.rt, base, offset];
+                self.call_value(self.callbacks.load, &args)
+            },
+        }
+    }
+
+    /// The address of a memory operand as a base and an offset, which the
+    /// callbacks add exactly, computed in the same order as `VM::address`.
+    fn address(&mut self, r: Operand) -> (Value, Value) {
+        match r {
+            Operand::Address(b) => {
+                let base = self.base(b);
+                (base, self.builder.ins().iconst(types::I64, 0))
+            },
+            Operand::Offset(b, n) => {
+                let base = self.base(b);
+                (base, self.builder.ins().iconst(types::I64, n))
+            },
+            Operand::Sum(a, b) => {
+                let a = self.base(a);
+                let b = self.base(b);
+                (a, b)
+            },
+            Operand::Index(b, i) => {
+                let base = self.base(b);
+                let i = self.base(i);
+                let args = [self.rt, base, i];
+                let addr = self.call_value(self.callbacks.element, &args);
+                (addr, self.builder.ins().iconst(types::I64, 0))
+            },
+            _ => unreachable!("[JIT] {r:?} is not a memory operand."),
+        }
+    }
+
+    fn write(&mut self, r: Operand, val: Value) {
+        match r {
+            Operand::Register(Register::Fp) => {
+                let args = [self.rt, val];
+                self.call_status(self.callbacks.set_fp, &args);
+            },
+            Operand::Register(r) => {
+                let index = r.index();
+                self.builder.def_var(Self::value_var(index), val);
+                let init = self.builder.ins().iconst(types::I8, 1);
+                self.builder.def_var(Self::init_var(index), init);
+            },
+            Operand::Sensor(_) | Operand::Value(_) => panic!("[JIT] Unable to modify readonly value {r:?}."),
+            _ => {
+                let (base, offset) = self.address(r);
+                let args = [self.rt, base, offset, val];
+                self.call_status(self.callbacks.store, &args);
+            },
+        }
+    }
+
+    /// Bitwise operations, `MIN` and `MAX` cannot overflow and run inline.
+    /// `ADD`, `SUB` and `MULT` run inline in the width of a word and only call
+    /// back when the result overflows and the program does not wrap. The rest
+    /// call back into the VM.
+    fn alu(&mut self, op: Alu, v: Operand, r: Operand) {
+        let val = self.read(v);
+        let current = if op.is_unary() { val } else { self.read(r) };
+        let res = match op {
+            Alu::And => self.builder.ins().band(current, val),
+            Alu::Or => self.builder.ins().bor(current, val),
+            Alu::Xor => self.builder.ins().bxor(current, val),
+            Alu::Min => self.builder.ins().smin(current, val),
+            Alu::Max => self.builder.ins().smax(current, val),
+            Alu::Not => self.builder.ins().bnot(val),
+            Alu::Add | Alu::Sub | Alu::Mult => self.checked(op, val, current),
+            _ => self.arith(op, val, current),
+        };
+        self.write(r, res);
+    }
+
+    /// `ADD`, `SUB` or `MULT` in the width of a word, sign-extended back to an i64.
+    fn checked(&mut self, op: Alu, val: Value, current: Value) -> Value {
+        let (a, b) = match self.arith.width {
+            Width::W32 => (self.builder.ins().ireduce(types::I32, val), self.builder.ins().ireduce(types::I32, current)),
+            Width::W64 => (val, current),
+        };
+        let extend = |t: &mut Self, res: Value| match t.arith.width {
+            Width::W32 => t.builder.ins().sextend(types::I64, res),
+            Width::W64 => res,
+        };
+        if self.arith.overflow == Overflow::Wrapping {
+            let res = match op {
+                Alu::Add => self.builder.ins().iadd(a, b),
+                Alu::Sub => self.builder.ins().isub(b, a),
+                _ => self.builder.ins().imul(a, b),
+            };
+            return extend(self, res);
+        }
+
+        let (res, overflow) = match op {
+            Alu::Add => self.builder.ins().sadd_overflow(a, b),
+            Alu::Sub => self.builder.ins().ssub_overflow(b, a),
+            _ => self.builder.ins().smul_overflow(a, b),
+        };
+        let res = extend(self, res);
+        let overflowed = self.builder.create_block();
+        let next = self.builder.create_block();
+        self.builder.append_block_param(next, types::I64);
+        self.builder.ins().brif(overflow, overflowed, &[], next, &[res]);
+        self.builder.switch_to_block(overflowed);
+        let fitted = self.arith(op, val, current);
+        self.builder.ins().jump(next, &[fitted]);
+        self.builder.switch_to_block(next);
+        self.builder.block_params(next)[0]
+    }
+
+    fn arith(&mut self, op: Alu, val: Value, current: Value) -> Value {
+        let op = self.builder.ins().iconst(types::I32, op as i64);
+        let args = [self.rt, op, val, current];
+        self.call_value(self.callbacks.arith, &args)
+    }
+}
+
+fn type_index(t: Type) -> i64 {
+    match t {
+        Type::Int => 0,
+        Type::Bool => 1,
+        Type::Char => 2,
+        Type::Str => 3,
+        Type::Money => 4,
+    }
+}
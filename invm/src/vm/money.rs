@@ -0,0 +1,107 @@
+//! Money, a fixed-point number of cents in a word.
+//!
+//! `BALANCE`, `STOCKPRICE`, `MARKETVAL` and `EQUITY` hold cents, so the word
+//! 150 is `R$ 1,50`. Adding, subtracting or multiplying by a whole number
+//! keeps the scale, and `MMULT` and `MDIV` multiply and divide two amounts.
+
+use crate::vm::Word;
+
+/// Cents in a real.
+pub const SCALE: Word = 100;
+
+/// `cents` as `R$ 1.234,56`, with a `-` before negative amounts. Totals that
+/// do not fit in a word, like the price of a large order, are shown exactly.
+pub fn format(cents: impl Into<i128>) -> String {
+    let cents = cents.into();
+    let magnitude = cents.unsigned_abs();
+    let whole = (magnitude / SCALE as u128).to_string();
+    let mut grouped = String::new();
+    for (i, digit) in whole.chars().enumerate() {
+        if i > 0 && (whole.len() - i).is_multiple_of(3) {
+            grouped.push('.');
+        }
+        grouped.push(digit);
+    }
+    let sign = if cents < 0 { "-" } else { "" };
+    format!("{sign}R$ {grouped},{:02}", magnitude % SCALE as u128)
+}
+
+/// The cents of an amount written like `R$ 1.234,56`, `-1234,5` or `12`: an
+/// optional sign and `R$`, the reais with or without `.` between groups of
+/// three digits, and up to two decimal places after a `,`.
+pub fn parse(text: &str) -> Option<i128> {
+    let (negative, text) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let text = text.strip_prefix("R$").map_or(text, str::trim_start);
+    let (whole, fraction) = match text.split_once(',') {
+        Some((whole, fraction)) => (whole, Some(fraction)),
+        None => (text, None),
+    };
+
+    let groups: Vec<&str> = whole.split('.').collect();
+    let grouped = groups.len() > 1
+        && (1..=3).contains(&groups[0].len())
+        && groups[1..].iter().all(|g| g.len() == 3);
+    if groups.len() > 1 && !grouped {
+        return None;
+    }
+    let reais = digits(&groups.concat())?;
+
+    let cents = match fraction {
+        None => 0,
+        Some(f) if (1..=2).contains(&f.len()) => digits(f)? * if f.len() == 1 { 10 } else { 1 },
+        Some(_) => return None,
+    };
+    let exact = reais.checked_mul(SCALE.into())?.checked_add(cents)?;
+    Some(if negative { -exact } else { exact })
+}
+
+/// The value of a non-empty run of decimal digits.
+fn digits(s: &str) -> Option<i128> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_groups_thousands_and_signs_negatives() {
+        assert_eq!(format(0), "R$ 0,00");
+        assert_eq!(format(5), "R$ 0,05");
+        assert_eq!(format(123456), "R$ 1.234,56");
+        assert_eq!(format(100000000), "R$ 1.000.000,00");
+        assert_eq!(format(-150), "-R$ 1,50");
+        assert_eq!(format(i64::MIN), "-R$ 92.233.720.368.547.758,08");
+    }
+
+    #[test]
+    fn parse_reads_what_format_writes() {
+        for cents in [0, 5, 123456, -150, 100000000, i64::MIN.into(), i64::MAX.into()] {
+            assert_eq!(parse(&format(cents)), Some(cents), "{}", format(cents));
+        }
+    }
+
+    #[test]
+    fn parse_takes_the_prefix_and_the_separators_as_optional() {
+        assert_eq!(parse("R$ 1.234,56"), Some(123456));
+        assert_eq!(parse("R$1234,56"), Some(123456));
+        assert_eq!(parse("1.234,56"), Some(123456));
+        assert_eq!(parse("1234,5"), Some(123450));
+        assert_eq!(parse("12"), Some(1200));
+        assert_eq!(parse("-0,5"), Some(-50));
+        assert_eq!(parse("-R$ 1,50"), Some(-150));
+    }
+
+    #[test]
+    fn parse_rejects_malformed_amounts() {
+        for text in ["", "R$", "1,234", "1,", ",5", "1.23,00", "12.345.6", "1,5x", "+1", "R$ -1", "abc"] {
+            assert_eq!(parse(text), None, "{text}");
+        }
+    }
+}
@@ -1,8 +1,12 @@
 use rand::Rng;
 
-use crate::vm::{Sensor, VM};
+use crate::vm::{money, Sensor, VM};
+
+/// Name of the market simulation in overflow faults.
+const MARKET: &str = "the market simulation";
 
 impl VM {
+    #[inline(never)]
     pub fn simulate(&mut self) {
 
         let balance = self.expect_sensor_value(&Sensor::Balance);
@@ -11,35 +15,40 @@ impl VM {
         let shares = self.expect_sensor_value(&Sensor::Shares);
         let owned = self.expect_sensor_value(&Sensor::Owned);
 
-        let mut r = rand::rng();
-        let rep_shift = r.random_range(-5..=5);
-        reputation += rep_shift;
+        let rep_shift: i32 = self.rng.random_range(-5..=5);
+        reputation = self.add(MARKET, reputation, rep_shift.into());
 
-        stockprice += r.random_range(-5..=5);
+        let price_shift = self.rng.random_range(-5 * money::SCALE..=5 * money::SCALE);
+        stockprice = self.add(MARKET, stockprice, price_shift);
         if stockprice < 0 {
             stockprice = 0;
         }
 
-        let bias = (reputation - 50) * stockprice / 10;
+        let bias = self.mul(MARKET, self.sub(MARKET, reputation, 50), stockprice) / (10 * money::SCALE);
+        let square = self.mul(MARKET, bias, bias);
         let factor = if bias < 0 {
-            - (bias * bias)
+            self.sub(MARKET, 0, square)
         } else {
-            bias * bias
+            square
         };
 
-        let amount = factor * (shares - owned);
-        let new = if amount + shares < 0 {
+        let amount = self.mul(MARKET, factor, self.sub(MARKET, shares, owned));
+        let total = self.add(MARKET, amount, shares);
+        let new = if total < 0 {
             owned
         } else {
-            amount + shares
+            total
         };
 
-        self.sensors.insert(Sensor::Shares, new);
-        self.sensors.insert(Sensor::Stockprice, stockprice);
-        self.sensors.insert(Sensor::Reputation, reputation);
-        self.sensors.insert(Sensor::MarketValue, shares * stockprice);
-        self.sensors.insert(Sensor::Equity, owned * stockprice);
-        self.sensors.insert(Sensor::Balance, balance + 100);
+        let market_value = self.mul(MARKET, shares, stockprice);
+        let equity = self.mul(MARKET, owned, stockprice);
+        let balance = self.add(MARKET, balance, 100 * money::SCALE);
+        self.set_sensor_value(Sensor::Shares, new);
+        self.set_sensor_value(Sensor::Stockprice, stockprice);
+        self.set_sensor_value(Sensor::Reputation, reputation);
+        self.set_sensor_value(Sensor::MarketValue, market_value);
+        self.set_sensor_value(Sensor::Equity, equity);
+        self.set_sensor_value(Sensor::Balance, balance);
 
     }
 }
@@ -0,0 +1,51 @@
+//! Reading and writing memory with `*R`, `*R+n`, `*R-n`, `*(R+R)` and `*R[i]`,
+//! in the interpreter, the JIT when it is built, and translated to C.
+
+mod common;
+
+use common::{Scratch, outputs};
+
+/// `primes` is at address 0 and `out` at address 5.
+const PROGRAM: &str = "\
+.word primes 4 2 3 5 7
+.word out 3 0 0 0
+    SET R0 primes
+    PRINT *R0 int
+    PRINT *R0+2 int
+    SET R1 out
+    SET *R1+2 9
+    SET R2 3
+    PRINT *(R0+R2) int
+    SET *(R1+R2) 11
+    SET R3 3
+    PRINT *primes[R3] int
+    SET *out-1 6
+    PRINT *primes[3] int
+    ADD *R0[0] *R1[0]
+    PRINT *out+1 int
+    PRINT *R1[1] int
+    PRINT *(out+R3) int
+";
+
+const OUTPUT: &str = "4\n3\n5\n7\n6\n2\n9\n11\n";
+
+#[test]
+fn address_modes_read_and_write_memory() {
+    let scratch = Scratch::new("addressing");
+    for (runner, out) in outputs(&scratch, PROGRAM, &[]) {
+        assert!(out.ok, "{runner} faulted with {}", out.stderr);
+        assert_eq!(out.stdout, OUTPUT, "{runner}");
+    }
+}
+
+#[test]
+fn indexes_outside_the_array_fault() {
+    let scratch = Scratch::new("addressing-bounds");
+    for index in [3, -1] {
+        let source = format!(".word out 3 0 0 0\nSET R0 {index}\nPRINT *out[R0] int\n");
+        let fault = format!("[INVM] Index {index} out of bounds for the array of length 3 at address 0.");
+        for (runner, out) in outputs(&scratch, &source, &[]) {
+            assert!(out.faulted_with(&fault), "{runner} on {index} faulted with {}", out.stderr);
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! `.invmb` files written by `assemble`, run, disassembled and rejected when corrupt.
+
+mod common;
+
+use std::fs;
+
+use common::{Scratch, invm};
+
+/// Uses data, labels, calls and every addressing mode, and prints what it did.
+const PROGRAM: &str = "\
+.width 64
+.arith saturating
+.string greeting \"olá\"
+.word table 3 10 20 30
+.zero scratch 2
+    PRINT greeting str
+    SET R0 table
+    SET R1 *R0[2]
+    PRINT R1 int
+    SET R2 1
+    SET R3 *(R0+R2)
+    PRINT R3 int
+    SET *scratch+1 7
+    PRINT *scratch+1 int
+    SET R4 9223372036854775807
+    ADD 1 R4
+    PRINT R4 int
+    CALL $twice
+    PRINT R5 int
+    CRASH
+twice:
+    SET R5 21
+    MULT 2 R5
+    RET
+";
+
+const OUTPUT: &str = "olá\n30\n10\n7\n9223372036854775807\n42\n";
+
+/// Assembles `source` as `name.invm`, returning the bytecode and its path.
+fn assemble(scratch: &Scratch, name: &str, source: &str) -> (Vec<u8>, std::path::PathBuf) {
+    let program = scratch.write(&format!("{name}.invm"), source);
+    let out = invm(["assemble", program.to_str().unwrap()]);
+    assert!(out.ok, "assemble faulted with {}", out.stderr);
+    let path = scratch.path(&format!("{name}.invmb"));
+    (fs::read(&path).unwrap(), path)
+}
+
+/// Runs the bytecode `bytes`, returning the fault it was rejected with.
+fn rejection(scratch: &Scratch, bytes: &[u8]) -> String {
+    let path = scratch.write("corrupt.invmb", bytes);
+    let out = invm(["run", path.to_str().unwrap()]);
+    assert!(!out.ok, "loaded the corrupt file");
+    out.stderr.lines().find(|line| line.starts_with("[Bytecode]")).unwrap_or(&out.stderr).to_string()
+}
+
+#[test]
+fn assembled_programs_run_like_their_source() {
+    let scratch = Scratch::new("bytecode-round-trip");
+    let (_, bytecode) = assemble(&scratch, "program", PROGRAM);
+    let source = scratch.path("program.invm");
+
+    for path in [&source, &bytecode] {
+        let out = invm(["run", path.to_str().unwrap(), "--seed", "7"]);
+        assert!(out.ok, "{} faulted with {}", path.display(), out.stderr);
+        assert_eq!(out.stdout, OUTPUT, "{}", path.display());
+    }
+
+    let disasm = invm(["disasm", bytecode.to_str().unwrap()]);
+    assert!(disasm.ok, "disasm faulted with {}", disasm.stderr);
+    let reparsed = scratch.write("reparsed.invm", &disasm.stdout);
+    let out = invm(["run", reparsed.to_str().unwrap()]);
+    assert!(out.ok, "the disassembly faulted with {}\n{}", out.stderr, disasm.stdout);
+    assert_eq!(out.stdout, OUTPUT);
+}
+
+#[test]
+fn corrupt_headers_are_rejected() {
+    let scratch = Scratch::new("bytecode-header");
+    let (bytes, _) = assemble(&scratch, "program", PROGRAM);
+    let corrupt = |at: usize, byte: u8| {
+        let mut bytes = bytes.clone();
+        bytes[at] = byte;
+        bytes
+    };
+
+    assert_eq!(rejection(&scratch, &corrupt(0, b'X')), "[Bytecode] Not an invm bytecode file.");
+    assert_eq!(rejection(&scratch, &corrupt(4, 9)), "[Bytecode] Unsupported bytecode version 9, expected 4.");
+    assert_eq!(rejection(&scratch, &corrupt(7, 0x80)), "[Bytecode] Invalid flags 0x8007.");
+    assert_eq!(rejection(&scratch, &bytes[..6]), "[Bytecode] Unexpected end of file.");
+}
+
+#[test]
+fn corrupt_names_and_sizes_are_rejected() {
+    let scratch = Scratch::new("bytecode-data");
+    let (bytes, _) = assemble(&scratch, "program", ".zero zeros 4\n.zero other 1\nloop:\nGOTO $loop\n");
+    let find = |needle: &[u8]| bytes.windows(needle.len()).position(|w| w == needle).unwrap();
+    let replaced = |at: usize, with: &[u8]| {
+        let mut bytes = bytes.clone();
+        bytes[at..at + with.len()].copy_from_slice(with);
+        bytes
+    };
+
+    let label = find(b"loop");
+    let fault = format!("[Bytecode] Invalid symbol 0 at byte {}.", label + 4);
+    assert_eq!(rejection(&scratch, &replaced(label, b"lo-p")), fault);
+    assert_eq!(rejection(&scratch, &replaced(label, b"1oop")), fault);
+
+    let name = find(b"zeros");
+    let fault = format!("[Bytecode] Invalid or duplicate data name at byte {}.", name - 2);
+    assert_eq!(rejection(&scratch, &replaced(name, b"ze ro")), fault);
+    assert_eq!(rejection(&scratch, &replaced(name, b"FUND1")), fault);
+    let other = find(b"other");
+    let fault = format!("[Bytecode] Invalid or duplicate data name at byte {}.", other - 2);
+    assert_eq!(rejection(&scratch, &replaced(other, b"zeros")), fault);
+
+    // A `.zero` of 4 billion words, which would otherwise be allocated.
+    let kind = name + 5;
+    let fault = format!("[Bytecode] Data at byte {kind} does not fit in the 1024 words of static data.");
+    assert_eq!(rejection(&scratch, &replaced(kind + 1, &u32::MAX.to_le_bytes())), fault);
+    assert_eq!(rejection(&scratch, &replaced(kind + 1, &1025u32.to_le_bytes())), fault);
+}
+
+#[test]
+fn writing_a_sensor_faults_naming_the_instruction() {
+    let scratch = Scratch::new("bytecode-sensor-destination");
+    for inst in ["DIV", "MOD", "MMULT", "MDIV", "NEG", "POP"] {
+        let source = match inst {
+            "POP" => "PUSH 2\nPOP R0\n".to_string(),
+            _ => format!("SET R0 6\n{inst} 2 R0\n"),
+        };
+        let program = scratch.write("sensor.invm", source);
+        assert!(invm(["assemble", program.to_str().unwrap(), "--strip"]).ok);
+        // Without a line table, the file ends with the destination `R0`, which
+        // becomes `STOCKPRICE`: the sensor tag and the index of the sensor.
+        let path = scratch.path("sensor.invmb");
+        let mut bytes = fs::read(&path).unwrap();
+        let len = bytes.len();
+        bytes[len - 2..].copy_from_slice(&[0x01, 0x01]);
+        fs::write(&path, bytes).unwrap();
+
+        let out = invm(["run", path.to_str().unwrap()]);
+        let fault = format!("[INVM] Unable to modify readonly value Sensor(Stockprice) at {inst} instruction.");
+        assert!(out.faulted_with(&fault), "{inst} faulted with {}", out.stderr);
+    }
+}
@@ -0,0 +1,64 @@
+//! The control-flow graph printed by `invm cfg` as Graphviz DOT.
+
+mod common;
+
+use common::{Scratch, invm};
+
+const PROGRAM: &str = "\
+start:
+    SET R0 3
+loop:
+    GOIF == R0 $done
+    CALL $work
+    GOTO $loop
+done:
+    CRASH
+work:
+    SUB 1 R0
+    RET
+";
+
+#[test]
+fn jumps_calls_and_crashes_become_edges() {
+    let scratch = Scratch::new("cfg");
+    let program = scratch.write("cfg.invm", PROGRAM);
+    let out = invm(["cfg", program.to_str().unwrap()]);
+    assert!(out.ok, "faulted with {}", out.stderr);
+    assert_eq!(out.stdout, concat!(
+        "digraph invm {\n",
+        "    node [shape=box, fontname=\"monospace\"];\n",
+        "    entry [shape=oval];\n",
+        "    exit [shape=oval];\n",
+        "    entry -> b0;\n",
+        "    b0 [label=\"1: start:\\l2:   SET R0 3\\l\"];\n",
+        "    b0 -> b1;\n",
+        "    b1 [label=\"3: loop:\\l4:   GOIF == R0 $done\\l\"];\n",
+        "    b1 -> b4 [label=\"R0 == 0\", color=darkgreen];\n",
+        "    b1 -> b2 [label=\"R0 != 0\", color=red];\n",
+        "    b2 [label=\"5:   CALL $work\\l\"];\n",
+        "    b2 -> b5 [label=\"CALL\"];\n",
+        "    b3 [label=\"6:   GOTO $loop\\l\"];\n",
+        "    b3 -> b1 [label=\"GOTO\"];\n",
+        "    b4 [label=\"7: done:\\l8:   CRASH\\l\"];\n",
+        "    b4 -> exit [label=\"CRASH\"];\n",
+        "    b5 [label=\"9: work:\\l10:   SUB 1 R0\\l11:   RET\\l\"];\n",
+        "    b5 -> b3 [label=\"RET\", style=dashed];\n",
+        "}\n",
+    ));
+}
+
+#[test]
+fn falling_off_the_end_leads_to_the_exit() {
+    let scratch = Scratch::new("cfg-end");
+    let program = scratch.write("cfg.invm", "GOIF > R0 1 $big\nPRINT 0 int\nbig:\nPRINT 1 int\n");
+    let out = invm(["cfg", program.to_str().unwrap()]);
+    assert!(out.ok, "faulted with {}", out.stderr);
+    let edges: Vec<&str> = out.stdout.lines().map(str::trim).filter(|line| line.contains("->")).collect();
+    assert_eq!(edges, [
+        "entry -> b0;",
+        "b0 -> b2 [label=\"R0 > 1\", color=darkgreen];",
+        "b0 -> b1 [label=\"R0 <= 1\", color=red];",
+        "b1 -> b2;",
+        "b2 -> exit;",
+    ]);
+}
@@ -0,0 +1,108 @@
+//! Helpers shared by the integration tests, which run the `invm` binary.
+
+// Every test file uses only some of the helpers.
+#![allow(dead_code)]
+
+use std::{env, ffi::OsStr, fs, io::Write, path::{Path, PathBuf}, process::{Command, Stdio}};
+
+/// What a run of `invm`, or of a translated C program, did.
+pub struct Output {
+    pub ok: bool,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl Output {
+    /// Whether the run stopped with this fault message.
+    pub fn faulted_with(&self, fault: &str) -> bool {
+        !self.ok && self.stderr.lines().any(|line| line == fault)
+    }
+}
+
+/// A directory of its own for a test, removed when the test ends.
+pub struct Scratch {
+    pub dir: PathBuf,
+}
+
+impl Scratch {
+    pub fn new(name: &str) -> Self {
+        let dir = env::temp_dir().join(format!("invm-test-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Scratch { dir }
+    }
+
+    pub fn path(&self, file: &str) -> PathBuf {
+        self.dir.join(file)
+    }
+
+    /// Writes `contents` to `file` in the directory, returning its path.
+    pub fn write(&self, file: &str, contents: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.path(file);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Runs `invm` with the arguments and an empty input.
+pub fn invm<S: AsRef<OsStr>>(args: impl IntoIterator<Item = S>) -> Output {
+    invm_with_input(args, "")
+}
+
+/// Runs `invm` with the arguments, giving it `input` on stdin.
+pub fn invm_with_input<S: AsRef<OsStr>>(args: impl IntoIterator<Item = S>, input: &str) -> Output {
+    command(Command::new(env!("CARGO_BIN_EXE_invm")).args(args), input)
+}
+
+/// The commands that run a program: the interpreter, and the JIT when it is built.
+pub fn runners() -> Vec<&'static str> {
+    if cfg!(feature = "jit") { vec!["run", "jit"] } else { vec!["run"] }
+}
+
+/// Translates the program with `invm c <program> <args>`, compiles it with
+/// `cc` and runs it, or returns `None` if there is no C compiler.
+pub fn run_c(scratch: &Scratch, program: &Path, args: &[&str]) -> Option<Output> {
+    let c = invm([OsStr::new("c"), program.as_os_str()].into_iter().chain(args.iter().map(OsStr::new)));
+    assert!(c.ok, "translating {} failed with {}", program.display(), c.stderr);
+    let source = scratch.write("program.c", c.stdout);
+    let binary = scratch.path("program");
+    let compiled = Command::new("cc").arg("-o").arg(&binary).arg(&source).output().ok()?;
+    assert!(compiled.status.success(), "cc failed with {}", String::from_utf8_lossy(&compiled.stderr));
+    Some(command(&mut Command::new(binary), ""))
+}
+
+/// Writes `source` to `program.invm` and runs it with every runner and, when
+/// there is a C compiler, translated to C, returning what each printed. The
+/// arguments go to every run, and to `invm c` for C, which is never seeded.
+pub fn outputs(scratch: &Scratch, source: &str, args: &[&str]) -> Vec<(&'static str, Output)> {
+    let program = scratch.write("program.invm", source);
+    let mut outputs: Vec<_> = runners()
+        .into_iter()
+        .map(|command| (command, invm([command, program.to_str().unwrap()].iter().chain(args))))
+        .collect();
+    if let Some(out) = run_c(scratch, &program, args) {
+        outputs.push(("C", out));
+    }
+    outputs
+}
+
+fn command(command: &mut Command, input: &str) -> Output {
+    let mut child = command
+        .env("RUST_BACKTRACE", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // A program may stop before reading all of its input.
+    let _ = child.stdin.take().unwrap().write_all(input.as_bytes());
+    let output = child.wait_with_output().unwrap();
+    let text = |bytes: Vec<u8>| String::from_utf8(bytes).unwrap();
+    Output { ok: output.status.success(), stdout: text(output.stdout), stderr: text(output.stderr) }
+}
@@ -0,0 +1,90 @@
+//! Conformance tests for the arithmetic instructions of the README's
+//! instruction table, `OP *R1/n *R2`, which store in R2 the result of R2 and
+//! the value. Every program runs in the interpreter, the JIT when it is built,
+//! and translated to C, with and without `-O`.
+
+mod common;
+
+use std::{collections::BTreeSet, fs};
+
+use common::{Scratch, outputs};
+
+/// What a program does: print these lines, or stop with this fault.
+enum Expect {
+    Prints(&'static str),
+    Faults(&'static str),
+}
+
+use Expect::{Faults, Prints};
+
+/// The instruction each program checks, the program and what it does.
+const CASES: &[(&str, &str, Expect)] = &[
+    ("ADD", "SET R0 5\nADD 3 R0\nPRINT R0 int\nSET R1 -9\nADD R1 R0\nPRINT R0 int\n", Prints("8\n-1\n")),
+    ("SUB", "SET R0 5\nSUB 3 R0\nPRINT R0 int\nSUB 10 R0\nPRINT R0 int\n", Prints("2\n-8\n")),
+    ("MULT", "SET R0 6\nMULT 7 R0\nPRINT R0 int\nMULT -2 R0\nPRINT R0 int\n", Prints("42\n-84\n")),
+    ("DIV", "SET R0 7\nDIV 2 R0\nPRINT R0 int\nSET R0 -7\nDIV 2 R0\nPRINT R0 int\nSET R0 7\nDIV -2 R0\nPRINT R0 int\n", Prints("3\n-3\n-3\n")),
+    ("DIV", "SET R0 2\nSET R1 10\nDIV R0 R1\nPRINT R1 int\nPRINT R0 int\nDIV 1 R1\nPRINT R1 int\n", Prints("5\n2\n5\n")),
+    ("DIV", "SET R0 7\nDIV 0 R0\n", Faults("[INVM] Division by zero in DIV.")),
+    ("DIV", "SET R0 7\nSET R1 0\nDIV R1 R0\n", Faults("[INVM] Division by zero in DIV.")),
+    ("DIV", ".arith saturating\nSET R0 7\nDIV 0 R0\n", Faults("[INVM] Division by zero in DIV.")),
+    ("DIV", "SET R0 -2147483648\nDIV -1 R0\nPRINT R0 int\n", Prints("-2147483648\n")),
+    ("DIV", ".arith saturating\nSET R0 -2147483648\nDIV -1 R0\nPRINT R0 int\n", Prints("2147483647\n")),
+    ("DIV", ".arith trapping\nSET R0 -2147483648\nDIV -1 R0\n", Faults("[INVM] Overflow in DIV: 2147483648 does not fit in 32 bits.")),
+    ("MOD", "SET R0 7\nMOD 2 R0\nPRINT R0 int\nSET R0 -7\nMOD 2 R0\nPRINT R0 int\nSET R0 7\nMOD -2 R0\nPRINT R0 int\n", Prints("1\n-1\n1\n")),
+    ("MOD", "SET R0 7\nMOD 0 R0\n", Faults("[INVM] Division by zero in MOD.")),
+    ("MOD", ".width 64\nSET R0 7\nSET R1 0\nMOD R1 R0\n", Faults("[INVM] Division by zero in MOD.")),
+    ("AND", "SET R0 12\nAND 10 R0\nPRINT R0 int\n", Prints("8\n")),
+    ("OR", "SET R0 12\nOR 10 R0\nPRINT R0 int\n", Prints("14\n")),
+    ("XOR", "SET R0 12\nXOR 10 R0\nPRINT R0 int\n", Prints("6\n")),
+    ("NOT", "SET R1 0\nNOT R1 R0\nPRINT R0 int\n", Prints("-1\n")),
+    ("SHL", "SET R0 1\nSHL 4 R0\nPRINT R0 int\n", Prints("16\n")),
+    ("SHL", "SET R0 1\nSHL 32 R0\n", Faults("[INVM] Shift by 32 bits, expected 0 to 31.")),
+    ("SHR", "SET R0 -8\nSHR 1 R0\nPRINT R0 int\n", Prints("-4\n")),
+    ("NEG", "SET R0 5\nNEG R0 R0\nPRINT R0 int\n", Prints("-5\n")),
+    ("ABS", "SET R0 -5\nABS R0 R1\nPRINT R1 int\n", Prints("5\n")),
+    ("MIN", "SET R0 5\nMIN 3 R0\nPRINT R0 int\nMIN 100 R0\nPRINT R0 int\n", Prints("3\n3\n")),
+    ("MAX", "SET R0 5\nMAX 3 R0\nPRINT R0 int\nMAX 100 R0\nPRINT R0 int\n", Prints("5\n100\n")),
+    ("MMULT", "SET R0 100,00\nMMULT 1,05 R0\nPRINT R0 money\n", Prints("R$ 105,00\n")),
+    ("MDIV", "SET R0 10,00\nMDIV 2,50 R0\nPRINT R0 money\n", Prints("R$ 4,00\n")),
+    ("MDIV", "SET R0 10,00\nMDIV 0 R0\n", Faults("[INVM] Division by zero in MDIV.")),
+];
+
+/// The instructions of the README's table with `*R1/n *R2` operands.
+fn documented() -> BTreeSet<String> {
+    let readme = fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/../README.md")).unwrap();
+    readme.lines()
+        .filter_map(|line| {
+            let mut cells = line.split('|').map(str::trim).skip(1);
+            let name = cells.next()?.strip_prefix("**")?.strip_suffix("**")?;
+            let syntax = cells.next()?;
+            (syntax == format!("`{name} *R1/n *R2`")).then(|| name.to_string())
+        })
+        .collect()
+}
+
+#[test]
+fn every_arithmetic_instruction_is_covered() {
+    let documented = documented();
+    let tested: BTreeSet<String> = CASES.iter().map(|(name, ..)| name.to_string()).collect();
+    assert!(documented.len() >= 15, "found only {documented:?} in the README");
+    assert_eq!(documented, tested);
+}
+
+#[test]
+fn arithmetic_follows_the_readme() {
+    let scratch = Scratch::new("conformance");
+    for (name, program, expect) in CASES {
+        for args in [&[][..], &["-O"]] {
+            for (runner, out) in outputs(&scratch, program, args) {
+                let context = format!("{name} in {runner} {args:?} on:\n{program}");
+                match expect {
+                    Prints(lines) => {
+                        assert!(out.ok, "{context}\nfaulted with {}", out.stderr);
+                        assert_eq!(&out.stdout, lines, "{context}");
+                    },
+                    Faults(fault) => assert!(out.faulted_with(fault), "{context}\nfaulted with {}", out.stderr),
+                }
+            }
+        }
+    }
+}
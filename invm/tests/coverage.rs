@@ -0,0 +1,80 @@
+//! `invm coverage`: the counts merged in `.cov`, the lcov report and the
+//! annotated listing.
+
+mod common;
+
+use std::fs;
+
+use common::{Scratch, invm};
+
+const PROGRAM: &str = "\
+SET R0 2
+loop:
+GOIF == R0 $end
+SUB 1 R0
+GOTO $loop
+end:
+PRINT R0 int
+";
+
+#[test]
+fn runs_are_merged_and_reported_in_lcov() {
+    let scratch = Scratch::new("coverage");
+    let program = scratch.write("loop.invm", PROGRAM);
+
+    let first = invm(["coverage", program.to_str().unwrap()]);
+    assert!(first.ok, "faulted with {}", first.stderr);
+    assert!(first.stderr.contains("        3 |     3 | GOIF == R0 $end  [taken 1, not taken 2]\n"), "{}", first.stderr);
+
+    let second = invm(["coverage", program.to_str().unwrap()]);
+    assert!(second.ok, "faulted with {}", second.stderr);
+    assert_eq!(second.stdout, "0\n");
+    assert!(second.stderr.contains("        6 |     3 | GOIF == R0 $end  [taken 2, not taken 4]\n"), "{}", second.stderr);
+    assert!(second.stderr.ends_with("[Coverage] Instructions: 5/5.\n[Coverage] Branches: 2/2.\n"), "{}", second.stderr);
+
+    let lcov = fs::read_to_string(scratch.path("loop.invm.lcov")).unwrap();
+    assert_eq!(lcov, format!(concat!(
+        "TN:\nSF:{}\n",
+        "BRDA:3,2,0,2\nBRDA:3,2,1,4\nBRF:2\nBRH:2\n",
+        "DA:1,2\nDA:3,6\nDA:4,4\nDA:5,4\nDA:7,2\nLF:5\nLH:5\n",
+        "end_of_record\n",
+    ), program.display()));
+}
+
+#[test]
+fn branches_never_reached_are_marked() {
+    let scratch = Scratch::new("coverage-unreached");
+    let program = scratch.write("crash.invm", "CRASH\nGOIF == R0 $end\nend:\n");
+    let out = invm(["coverage", program.to_str().unwrap()]);
+    assert!(out.ok, "faulted with {}", out.stderr);
+    assert!(out.stderr.contains("    ##### |     2 | GOIF == R0 $end  [taken 0, not taken 0]\n"), "{}", out.stderr);
+    let lcov = fs::read_to_string(scratch.path("crash.invm.lcov")).unwrap();
+    assert!(lcov.contains("BRDA:2,1,0,-\nBRDA:2,1,1,-\nBRF:2\nBRH:0\nDA:1,1\nDA:2,0\n"), "{lcov}");
+}
+
+#[test]
+fn data_of_another_program_is_rejected() {
+    let scratch = Scratch::new("coverage-other");
+    let fault = "[Coverage] Coverage data was recorded for a different program.";
+    let program = scratch.write("loop.invm", PROGRAM);
+    let cov = scratch.path("loop.invm.cov");
+
+    // The same number of instructions, with another constant.
+    let other = scratch.write("other.invm", PROGRAM.replace("SET R0 2", "SET R0 3"));
+    assert!(invm(["coverage", other.to_str().unwrap()]).ok);
+    fs::copy(scratch.path("other.invm.cov"), &cov).unwrap();
+    let out = invm(["coverage", program.to_str().unwrap()]);
+    assert!(out.faulted_with(fault), "faulted with {}", out.stderr);
+
+    // Data of this program with an instruction missing.
+    fs::remove_file(&cov).unwrap();
+    assert!(invm(["coverage", program.to_str().unwrap()]).ok);
+    let data = fs::read_to_string(&cov).unwrap();
+    fs::write(&cov, &data[..data.trim_end().rfind('\n').unwrap() + 1]).unwrap();
+    let out = invm(["coverage", program.to_str().unwrap()]);
+    assert!(out.faulted_with(fault), "faulted with {}", out.stderr);
+
+    fs::write(&cov, "invm-coverage 1\n0 1 0 0\n").unwrap();
+    let out = invm(["coverage", program.to_str().unwrap()]);
+    assert!(out.faulted_with(&format!("[Coverage] {} is not a coverage data file.", cov.display())), "faulted with {}", out.stderr);
+}
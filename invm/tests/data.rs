@@ -0,0 +1,29 @@
+//! The `.string`, `.word` and `.zero` data directives.
+
+mod common;
+
+use common::{Scratch, invm};
+
+#[test]
+fn data_too_large_for_the_static_data_faults() {
+    let scratch = Scratch::new("data-too-large");
+    let cases = [
+        (".string s \"abc\"\n.zero big 1020\nPRINT 1 int\n", None::<u64>),
+        (".string s \"abc\"\n.zero big 1021\nPRINT 1 int\n", Some(1025)),
+        (".word w 1 2 3\n.zero big 1022\nPRINT 1 int\n", Some(1025)),
+        (".width 64\n.zero big 1000000000000\nPRINT 1 int\n", Some(1_000_000_000_000)),
+    ];
+    for (i, (source, needed)) in cases.into_iter().enumerate() {
+        let program = scratch.write(&format!("data{i}.invm"), source);
+        for command in ["run", "c"] {
+            let out = invm([command, program.to_str().unwrap()]);
+            match needed {
+                None => assert!(out.ok, "{command} on:\n{source}\nfaulted with {}", out.stderr),
+                Some(words) => {
+                    let fault = format!("[INVM] The data section needs {words} words, but there are only 1024 words of static data.");
+                    assert!(out.faulted_with(&fault), "{command} on:\n{source}\nfaulted with {}", out.stderr);
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! `OPEN`, `READLINE`, `WRITE` and `CLOSE` in the directory granted with
+//! `--files`, in the interpreter, the JIT when it is built, and translated to C.
+#![cfg(unix)]
+
+mod common;
+
+use std::{fs, os::unix::fs::symlink};
+
+use common::{Scratch, outputs};
+
+/// Opens names that fail in every way a program can check, then writes a
+/// file, reads it to the end and opens it until there are too many open.
+const STATUSES: &str = "\
+.string missing \"missing.txt\"
+.string parent \"../outside/secret\"
+.string absolute \"/etc/passwd\"
+.string dot \"./notes.txt\"
+.string link \"link\"
+.string dir \"sub\"
+.string notes \"notes.txt\"
+.string line \"first line\\n\"
+    OPEN missing read R0
+    PRINT R0 int
+    OPEN parent read R0
+    PRINT R0 int
+    OPEN absolute read R0
+    PRINT R0 int
+    OPEN dot write R0
+    PRINT R0 int
+    OPEN link read R0
+    PRINT R0 int
+    OPEN dir read R0
+    PRINT R0 int
+    OPEN notes write R0
+    WRITE R0 line
+    CLOSE R0
+    OPEN notes read R0
+    READLINE R0 R1
+    PRINT R1 str
+    READLINE R0 R1
+    PRINT R1 int
+    SET R2 0
+many:
+    OPEN notes read R1
+    ADD 1 R2
+    GOIF > R1 0 $many
+    PRINT R1 int
+    PRINT R2 int
+";
+
+/// The file handle already open leaves room for 63 more.
+const STATUS_OUTPUT: &str = "-1\n-2\n-2\n-2\n-2\n-3\nfirst line\n-4\n-3\n64\n";
+
+#[test]
+fn failures_are_reported_with_status_codes() {
+    let scratch = Scratch::new("file-statuses");
+    let granted = scratch.path("granted");
+    let outside = scratch.path("outside");
+    fs::create_dir_all(granted.join("sub")).unwrap();
+    fs::create_dir_all(&outside).unwrap();
+    fs::write(outside.join("secret"), "secret\n").unwrap();
+    symlink(outside.join("secret"), granted.join("link")).unwrap();
+    let files = granted.to_str().unwrap();
+
+    for (runner, out) in outputs(&scratch, STATUSES, &["--files", files]) {
+        assert!(out.ok, "{runner} faulted with {}", out.stderr);
+        assert_eq!(out.stdout, STATUS_OUTPUT, "{runner}");
+    }
+}
+
+#[test]
+fn files_are_denied_without_a_directory() {
+    let scratch = Scratch::new("files-not-granted");
+    let source = ".string name \"notes.txt\"\nOPEN name write R0\nPRINT R0 int\n";
+    for (runner, out) in outputs(&scratch, source, &[]) {
+        assert!(out.ok, "{runner} faulted with {}", out.stderr);
+        assert_eq!(out.stdout, "-2\n", "{runner}");
+    }
+}
+
+#[test]
+fn writing_through_a_dangling_link_is_denied() {
+    let scratch = Scratch::new("dangling-link");
+    let granted = scratch.path("granted");
+    let outside = scratch.path("outside");
+    fs::create_dir_all(&granted).unwrap();
+    fs::create_dir_all(&outside).unwrap();
+    symlink(outside.join("escaped"), granted.join("evil")).unwrap();
+    let source = concat!(
+        ".string name \"evil\"\n",
+        ".string msg \"pwned\"\n",
+        "OPEN name write R0\nPRINT R0 int\n",
+        "OPEN name append R0\nPRINT R0 int\n",
+        "GOIF < R0 $end\nWRITE R0 msg\n",
+        "end:\n",
+    );
+    let files = granted.to_str().unwrap();
+
+    for (runner, out) in outputs(&scratch, source, &["--files", files]) {
+        assert!(out.ok, "{runner} faulted with {}", out.stderr);
+        assert_eq!(out.stdout, "-2\n-2\n", "{runner}");
+    }
+    assert!(!outside.join("escaped").exists());
+}
@@ -0,0 +1,121 @@
+//! Frames opened with `ENTER` and closed with `LEAVE`, the `--call-limit` on
+//! nested calls, and what is shown of both when the program faults.
+
+mod common;
+
+use common::{Scratch, outputs};
+
+/// The factorial from the README.
+const FACTORIAL: &str = "\
+PUSH 5
+CALL $fat
+POP R0
+PRINT R0 int  # 120
+CRASH
+
+fat:
+ENTER 1
+SET *FP-1 *FP+1
+GOIF > *FP-1 1 $rec
+SET *FP+1 1
+LEAVE
+RET
+rec:
+SET R0 *FP-1
+SUB 1 R0
+PUSH R0
+CALL $fat
+POP R0
+MULT *FP-1 R0
+SET *FP+1 R0
+LEAVE
+RET
+";
+
+#[test]
+fn factorial_from_the_readme() {
+    let scratch = Scratch::new("frames-factorial");
+    for (runner, out) in outputs(&scratch, FACTORIAL, &[]) {
+        assert!(out.ok, "{runner} faulted with {}", out.stderr);
+        assert_eq!(out.stdout, "120\n", "{runner}");
+    }
+}
+
+#[test]
+fn locals_are_below_fp_and_arguments_above() {
+    let scratch = Scratch::new("frames-slots");
+    // LEAVE also drops the value pushed after ENTER, so the POPs see the arguments.
+    let source = "\
+PUSH 10
+PUSH 20
+CALL $f
+POP R0
+POP R1
+PRINT R0 int
+PRINT R1 int
+PRINT FP int
+CRASH
+f:
+ENTER 2
+PRINT *FP-1 int
+SET *FP-1 *FP+1
+SET *FP-2 *FP+2
+ADD *FP-2 *FP-1
+SET *FP+1 *FP-1
+PUSH 99
+LEAVE
+RET
+";
+    for (runner, out) in outputs(&scratch, source, &[]) {
+        assert!(out.ok, "{runner} faulted with {}", out.stderr);
+        // FP is back at the end of memory, where it starts.
+        assert_eq!(out.stdout, "0\n30\n10\n65536\n", "{runner}");
+    }
+}
+
+#[test]
+fn leave_without_a_frame_faults() {
+    let scratch = Scratch::new("frames-leave");
+    for (runner, out) in outputs(&scratch, "ENTER 1\nLEAVE\nLEAVE\n", &[]) {
+        assert!(out.faulted_with("[INVM] LEAVE without a frame."), "{runner} faulted with {}", out.stderr);
+    }
+}
+
+#[test]
+fn open_frames_are_shown_on_a_fault() {
+    let scratch = Scratch::new("frames-dump");
+    let source = "\
+PUSH 9
+CALL $f
+CRASH
+f:
+ENTER 2
+SET *FP-1 3
+SET *FP-2 4
+PUSH 1
+CALL $g
+g:
+ENTER 1
+SET *FP-1 8
+PRINT *1024 int
+";
+    for (runner, out) in outputs(&scratch, source, &[]) {
+        assert!(out.faulted_with("[INVM] Segmentation fault at address 1024."), "{runner} faulted with {}", out.stderr);
+        let report: Vec<&str> = out.stderr.lines().skip_while(|line| !line.starts_with("[INVM] Call chain")).collect();
+        assert_eq!(report, [
+            "[INVM] Call chain: $f -> $g",
+            "[INVM] Frames, innermost first:",
+            "[INVM]   FP 65530: FP-1 = 8",
+            "[INVM]   FP 65534: FP-1 = 3, FP-2 = 4, FP-3 = 1",
+        ], "{runner}");
+    }
+}
+
+#[test]
+fn nested_calls_past_the_limit_fault() {
+    let scratch = Scratch::new("frames-call-limit");
+    for (runner, out) in outputs(&scratch, "CALL $a\na:\nCALL $b\nb:\nCALL $b\n", &["--call-limit", "4"]) {
+        assert!(out.faulted_with("[INVM] Call stack overflow: more than 4 nested calls."), "{runner} faulted with {}", out.stderr);
+        assert!(out.stderr.lines().any(|line| line == "[INVM] Call chain: $a -> $b (x3)"), "{runner}: {}", out.stderr);
+    }
+}
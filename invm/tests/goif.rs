@@ -0,0 +1,58 @@
+//! `GOIF` comparing two operands, with every condition.
+
+mod common;
+
+use std::fmt::Write;
+
+use common::{Scratch, outputs};
+
+/// Whether a condition holds for the two operands.
+type Holds = fn(i64, i64) -> bool;
+
+const CONDITIONS: [(&str, Holds); 6] = [
+    ("==", |a, b| a == b),
+    ("!=", |a, b| a != b),
+    ("<", |a, b| a < b),
+    ("<=", |a, b| a <= b),
+    (">", |a, b| a > b),
+    (">=", |a, b| a >= b),
+];
+
+#[test]
+fn every_condition_compares_the_first_operand_with_the_second() {
+    let scratch = Scratch::new("goif-conditions");
+    let (mut source, mut expected) = (String::new(), String::new());
+    for (i, (condition, holds)) in CONDITIONS.iter().enumerate() {
+        for (j, b) in [5, 9, 1].into_iter().enumerate() {
+            let label = format!("{i}_{j}");
+            writeln!(source, "SET R0 5\nSET R1 {b}\nGOIF {condition} R0 R1 $taken{label}\nPRINT 0 int\nGOTO $next{label}").unwrap();
+            writeln!(source, "taken{label}:\nPRINT 1 int\nnext{label}:").unwrap();
+            writeln!(expected, "{}", u8::from(holds(5, b))).unwrap();
+        }
+    }
+    for (runner, out) in outputs(&scratch, &source, &[]) {
+        assert!(out.ok, "{runner} faulted with {}", out.stderr);
+        assert_eq!(out.stdout, expected, "{runner}");
+    }
+}
+
+#[test]
+fn neither_operand_is_modified() {
+    let scratch = Scratch::new("goif-operands");
+    let source = "\
+.word x 7
+SET R0 3
+GOIF < R0 *x $less
+CRASH
+less:
+GOIF >= *x 7 $done
+CRASH
+done:
+PRINT R0 int
+PRINT *x int
+";
+    for (runner, out) in outputs(&scratch, source, &[]) {
+        assert!(out.ok, "{runner} faulted with {}", out.stderr);
+        assert_eq!(out.stdout, "3\n7\n", "{runner}");
+    }
+}
@@ -0,0 +1,86 @@
+//! The heap of `ALLOC` and `FREE`: first-fit reuse, shrinking when the top
+//! block is freed, and the checks and leak report of `--heap-debug`.
+
+mod common;
+
+use common::{Scratch, outputs};
+
+/// The heap starts after the 1024 words of static data.
+const REUSE: &str = "\
+ALLOC 2 R0
+ALLOC 3 R1
+ALLOC 1 R2
+FREE R0
+ALLOC 1 R3
+PRINT R3 int
+ALLOC 1 R4
+PRINT R4 int
+ALLOC 1 R5
+PRINT R5 int
+";
+
+#[test]
+fn freed_blocks_are_reused_first_fit() {
+    let scratch = Scratch::new("heap-reuse");
+    for (runner, out) in outputs(&scratch, REUSE, &[]) {
+        assert!(out.ok, "{runner} faulted with {}", out.stderr);
+        assert_eq!(out.stdout, "1024\n1025\n1030\n", "{runner}");
+    }
+}
+
+#[test]
+fn freeing_the_top_block_shrinks_the_heap() {
+    let scratch = Scratch::new("heap-shrink");
+    // The free block at 1025 merges with the top one, so the heap ends at 1025.
+    let source = "ALLOC 1 R0\nALLOC 1 R1\nALLOC 2 R2\nFREE R1\nFREE R2\nALLOC 4 R3\nPRINT R3 int\nFREE R3\nPRINT *1025 int\n";
+    for (runner, out) in outputs(&scratch, source, &[]) {
+        assert!(out.faulted_with("[INVM] Segmentation fault at address 1025."), "{runner} faulted with {}", out.stderr);
+        assert_eq!(out.stdout, "1025\n", "{runner}");
+    }
+}
+
+#[test]
+fn freeing_a_block_twice_faults() {
+    let scratch = Scratch::new("heap-double-free");
+    let source = "ALLOC 2 R0\nFREE R0\nFREE R0\n";
+    for (runner, out) in outputs(&scratch, source, &[]) {
+        let fault = "[INVM] FREE of address 1024, which is not the start of an allocated block.";
+        assert!(out.faulted_with(fault), "{runner} faulted with {}", out.stderr);
+    }
+    for (runner, out) in outputs(&scratch, source, &["--heap-debug"]) {
+        assert!(out.faulted_with("[INVM] Double free of address 1024."), "{runner} faulted with {}", out.stderr);
+    }
+}
+
+#[test]
+fn using_a_freed_block_faults_in_debug_mode() {
+    let scratch = Scratch::new("heap-use-after-free");
+    // The block at 1024 is not the top one, so it stays mapped once freed.
+    let source = "ALLOC 2 R0\nALLOC 1 R1\nSET *R0+1 5\nFREE R0\nPRINT *R0+1 int\n";
+    for (runner, out) in outputs(&scratch, source, &[]) {
+        assert!(out.ok, "{runner} faulted with {}", out.stderr);
+        assert_eq!(out.stdout, "5\n", "{runner}");
+    }
+    for (runner, out) in outputs(&scratch, source, &["--heap-debug"]) {
+        let fault = "[INVM] Use after free at address 1025, in the block of 2 words at 1024.";
+        assert!(out.faulted_with(fault), "{runner} faulted with {}", out.stderr);
+    }
+}
+
+#[test]
+fn leaks_are_reported_at_exit() {
+    let scratch = Scratch::new("heap-leaks");
+    for (runner, out) in outputs(&scratch, REUSE, &["--heap-debug"]) {
+        assert!(out.ok, "{runner} faulted with {}", out.stderr);
+        // Freed blocks are never reused.
+        assert_eq!(out.stdout, "1030\n1031\n1032\n", "{runner}");
+        assert_eq!(out.stderr, concat!(
+            "[INVM] Leak: 5 blocks, 7 words still allocated.\n",
+            "[INVM]   3 words at address 1026, allocated by instruction 1: ALLOC 3 R1\n",
+            "[INVM]   1 words at address 1029, allocated by instruction 2: ALLOC 1 R2\n",
+            "[INVM]   1 words at address 1030, allocated by instruction 4: ALLOC 1 R3\n",
+            "[INVM]   1 words at address 1031, allocated by instruction 6: ALLOC 1 R4\n",
+            "[INVM]   1 words at address 1032, allocated by instruction 8: ALLOC 1 R5\n",
+        ), "{runner}");
+    }
+}
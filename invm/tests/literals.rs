@@ -0,0 +1,52 @@
+//! The ways to write a number, and numbers too large for a word.
+
+mod common;
+
+use common::{Scratch, invm, runners};
+
+#[test]
+fn literals_are_read_as_words() {
+    let scratch = Scratch::new("literals");
+    let cases = [
+        ("", "42", "42"),
+        ("", "-5", "-5"),
+        ("", "0x1F", "31"),
+        ("", "0b1010", "10"),
+        ("", "0xFFFFFFFF", "-1"),
+        (".width 64\n", "0xFFFFFFFF", "4294967295"),
+        (".width 64\n", "0xFFFFFFFFFFFFFFFF", "-1"),
+        ("", "1_000_000", "1000000"),
+        ("", "0xFF_FF", "65535"),
+        ("", "'A'", "65"),
+        ("", "'\\n'", "10"),
+        ("", "'\\''", "39"),
+        ("", "12,50", "1250"),
+        ("", "-0,5", "-50"),
+    ];
+    for (directives, literal, value) in cases {
+        let program = scratch.write("literal.invm", format!("{directives}SET R0 {literal}\nPRINT R0 int\n"));
+        for command in runners() {
+            let out = invm([command, program.to_str().unwrap()]);
+            assert!(out.ok, "{command} on {literal} faulted with {}", out.stderr);
+            assert_eq!(out.stdout, format!("{value}\n"), "{command} on {literal}");
+        }
+    }
+}
+
+#[test]
+fn invalid_literals_fault_with_their_line() {
+    let scratch = Scratch::new("literal-errors");
+    let cases = [
+        ("", "2147483648", "[Lexer] Number 2147483648 on line 2 does not fit in 32 bits."),
+        ("", "0x1FFFFFFFF", "[Lexer] Number 0x1FFFFFFFF on line 2 does not fit in 32 bits."),
+        ("", "21474837,00", "[Lexer] Number 21474837,00 on line 2 does not fit in 32 bits."),
+        (".width 64", "99999999999999999999", "[Lexer] Number 99999999999999999999 on line 2 does not fit in 64 bits."),
+        ("", "1,234", "[Lexer] Invalid amount 1,234 on line 2."),
+        ("", "''", "[Lexer] Empty character literal on line 2."),
+    ];
+    for (directives, literal, fault) in cases {
+        let program = scratch.write("literal.invm", format!("{directives}\nSET R0 {literal}\n"));
+        let out = invm(["run", program.to_str().unwrap()]);
+        assert!(out.faulted_with(fault), "{literal} faulted with {}", out.stderr);
+    }
+}
@@ -0,0 +1,58 @@
+//! The regions of memory: the unmapped gap between the heap and the stack, the
+//! two growing into each other, and the sizes given with `--memory` and `--data`.
+
+mod common;
+
+use common::{Scratch, invm, outputs};
+
+/// Three words for the heap and the stack after the static data.
+const SMALL: [&str; 4] = ["--memory", "1027", "--data", "1024"];
+
+#[test]
+fn addresses_between_the_heap_and_the_stack_are_unmapped() {
+    let scratch = Scratch::new("memory-unmapped");
+    let cases = [
+        ("ALLOC 2 R0\nSET *R0+1 5\nPRINT *R0+1 int\nPRINT *1026 int\n", "5\n", 1026),
+        ("PUSH 7\nPRINT *65535 int\nPRINT *65534 int\n", "7\n", 65534),
+    ];
+    for (source, stdout, addr) in cases {
+        let fault = format!("[INVM] Segmentation fault at address {addr}.");
+        for (runner, out) in outputs(&scratch, source, &[]) {
+            assert!(out.faulted_with(&fault), "{runner} faulted with {}", out.stderr);
+            assert_eq!(out.stdout, stdout, "{runner}");
+        }
+    }
+}
+
+#[test]
+fn the_stack_and_the_heap_cannot_grow_into_each_other() {
+    let scratch = Scratch::new("memory-collision");
+    let cases = [
+        ("ALLOC 1 R0\nPUSH 1\nPUSH 2\nPUSH 3\n", "[INVM] Stack overflow."),
+        ("PUSH 1\nALLOC 2 R0\nALLOC 1 R1\n", "[INVM] Heap overflow: 1 words do not fit between the heap and the stack."),
+    ];
+    for (source, fault) in cases {
+        for (runner, out) in outputs(&scratch, source, &SMALL) {
+            assert!(out.faulted_with(fault), "{runner} on:\n{source}\nfaulted with {}", out.stderr);
+        }
+    }
+}
+
+#[test]
+fn sizes_that_do_not_fit_are_refused() {
+    let scratch = Scratch::new("memory-sizes");
+    let program = scratch.write("memory.invm", "NOP\n");
+    let cases: [(&[&str], &str); 2] = [
+        (&["--memory", "2147483648"], "[Run] Missing or invalid value for --memory."),
+        (&["--memory", "100", "--data", "101"], "[Run] Missing or invalid value for --data."),
+    ];
+    for (args, fault) in cases {
+        for command in ["run", "c"] {
+            let out = invm([command, program.to_str().unwrap()].iter().chain(args));
+            assert!(out.faulted_with(fault), "{command} {args:?} faulted with {}", out.stderr);
+        }
+    }
+    let program = scratch.write("data.invm", ".word big 3 1 2 3\nPRINT *big int\n");
+    let out = invm(["run", program.to_str().unwrap(), "--data", "3"]);
+    assert!(out.faulted_with("[INVM] The data section needs 4 words, but there are only 3 words of static data."), "faulted with {}", out.stderr);
+}
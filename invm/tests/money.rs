@@ -0,0 +1,50 @@
+//! Reading amounts with `READ R money`, and the truncation of `MMULT` and `MDIV`.
+
+mod common;
+
+use common::{Scratch, invm_with_input, outputs, runners};
+
+#[test]
+fn read_money_accepts_amounts_as_they_are_printed() {
+    let scratch = Scratch::new("money-read");
+    let program = scratch.write("money.invm", "READ R0 money\nPRINT R0 int\nPRINT R0 money\n");
+    let cases = [
+        ("R$ 1.234,56\n", "123456\nR$ 1.234,56\n"),
+        ("-12,5\n", "-1250\n-R$ 12,50\n"),
+        ("7\n", "700\nR$ 7,00\n"),
+    ];
+    for command in runners() {
+        for (input, stdout) in cases {
+            let out = invm_with_input([command, program.to_str().unwrap()], input);
+            assert!(out.ok, "{command} on {input:?} faulted with {}", out.stderr);
+            assert_eq!(out.stdout, stdout, "{command} on {input:?}");
+        }
+        for input in ["1,234\n", "abc\n"] {
+            let out = invm_with_input([command, program.to_str().unwrap()], input);
+            assert!(out.faulted_with("[INVM] Failed to convert READ value to money."), "{command} on {input:?} faulted with {}", out.stderr);
+        }
+    }
+}
+
+#[test]
+fn mmult_and_mdiv_truncate_toward_zero() {
+    let scratch = Scratch::new("money-truncate");
+    let source = "\
+SET R0 333
+MMULT 0,5 R0
+PRINT R0 money
+SET R1 -333
+MMULT 0,5 R1
+PRINT R1 money
+SET R2 1000
+MDIV 3,00 R2
+PRINT R2 money
+SET R3 -1000
+MDIV 3,00 R3
+PRINT R3 money
+";
+    for (runner, out) in outputs(&scratch, source, &[]) {
+        assert!(out.ok, "{runner} faulted with {}", out.stderr);
+        assert_eq!(out.stdout, "R$ 1,66\n-R$ 1,66\nR$ 3,33\n-R$ 3,33\n", "{runner}");
+    }
+}
@@ -0,0 +1,79 @@
+//! Programs optimised with `-O` print what the original prints, and with
+//! `--keep-ticks` also see the same market.
+
+mod common;
+
+use common::{Scratch, invm, runners};
+
+/// Has constants to propagate, dead stores, a chain of jumps and unreachable
+/// code, and ends by printing the market it saw.
+const PROGRAM: &str = "\
+    SET FUND1 5
+    SET FUND2 FUND1
+    ADD 3 FUND2
+    SET FUND1 99
+    SET FUND1 1
+    GOTO $a
+dead:
+    PRINT 0 int
+a:
+    GOTO $b
+b:
+    MULT FUND2 FUND1
+    PRINT FUND1 int
+    PRINT STOCKPRICE money
+    PRINT BALANCE money
+";
+
+/// Runs `program` with `command`, returning the values printed before the
+/// market and the market itself.
+fn run(command: &str, program: &str, flags: &[&str]) -> (String, String) {
+    let out = invm([command, program, "--seed", "3"].iter().chain(flags));
+    assert!(out.ok, "{command} {flags:?} faulted with {}", out.stderr);
+    let lines: Vec<&str> = out.stdout.lines().collect();
+    let (values, market) = lines.split_at(lines.len() - 2);
+    (values.join("\n"), market.join("\n"))
+}
+
+#[test]
+fn optimised_programs_print_the_same_values() {
+    let scratch = Scratch::new("optimize-values");
+    let program = scratch.write("program.invm", PROGRAM);
+    let program = program.to_str().unwrap();
+
+    for command in runners() {
+        let (values, market) = run(command, program, &[]);
+        assert_eq!(values, "8", "{command}");
+        assert_eq!(market, "R$ 203,40\nR$ 11.000,00", "{command}");
+
+        // Fewer instructions run, so the market moves fewer ticks.
+        let (optimised, optimised_market) = run(command, program, &["-O"]);
+        assert_eq!(optimised, values, "{command}");
+        assert_ne!(optimised_market, market, "{command}");
+
+        assert_eq!(run(command, program, &["-O", "--keep-ticks"]), (values, market), "{command}");
+    }
+}
+
+#[test]
+fn optimised_examples_print_the_same_output() {
+    for example in ["factorial", "factorialheap", "printrange", "readwrite"] {
+        let program = format!("{}/examples/{example}.invm", env!("CARGO_MANIFEST_DIR"));
+        let original = invm(["run", &program]);
+        let optimised = invm(["run", &program, "-O"]);
+        assert!(original.ok && optimised.ok, "{example} faulted with {}{}", original.stderr, optimised.stderr);
+        assert_eq!(optimised.stdout, original.stdout, "{example}");
+    }
+}
+
+#[cfg(feature = "jit")]
+#[test]
+fn jit_diff_runs_the_optimised_program() {
+    let scratch = Scratch::new("optimize-jit-diff");
+    let program = scratch.write("program.invm", PROGRAM);
+    for flags in [&["-O"][..], &["-O", "--keep-ticks"]] {
+        let out = invm(["jit-diff", program.to_str().unwrap(), "--seed", "3"].iter().chain(flags));
+        assert!(out.ok, "{flags:?} faulted with {}{}", out.stdout, out.stderr);
+        assert_eq!(out.stdout, "[JIT] Interpreter and JIT agree with seed 3.\n", "{flags:?}");
+    }
+}
@@ -0,0 +1,84 @@
+//! Output with `PRINT`, `PUT` and `PRINTF`, to stdout or with `err` to stderr.
+
+mod common;
+
+use common::{Scratch, invm, outputs};
+
+#[test]
+fn printf_replaces_every_placeholder() {
+    let scratch = Scratch::new("output-placeholders");
+    let source = "\
+.string fmt \"%d|%b|%c|%s|%m|100%%\\n\"
+.string name \"Ana\"
+PUSH -3
+PUSH 1
+PUSH 'x'
+PUSH name
+PUSH 12345
+PRINTF fmt
+";
+    for (runner, out) in outputs(&scratch, source, &[]) {
+        assert!(out.ok, "{runner} faulted with {}", out.stderr);
+        assert_eq!(out.stdout, "-3|certeza|x|Ana|R$ 123,45|100%\n", "{runner}");
+    }
+}
+
+#[test]
+fn the_first_placeholder_takes_the_first_value_pushed() {
+    let scratch = Scratch::new("output-order");
+    let source = ".string fmt \"%d %d %d\\n\"\nPUSH 7\nPUSH 1\nPUSH 2\nPUSH 3\nPRINTF fmt\nPOP R0\nPRINT R0 int\n";
+    for (runner, out) in outputs(&scratch, source, &[]) {
+        assert!(out.ok, "{runner} faulted with {}", out.stderr);
+        assert_eq!(out.stdout, "1 2 3\n7\n", "{runner}");
+    }
+}
+
+#[test]
+fn bad_formats_fault() {
+    let scratch = Scratch::new("output-faults");
+    let cases = [
+        (".string fmt \"%q\"\nPUSH 1\nPRINTF fmt\n", "[INVM] Invalid placeholder %q in the PRINTF format at address 0."),
+        (".string fmt \"%d %d\"\nPUSH 1\nPRINTF fmt\n", "[INVM] PRINTF needs 2 values, but the stack has 1."),
+    ];
+    for (source, fault) in cases {
+        for (runner, out) in outputs(&scratch, source, &[]) {
+            assert!(out.faulted_with(fault), "{runner} faulted with {}", out.stderr);
+            assert_eq!(out.stdout, "", "{runner}");
+        }
+    }
+}
+
+#[test]
+fn put_leaves_out_the_newline() {
+    let scratch = Scratch::new("output-put");
+    for (runner, out) in outputs(&scratch, "PUT 'a' char\nPUT 7 int\nPUT 0 bool\nPRINT 1 int\n", &[]) {
+        assert!(out.ok, "{runner} faulted with {}", out.stderr);
+        assert_eq!(out.stdout, "a7bizarro1\n", "{runner}");
+    }
+}
+
+#[test]
+fn err_writes_to_stderr_only() {
+    let scratch = Scratch::new("output-err");
+    let source = ".string fmt \"%d!\\n\"\nPRINT 1 int\nPRINT 2 int err\nPUT 3 int err\nPUSH 4\nPRINTF fmt err\nPUT 5 int\n";
+    for (runner, out) in outputs(&scratch, source, &[]) {
+        assert!(out.ok, "{runner} faulted with {}", out.stderr);
+        assert_eq!(out.stdout, "1\n5", "{runner}");
+        assert_eq!(out.stderr, "2\n34!\n", "{runner}");
+    }
+}
+
+#[test]
+fn stack_counts_the_placeholders_of_the_format() {
+    let scratch = Scratch::new("output-stack");
+    let program = scratch.write("stack.invm", ".string fmt \"%d %d%% %s\\n\"\nPUSH 1\nPUSH 2\nPUSH fmt\nPRINTF fmt\nPUSH 3\nPOP R0\n");
+    let out = invm(["stack", program.to_str().unwrap()]);
+    assert!(out.ok, "{}", out.stdout);
+    assert!(out.stdout.contains("    6 |        0 |   PUSH 3\n"), "{}", out.stdout);
+
+    let program = scratch.write("stack.invm", ".string fmt \"%d %d\"\nPUSH 1\nPRINTF fmt\n");
+    let out = invm(["stack", program.to_str().unwrap()]);
+    assert!(!out.ok, "{}", out.stdout);
+    assert!(out.stdout.lines().any(|line| line
+        == "[Stack] Line 3: PRINTF may run with fewer values on the stack than placeholders (path through lines 2 -> 3)."), "{}", out.stdout);
+}
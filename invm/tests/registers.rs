@@ -0,0 +1,42 @@
+//! The general-purpose registers `R0` to `R29`, of which `--registers` chooses
+//! how many the VM has.
+
+mod common;
+
+use common::{Scratch, invm, outputs, runners};
+
+const PROGRAM: &str = "SET R16 7\nPRINT R16 int\n";
+
+#[test]
+fn registers_past_the_count_are_rejected() {
+    let scratch = Scratch::new("registers-default");
+    let program = scratch.write("registers.invm", PROGRAM);
+    let fault = "[INVM] Use of register R16, but the VM has 16 general-purpose registers.";
+    for command in runners().into_iter().chain(["c"]) {
+        let out = invm([command, program.to_str().unwrap()]);
+        assert!(out.faulted_with(fault), "{command} faulted with {}", out.stderr);
+        let out = invm([command, program.to_str().unwrap(), "--registers", "16"]);
+        assert!(out.faulted_with(fault), "{command} with 16 registers faulted with {}", out.stderr);
+    }
+}
+
+#[test]
+fn more_registers_can_be_asked_for() {
+    let scratch = Scratch::new("registers-more");
+    for (runner, out) in outputs(&scratch, PROGRAM, &["--registers", "17"]) {
+        assert!(out.ok, "{runner} faulted with {}", out.stderr);
+        assert_eq!(out.stdout, "7\n", "{runner}");
+    }
+    for (runner, out) in outputs(&scratch, "SET R29 7\nPRINT R29 int\n", &["--registers", "30"]) {
+        assert!(out.ok, "{runner} faulted with {}", out.stderr);
+        assert_eq!(out.stdout, "7\n", "{runner}");
+    }
+}
+
+#[test]
+fn at_most_30_registers_exist() {
+    let scratch = Scratch::new("registers-limit");
+    let program = scratch.write("registers.invm", PROGRAM);
+    let out = invm(["run", program.to_str().unwrap(), "--registers", "31"]);
+    assert!(out.faulted_with("[Run] Missing or invalid value for --registers."), "faulted with {}", out.stderr);
+}
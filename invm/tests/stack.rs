@@ -0,0 +1,77 @@
+//! The depths reported by `invm stack`, and the problems it finds.
+
+mod common;
+
+use common::{Scratch, invm};
+
+/// Runs `invm stack` on `source`, returning whether it found no errors and its report.
+fn stack(scratch: &Scratch, source: &str) -> (bool, String) {
+    let program = scratch.write("stack.invm", source);
+    let out = invm(["stack", program.to_str().unwrap()]);
+    (out.ok, out.stdout)
+}
+
+#[test]
+fn depths_follow_pushes_pops_and_calls() {
+    let scratch = Scratch::new("stack-depths");
+    let (ok, report) = stack(&scratch, "PUSH 1\nPUSH 2\nCALL $f\nPOP FUND1\nPOP FUND1\nPOP FUND1\nCRASH\nf:\nPUSH 3\nRET\n");
+    assert!(ok, "{report}");
+    assert_eq!(report, concat!(
+        "    1 |        0 |   PUSH 1\n",
+        "    2 |        1 |   PUSH 2\n",
+        "    3 |        2 |   CALL $f\n",
+        "    4 |        3 |   POP FUND1\n",
+        "    5 |        2 |   POP FUND1\n",
+        "    6 |        1 |   POP FUND1\n",
+        "    7 |        0 |   CRASH\n",
+        "    8 |        2 | f:\n",
+        "    9 |        2 |   PUSH 3\n",
+        "   10 |        3 |   RET\n",
+        "\n",
+        "[Stack] Maximum stack usage: 3 entries.\n",
+    ));
+}
+
+#[test]
+fn problems_are_reported() {
+    let scratch = Scratch::new("stack-problems");
+    let cases = [
+        ("PUSH 1\nPOP FUND1\nPOP FUND1\n",
+         "[Stack] Line 3: POP may run on an empty stack (path through lines 1 -> 3).",
+         "[Stack] Maximum stack usage: 1 entries."),
+        ("loop:\nPUSH 1\nGOTO $loop\n",
+         "[Stack] Line 1: stack depth grows without bound in the loop starting at loop:.",
+         "[Stack] Maximum stack usage: unbounded."),
+    ];
+    for (source, problem, usage) in cases {
+        let (ok, report) = stack(&scratch, source);
+        assert!(!ok, "no errors found in:\n{source}");
+        assert!(report.lines().any(|line| line == problem), "{report}");
+        assert!(report.ends_with(&format!("{usage}\n")), "{report}");
+    }
+}
+
+#[test]
+fn overflow_is_reported_once() {
+    let scratch = Scratch::new("stack-overflow");
+    let program = scratch.write("stack.invm", "PUSH 1\nPUSH 2\nPUSH 3\nPUSH 4\nNOP\nNOP\nPOP R0\n");
+    // Three words of stack after the static data.
+    let out = invm(["stack", program.to_str().unwrap(), "--memory", "1027", "--data", "1024"]);
+    assert!(!out.ok, "{}", out.stdout);
+    let problems: Vec<&str> = out.stdout.lines().filter(|line| line.starts_with("[Stack] Line")).collect();
+    assert_eq!(problems, ["[Stack] Line 4: stack depth may reach 4, over the limit of 3."]);
+}
+
+#[test]
+fn heap_taken_from_the_stack_is_reported() {
+    let scratch = Scratch::new("stack-heap");
+    let (ok, report) = stack(&scratch, "ALLOC 2 R0\nREADLINE 1 R1\nREAD R2 str\n");
+    // Only a warning: the program may still fit.
+    assert!(ok, "{report}");
+    let problems: Vec<&str> = report.lines().filter(|line| line.starts_with("[Stack] Line")).collect();
+    assert_eq!(problems, [
+        "[Stack] Line 1: ALLOC uses heap, leaving less room for the stack.",
+        "[Stack] Line 2: READLINE uses heap proportional to the input length, leaving less room for the stack.",
+        "[Stack] Line 3: READ str uses heap proportional to the input length, leaving less room for the stack.",
+    ]);
+}
@@ -0,0 +1,48 @@
+//! `BUY` and `SELL` with any operand, and the trades they refuse, in the
+//! interpreter, the JIT when it is built, and translated to C.
+
+mod common;
+
+use common::{Scratch, outputs};
+
+#[test]
+fn trades_take_registers_memory_and_sensors() {
+    let scratch = Scratch::new("trading");
+    // The first instruction trades at the starting price of R$ 200,00, and
+    // every instruction adds R$ 100,00 to the balance.
+    let source = "\
+.word n 3
+    BUY *n
+    PRINT BALANCE money
+    PRINT OWNED int
+    SET R0 2
+    SELL R0
+    PRINT OWNED int
+    SELL OWNED
+    PRINT OWNED int
+    BUY 0
+    PRINT OWNED int
+";
+    for (runner, out) in outputs(&scratch, source, &[]) {
+        assert!(out.ok, "{runner} faulted with {}", out.stderr);
+        assert_eq!(out.stdout, "R$ 9.500,00\n3\n1\n0\n0\n", "{runner}");
+    }
+}
+
+#[test]
+fn refused_trades_fault_without_trading() {
+    let scratch = Scratch::new("trading-refused");
+    let cases = [
+        ("SET R0 -2\nBUY R0\n", "[INVM] BUY of -2 stocks, expected at least 0."),
+        (".word n -1\nSELL *n\n", "[INVM] SELL of -1 stocks, expected at least 0."),
+        // The total price does not fit in a word, and the balance is the starting one.
+        ("BUY 2147483647\n", "[INVM] Insufficient balance (R$ 10.000,00) to buy 2147483647 stocks at price R$ 200,00 (total price: R$ 429.496.729.400,00)."),
+        ("BUY 1\nSET R0 2\nSELL R0\n", "[INVM] Insufficient stocks to sell (owned: 1, sell: 2)."),
+    ];
+    for (source, fault) in cases {
+        for (runner, out) in outputs(&scratch, &format!("{source}PRINT OWNED int\n"), &[]) {
+            assert!(out.faulted_with(fault), "{runner} on:\n{source}\nfaulted with {}", out.stderr);
+            assert_eq!(out.stdout, "", "{runner} on:\n{source}");
+        }
+    }
+}
@@ -0,0 +1,58 @@
+//! `WAIT n`, which lets the market move n ticks in one instruction.
+
+mod common;
+
+use common::{Scratch, invm, outputs};
+
+#[test]
+fn wait_advances_the_market_by_n_ticks() {
+    let scratch = Scratch::new("wait-ticks");
+    let program = "\
+    PRINT BALANCE money
+    WAIT 50
+    PRINT BALANCE money
+    SET R0 0
+    WAIT R0
+    PRINT BALANCE money
+";
+    for (runner, out) in outputs(&scratch, program, &[]) {
+        assert!(out.ok, "{runner} faulted with {}", out.stderr);
+        assert_eq!(out.stdout, "R$ 10.000,00\nR$ 15.100,00\nR$ 15.300,00\n", "{runner}");
+    }
+}
+
+#[test]
+fn wait_sees_the_prices_of_as_many_instructions() {
+    let scratch = Scratch::new("wait-prices");
+    let market = "PRINT STOCKPRICE money\nPRINT BALANCE money\n";
+    let waited = outputs(&scratch, &format!("WAIT 50\n{market}"), &["--seed", "4"]);
+    let stepped = outputs(&scratch, &format!("{}{market}", "NOP\n".repeat(50)), &["--seed", "4"]);
+    // C is never seeded, so only the runners see the same prices twice.
+    for ((runner, waited), (_, stepped)) in waited.into_iter().zip(stepped).filter(|((runner, _), _)| *runner != "C") {
+        assert!(waited.ok && stepped.ok, "{runner} faulted with {}{}", waited.stderr, stepped.stderr);
+        assert_eq!(waited.stdout, stepped.stdout, "{runner}");
+    }
+}
+
+#[test]
+fn negative_waits_fault() {
+    let scratch = Scratch::new("wait-negative");
+    for (runner, out) in outputs(&scratch, "SET R0 -3\nWAIT R0\nPRINT 1 int\n", &[]) {
+        assert!(out.faulted_with("[INVM] WAIT of -3 ticks, expected at least 0."), "{runner} faulted with {}", out.stderr);
+        assert_eq!(out.stdout, "", "{runner}");
+    }
+}
+
+#[test]
+fn trace_reports_each_wait() {
+    let scratch = Scratch::new("wait-trace");
+    let program = scratch.write("trace.invm", "NOP\nWAIT 0\n");
+    let out = invm(["run", program.to_str().unwrap(), "--trace"]);
+    assert!(out.ok, "faulted with {}", out.stderr);
+    let price = out.stderr.lines().next().and_then(|line| line.split("STOCKPRICE ").nth(1)).unwrap_or_default();
+    let price = price.split(" -> ").next().unwrap_or_default();
+    assert_eq!(
+        out.stderr,
+        format!("[Trace] WAIT 0 at instruction 1: 0 ticks, STOCKPRICE {price} -> {price} (low {price}, high {price}).\n"),
+    );
+}
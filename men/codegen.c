@@ -174,7 +174,7 @@ static int gen_expr_to_slot(AST *e) {
                 emitf("SET FUND1 *%d\n", L);
                 emitf("SET *%d FUND1\n", out);
                 emitf("SET FUND2 *%d\n", R);
-                emitf("DIV *%d FUND2\n", out); /* *out = *out / FUND2 -> left / right */
+                emitf("DIV FUND2 *%d\n", out); /* *out = *out / FUND2 -> left / right */
             }
             return out;
         }